@@ -18,6 +18,7 @@ mod branch_set_capnp {
     include!(concat!(env!("OUT_DIR"), "/branch_set_capnp.rs"));
 }
 
+mod mapping;
 mod store;
 
 pub use store::*;
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use attaca::{digest::Sha3Digest, store::RawHandle};
+
+/// A reference-counted mapping between digests and raw handles.
+///
+/// Entries are removed from the mapping as soon as their last reference is released, so the size
+/// of the mapping is proportional to the number of live handles rather than the number of objects
+/// ever seen. Raw handles are never reused, so a stale raw handle can never alias a different
+/// digest.
+#[derive(Debug)]
+pub struct Mapping {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next: u64,
+    digests: HashMap<RawHandle, (Sha3Digest, usize)>,
+    handles: HashMap<Sha3Digest, RawHandle>,
+}
+
+impl Inner {
+    fn reserve(&mut self, digest: Sha3Digest) -> Result<RawHandle, RawHandle> {
+        match self.handles.get(&digest).cloned() {
+            Some(id) => {
+                self.digests.get_mut(&id).unwrap().1 += 1;
+                Err(id)
+            }
+            None => {
+                let new_id = RawHandle(self.next);
+                self.next += 1;
+                self.handles.insert(digest, new_id);
+                self.digests.insert(new_id, (digest, 1));
+                Ok(new_id)
+            }
+        }
+    }
+}
+
+impl Mapping {
+    pub fn new() -> Mapping {
+        Self {
+            inner: RwLock::new(Inner::default()),
+        }
+    }
+
+    /// Get or allocate a new ID in the mapping for a given digest, taking a reference to it.
+    ///
+    /// This function returns `Ok` if the ID is fresh and `Err` if it is not.
+    pub fn reserve(&self, digest: Sha3Digest) -> Result<RawHandle, RawHandle> {
+        self.inner.write().unwrap().reserve(digest)
+    }
+
+    /// Take an additional reference to a live ID.
+    pub fn retain(&self, id: RawHandle) {
+        self.inner.write().unwrap().digests.get_mut(&id).unwrap().1 += 1;
+    }
+
+    /// Release a reference to an ID, removing it from the mapping if it was the last one.
+    pub fn release(&self, id: RawHandle) {
+        let mut inner = self.inner.write().unwrap();
+
+        let digest = {
+            let entry = inner.digests.get_mut(&id).unwrap();
+            entry.1 -= 1;
+
+            if entry.1 > 0 {
+                return;
+            }
+
+            entry.0
+        };
+
+        inner.digests.remove(&id);
+        inner.handles.remove(&digest);
+    }
+
+    /// Acquire a read lock and resolve IDs into digests.
+    pub fn map_ids_to_digests<'a, I>(&'a self, iter: I) -> impl Iterator<Item = Sha3Digest> + 'a
+    where
+        I: IntoIterator<Item = RawHandle>,
+        I::IntoIter: 'a,
+    {
+        let inner = self.inner.read().unwrap();
+        iter.into_iter().map(move |id| inner.digests[&id].0)
+    }
+
+    /// Get the digest corresponding to a given ID.
+    ///
+    /// The invariant that all live IDs have corresponding digests is preserved, so we may simply
+    /// index.
+    pub fn digest(&self, id: RawHandle) -> Sha3Digest {
+        self.inner.read().unwrap().digests[&id].0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attaca::digest::prelude::*;
+
+    fn live(mapping: &Mapping) -> usize {
+        mapping.inner.read().unwrap().digests.len()
+    }
+
+    #[test]
+    fn release_forgets_unreferenced() {
+        let mapping = Mapping::new();
+        let a = Sha3Digest::digest(b"a");
+        let b = Sha3Digest::digest(b"b");
+
+        let id_a = mapping.reserve(a).unwrap();
+        let id_b = mapping.reserve(b).unwrap();
+        assert_eq!(mapping.reserve(a), Err(id_a));
+        mapping.retain(id_b);
+        assert_eq!(live(&mapping), 2);
+
+        mapping.release(id_a);
+        mapping.release(id_b);
+        assert_eq!(live(&mapping), 2);
+        assert_eq!(mapping.digest(id_a), a);
+
+        mapping.release(id_a);
+        mapping.release(id_b);
+        assert_eq!(live(&mapping), 0);
+
+        // IDs are never reused, even for a digest which has been seen before.
+        let new_id_a = mapping.reserve(a).unwrap();
+        assert!(new_id_a != id_a && new_id_a != id_b);
+        assert_eq!(live(&mapping), 1);
+    }
+}
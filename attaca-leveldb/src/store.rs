@@ -1,8 +1,8 @@
-use std::{fmt, mem, str, collections::HashMap, io::{self, BufRead, Cursor, Read, Write}, path::Path,
-          sync::RwLock};
+use std::{fmt, str, collections::HashMap, io::{self, BufRead, Cursor, Read, Write}, path::Path,
+          sync::{Arc, RwLock}};
 
 use attaca::{canonical, Init, Open, digest::{Sha3Digest, prelude::*},
-             store::{RawHandle, prelude::*}};
+             store::{ContentArguments, RawArgument, RawHandle, prelude::*}};
 use capnp::{message, serialize_packed};
 use failure::*;
 use futures::{future::FutureResult, prelude::*};
@@ -12,6 +12,7 @@ use url::Url;
 use uuid::Uuid;
 
 use Key;
+use mapping::Mapping;
 
 fn decode_branch_set<R: BufRead>(reader: &mut R) -> Result<Vec<(String, Sha3Digest)>, Error> {
     use branch_set_capnp::*;
@@ -112,6 +113,7 @@ impl Init for LevelDbBackend {
 pub struct LevelDbBuilder {
     blob: Vec<u8>,
     refs: Vec<RawHandle>,
+    args: Vec<RawHandle>,
 }
 
 impl Write for LevelDbBuilder {
@@ -133,10 +135,21 @@ impl Extend<RawHandle> for LevelDbBuilder {
     }
 }
 
+impl Extend<RawArgument> for LevelDbBuilder {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = RawArgument>,
+    {
+        self.args.extend(iter.into_iter().map(|RawArgument(id)| id));
+    }
+}
+
 #[derive(Debug)]
 pub struct LevelDbContent {
     blob: Cursor<Vec<u8>>,
-    refs: <Vec<RawHandle> as IntoIterator>::IntoIter,
+    refs: <Vec<Sha3Digest> as IntoIterator>::IntoIter,
+    args: <Vec<Sha3Digest> as IntoIterator>::IntoIter,
+    mapping: Arc<Mapping>,
 }
 
 impl Read for LevelDbContent {
@@ -149,24 +162,33 @@ impl Iterator for LevelDbContent {
     type Item = RawHandle;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.refs.next()
+        // IDs are only reserved as they are yielded, so that unconsumed refs don't leak references.
+        let mapping = &self.mapping;
+        self.refs
+            .next()
+            .map(|digest| mapping.reserve(digest).unwrap_or_else(|e| e))
+    }
+}
+
+impl ContentArguments for LevelDbContent {
+    fn next_argument(&mut self) -> Option<RawHandle> {
+        let mapping = &self.mapping;
+        self.args
+            .next()
+            .map(|digest| mapping.reserve(digest).unwrap_or_else(|e| e))
     }
 }
 
 struct Inner {
     uuid: Uuid,
     db: Database<Key>,
-
-    ids: HashMap<Sha3Digest, RawHandle>,
-    handles: HashMap<RawHandle, Sha3Digest>,
 }
 
 impl fmt::Debug for Inner {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Inner")
+            .field("uuid", &self.uuid)
             .field("db", &"Database")
-            .field("ids", &self.ids)
-            .field("handles", &self.handles)
             .finish()
     }
 }
@@ -174,6 +196,7 @@ impl fmt::Debug for Inner {
 #[derive(Debug)]
 pub struct LevelDbBackend {
     inner: RwLock<Inner>,
+    mapping: Arc<Mapping>,
 }
 
 impl LevelDbBackend {
@@ -181,118 +204,113 @@ impl LevelDbBackend {
         let uuid = Uuid::from_bytes(&db.get(ReadOptions::new(), &Key::uuid())?.unwrap())?;
 
         Ok(Self {
-            inner: RwLock::new(Inner {
-                uuid,
-                db,
-
-                ids: HashMap::new(),
-                handles: HashMap::new(),
-            }),
+            inner: RwLock::new(Inner { uuid, db }),
+            mapping: Arc::new(Mapping::new()),
         })
     }
 
     // This function returns `Ok` if the ID is fresh and `Err` if it is not.
     fn reserve(&self, digest: Sha3Digest) -> Result<RawHandle, RawHandle> {
-        let attempt = self.inner.read().unwrap().ids.get(&digest).cloned();
-        match attempt {
-            Some(id) => Err(id),
-            None => {
-                let mut inner = self.inner.write().unwrap();
-
-                match inner.ids.get(&digest).cloned() {
-                    Some(id) => Err(id),
-                    None => {
-                        let new_id = RawHandle(inner.ids.len() as u64);
-                        inner.ids.insert(digest, new_id);
-                        inner.handles.insert(new_id, digest);
-                        Ok(new_id)
-                    }
-                }
-            }
-        }
+        self.mapping.reserve(digest)
     }
 
-    fn do_finish(&self, builder: LevelDbBuilder) -> Result<RawHandle, Error> {
-        let inner = self.inner.read().unwrap();
-
+    // `encoded` is the canonical encoding of the builder's contents, if it's already known.
+    fn do_finish(
+        &self,
+        builder: LevelDbBuilder,
+        encoded: Option<Vec<u8>>,
+    ) -> Result<RawHandle, Error> {
         let blob = builder.blob;
-        let refs = builder
-            .refs
-            .into_iter()
-            .map(|id| inner.handles[&id])
-            .collect::<Vec<_>>();
-
-        let mut hasher = Sha3Digest::writer();
-        canonical::encode(&mut hasher, &blob, &refs).unwrap();
-        let digest = hasher.finish();
-
-        mem::drop(inner);
+        let encoded = match encoded {
+            Some(encoded) => encoded,
+            None => {
+                let refs = self.mapping
+                    .map_ids_to_digests(builder.refs)
+                    .collect::<Vec<_>>();
+                let args = self.mapping
+                    .map_ids_to_digests(builder.args)
+                    .collect::<Vec<_>>();
+
+                let mut encoded = Vec::new();
+                canonical::encode(&mut encoded, &blob, &refs, &args)?;
+                encoded
+            }
+        };
+        let digest = Sha3Digest::digest(&encoded);
 
         match self.reserve(digest) {
             Ok(id) => {
-                let mut buf = Vec::new();
-                leb128::write::unsigned(&mut buf, blob.len() as u64)?; // `C.length || C`
-                buf.write_all(&blob)?;
-                canonical::encode(&mut buf, &blob, &refs)?; // `EncodedRefs(C)`
-                self.inner.read().unwrap().db.put(
-                    WriteOptions::new(),
-                    &Key::blob(digest.as_bytes()),
-                    &buf,
-                )?;
-
-                Ok(id)
+                // The fresh ID already holds a reference, which nobody will release if the object
+                // never makes it into the database.
+                match self.write_blob(digest, &blob, &encoded) {
+                    Ok(()) => Ok(id),
+                    Err(error) => {
+                        self.mapping.release(id);
+                        Err(error)
+                    }
+                }
             }
             Err(id) => Ok(id),
         }
     }
 
+    fn write_blob(&self, digest: Sha3Digest, blob: &[u8], encoded: &[u8]) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        leb128::write::unsigned(&mut buf, blob.len() as u64)?; // `C.length || C`
+        buf.write_all(blob)?;
+        buf.write_all(encoded)?; // `EncodedRefs(C)`
+        self.inner.read().unwrap().db.put(
+            WriteOptions::new(),
+            &Key::blob(digest.as_bytes()),
+            &buf,
+        )?;
+
+        Ok(())
+    }
+
     fn do_load(&self, id: RawHandle) -> Result<LevelDbContent, Error> {
-        let inner = self.inner.read().unwrap();
-        let digest = inner.handles[&id];
+        let digest = self.mapping.digest(id);
         let mut data = Cursor::new(
-            inner
+            self.inner
+                .read()
+                .unwrap()
                 .db
                 .get(ReadOptions::new(), &Key::blob(digest.as_bytes()))?
                 .expect("bad ID!"),
         );
         let mut blob = vec![0; leb128::read::unsigned(&mut data)? as usize]; // `C.length || C`
         data.read_exact(&mut blob)?;
-        let ref_digests = canonical::decode(&mut data)?.finish::<Sha3Digest>()?.refs; // `EncodedRefs(C)`
-
-        // Discard the read lock so we don't deadlock ourselves, because `reserve` may attempt to
-        // take a write lock.
-        mem::drop(inner);
-        let refs: Vec<_> = ref_digests
-            .into_iter()
-            .map(|digest| self.reserve(digest).unwrap_or_else(|e| e))
-            .collect();
+        let item = canonical::decode(&mut data)?.finish::<Sha3Digest>()?; // `EncodedRefs(C)`
 
         Ok(LevelDbContent {
             blob: Cursor::new(blob),
-            refs: refs.into_iter(),
+            refs: item.refs.into_iter(),
+            args: item.args.into_iter(),
+            mapping: self.mapping.clone(),
         })
     }
 
     fn do_id(&self, id: RawHandle) -> Result<Sha3Digest, Error> {
-        Ok(self.inner.read().unwrap().handles[&id])
+        Ok(self.mapping.digest(id))
     }
 
     fn do_digest(&self, signature: DigestSignature, id: RawHandle) -> Result<Sha3Digest, Error> {
         ensure!(signature == Sha3Digest::SIGNATURE, "bad digest");
 
-        Ok(self.inner.read().unwrap().handles[&id])
+        Ok(self.mapping.digest(id))
     }
 
     fn do_resolve_id(&self, digest: &Sha3Digest) -> Result<Option<RawHandle>, Error> {
-        let id = self.reserve(*digest).unwrap_or_else(|e| e);
-        let inner = self.inner.read().unwrap();
-        let db_contains_digest = inner
+        let db_contains_digest = self.inner
+            .read()
+            .unwrap()
             .db
             .get(ReadOptions::new(), &Key::blob(digest.as_bytes()))?
             .is_some();
 
+        // Only take a reference if we're actually going to hand out the ID.
         if db_contains_digest {
-            Ok(Some(id))
+            Ok(Some(self.reserve(*digest).unwrap_or_else(|e| e)))
         } else {
             Ok(None)
         }
@@ -343,9 +361,11 @@ impl LevelDbBackend {
             None => Vec::new(),
         };
 
-        let current = decoded
-            .into_iter()
-            .map(|(name, digest)| (name, inner.ids[&digest]))
+        // Compare by digest rather than by ID, since branches which nobody holds a handle to have
+        // no ID in the mapping.
+        let current = decoded.into_iter().collect::<HashMap<_, _>>();
+        let old = old.into_iter()
+            .map(|(name, id)| (name, self.mapping.digest(id)))
             .collect::<HashMap<_, _>>();
 
         ensure!(old == current, "compare failed");
@@ -354,7 +374,8 @@ impl LevelDbBackend {
         let new_len = new.len();
         encode_branch_set(
             &mut buf,
-            new.into_iter().map(|(name, id)| (name, inner.handles[&id])),
+            new.into_iter()
+                .map(|(name, id)| (name, self.mapping.digest(id))),
             new_len,
         )?;
         inner.db.put(WriteOptions::new(), &Key::branches(), &buf)?;
@@ -368,6 +389,14 @@ impl Backend for LevelDbBackend {
         *self.inner.read().unwrap().uuid.as_bytes()
     }
 
+    fn retain(&self, id: RawHandle) {
+        self.mapping.retain(id);
+    }
+
+    fn release(&self, id: RawHandle) {
+        self.mapping.release(id);
+    }
+
     type Builder = LevelDbBuilder;
     type FutureFinish = FutureResult<RawHandle, Error>;
 
@@ -375,11 +404,26 @@ impl Backend for LevelDbBackend {
         LevelDbBuilder {
             blob: Vec::new(),
             refs: Vec::new(),
+            args: Vec::new(),
         }
     }
 
     fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
-        self.do_finish(builder).into_future()
+        self.do_finish(builder, None).into_future()
+    }
+
+    fn finish_encoded(
+        &self,
+        builder: Self::Builder,
+        signature: DigestSignature,
+        encoded: Vec<u8>,
+    ) -> Self::FutureFinish {
+        let encoded = if signature == Sha3Digest::SIGNATURE {
+            Some(encoded)
+        } else {
+            None
+        };
+        self.do_finish(builder, encoded).into_future()
     }
 
     type Content = LevelDbContent;
@@ -22,7 +22,7 @@ mod mapping;
 use std::{collections::HashMap, io::{self, BufRead, Cursor, Read, Write}, path::Path,
           sync::{Arc, Mutex}};
 
-use attaca::{canonical, Open, digest::{Sha3Digest, prelude::*}, store::{RawHandle, prelude::*}};
+use attaca::{canonical, Open, digest::{Sha3Digest, prelude::*}, store::{ContentArguments, RawArgument, RawHandle, prelude::*}};
 use bytes::{BufMut, IntoBuf};
 use capnp::{message, serialize_packed};
 use failure::*;
@@ -179,6 +179,7 @@ where
 pub struct RadosBuilder {
     blob: Vec<u8>,
     refs: Vec<RawHandle>,
+    args: Vec<RawHandle>,
 }
 
 impl Write for RadosBuilder {
@@ -200,6 +201,15 @@ impl Extend<RawHandle> for RadosBuilder {
     }
 }
 
+impl Extend<RawArgument> for RadosBuilder {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = RawArgument>,
+    {
+        self.args.extend(iter.into_iter().map(|RawArgument(id)| id));
+    }
+}
+
 #[derive(Debug)]
 pub struct RadosFinish {
     blocking: rados::UnitFuture,
@@ -222,7 +232,9 @@ impl Future for RadosFinish {
 #[derive(Debug)]
 pub struct RadosContent {
     blob: Cursor<Vec<u8>>,
-    refs: <Vec<RawHandle> as IntoIterator>::IntoIter,
+    refs: <Vec<Sha3Digest> as IntoIterator>::IntoIter,
+    args: <Vec<Sha3Digest> as IntoIterator>::IntoIter,
+    mapping: Arc<Mapping>,
 }
 
 impl Read for RadosContent {
@@ -235,7 +247,20 @@ impl Iterator for RadosContent {
     type Item = RawHandle;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.refs.next()
+        // IDs are only reserved as they are yielded, so that unconsumed refs don't leak references.
+        let mapping = &self.mapping;
+        self.refs
+            .next()
+            .map(|digest| mapping.reserve(digest).unwrap_or_else(|e| e))
+    }
+}
+
+impl ContentArguments for RadosContent {
+    fn next_argument(&mut self) -> Option<RawHandle> {
+        let mapping = &self.mapping;
+        self.args
+            .next()
+            .map(|digest| mapping.reserve(digest).unwrap_or_else(|e| e))
     }
 }
 
@@ -257,15 +282,14 @@ impl Future for RadosLoad {
                     reader.read_exact(&mut buf)?;
                     Cursor::new(buf)
                 };
-                let refs = {
-                    let ref_digests = canonical::decode(&mut reader)?.finish::<Sha3Digest>()?.refs;
-                    self.mapping
-                        .map_digests_to_ids(ref_digests)
-                        .collect::<Vec<_>>()
-                        .into_iter()
-                };
-
-                Ok(Async::Ready(RadosContent { blob, refs }))
+                let item = canonical::decode(&mut reader)?.finish::<Sha3Digest>()?;
+
+                Ok(Async::Ready(RadosContent {
+                    blob,
+                    refs: item.refs.into_iter(),
+                    args: item.args.into_iter(),
+                    mapping: self.mapping.clone(),
+                }))
             }
             Async::NotReady => Ok(Async::NotReady),
         }
@@ -363,6 +387,14 @@ impl Backend for RadosBackend {
         *self.uuid.as_bytes()
     }
 
+    fn retain(&self, id: RawHandle) {
+        self.mapping.retain(id);
+    }
+
+    fn release(&self, id: RawHandle) {
+        self.mapping.release(id);
+    }
+
     type Builder = RadosBuilder;
     type FutureFinish = Either<RadosFinish, FutureResult<RawHandle, Error>>;
 
@@ -370,25 +402,43 @@ impl Backend for RadosBackend {
         RadosBuilder {
             blob: Vec::new(),
             refs: Vec::new(),
+            args: Vec::new(),
         }
     }
 
     fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
-        let blob = builder.blob;
         let refs = self.mapping
-            .map_ids_to_digests(builder.refs.into_iter())
+            .map_ids_to_digests(builder.refs.iter().cloned())
             .collect::<Vec<_>>();
+        let args = self.mapping
+            .map_ids_to_digests(builder.args.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut encoded = Vec::new();
+        canonical::encode(&mut encoded, &builder.blob, &refs, &args).unwrap();
+
+        self.finish_encoded(builder, Sha3Digest::SIGNATURE, encoded)
+    }
+
+    fn finish_encoded(
+        &self,
+        builder: Self::Builder,
+        signature: DigestSignature,
+        encoded: Vec<u8>,
+    ) -> Self::FutureFinish {
+        if signature != Sha3Digest::SIGNATURE {
+            return self.finish(builder);
+        }
 
-        let mut hasher = Sha3Digest::writer();
-        canonical::encode(&mut hasher, &blob, &refs).unwrap();
-        let digest = hasher.finish();
+        let blob = builder.blob;
+        let digest = Sha3Digest::digest(&encoded);
 
         match self.mapping.reserve(digest) {
             Ok(id) => {
                 let mut blob_buf = Vec::new();
                 leb128::write::unsigned(&mut blob_buf, blob.len() as u64).unwrap();
                 blob_buf.write_all(&blob).unwrap();
-                canonical::encode(&mut blob_buf, &blob, &refs).unwrap();
+                blob_buf.write_all(&encoded).unwrap();
 
                 let obj = Key::Blob.into_object(digest.as_bytes());
                 let blocking = self.context
@@ -524,22 +574,34 @@ impl Backend for RadosBackend {
         old: HashMap<String, RawHandle>,
         new: HashMap<String, RawHandle>,
     ) -> Self::FutureSwapBranches {
+        // Resolve the IDs to digests up front; the caller only guarantees that they are live for
+        // the duration of this call.
+        let old = old.into_iter()
+            .map(|(name, id)| (name, self.mapping.digest(id)))
+            .collect::<HashMap<_, _>>();
+        let new = new.into_iter()
+            .map(|(name, id)| (name, self.mapping.digest(id)))
+            .collect::<HashMap<_, _>>();
+
         let current_future = self.load_branches();
         let context = self.context.clone();
         let mapping = self.mapping.clone();
         let blocking = async_block! {
-            let current = await!(current_future)?;
+            let current = await!(current_future)?
+                .into_iter()
+                .map(|(name, id)| {
+                    let digest = mapping.digest(id);
+                    mapping.release(id);
+                    (name, digest)
+                })
+                .collect::<HashMap<_, _>>();
             ensure!(old == current, "compare failed");
 
             let obj = Key::Branches.into_object(&[][..]);
 
             let mut buf = Vec::new();
             let new_len = new.len();
-            encode_branch_set(
-                &mut buf,
-                new.into_iter().map(|(name, id)| (name, mapping.digest(id))),
-                new_len,
-            )?;
+            encode_branch_set(&mut buf, new, new_len)?;
             await!(context.lock().unwrap().write_full_async(&obj, &buf)).map_err(SyncFailure::new)?;
 
             Ok(())
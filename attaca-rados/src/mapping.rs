@@ -2,6 +2,12 @@ use std::{collections::HashMap, sync::RwLock};
 
 use attaca::{digest::Sha3Digest, store::RawHandle};
 
+/// A reference-counted mapping between digests and raw handles.
+///
+/// Entries are removed from the mapping as soon as their last reference is released, so the size
+/// of the mapping is proportional to the number of live handles rather than the number of objects
+/// ever seen. Raw handles are never reused, so a stale raw handle can never alias a different
+/// digest.
 #[derive(Debug)]
 pub struct Mapping {
     inner: RwLock<Inner>,
@@ -9,18 +15,23 @@ pub struct Mapping {
 
 #[derive(Debug, Default)]
 struct Inner {
-    digests: HashMap<RawHandle, Sha3Digest>,
+    next: u64,
+    digests: HashMap<RawHandle, (Sha3Digest, usize)>,
     handles: HashMap<Sha3Digest, RawHandle>,
 }
 
 impl Inner {
     fn reserve(&mut self, digest: Sha3Digest) -> Result<RawHandle, RawHandle> {
         match self.handles.get(&digest).cloned() {
-            Some(id) => Err(id),
+            Some(id) => {
+                self.digests.get_mut(&id).unwrap().1 += 1;
+                Err(id)
+            }
             None => {
-                let new_id = RawHandle(self.handles.len() as u64);
+                let new_id = RawHandle(self.next);
+                self.next += 1;
                 self.handles.insert(digest, new_id);
-                self.digests.insert(new_id, digest);
+                self.digests.insert(new_id, (digest, 1));
                 Ok(new_id)
             }
         }
@@ -34,13 +45,37 @@ impl Mapping {
         }
     }
 
-    /// Get or allocate a new ID in the mapping for a given digest.
+    /// Get or allocate a new ID in the mapping for a given digest, taking a reference to it.
     ///
     /// This function returns `Ok` if the ID is fresh and `Err` if it is not.
     pub fn reserve(&self, digest: Sha3Digest) -> Result<RawHandle, RawHandle> {
         self.inner.write().unwrap().reserve(digest)
     }
 
+    /// Take an additional reference to a live ID.
+    pub fn retain(&self, id: RawHandle) {
+        self.inner.write().unwrap().digests.get_mut(&id).unwrap().1 += 1;
+    }
+
+    /// Release a reference to an ID, removing it from the mapping if it was the last one.
+    pub fn release(&self, id: RawHandle) {
+        let mut inner = self.inner.write().unwrap();
+
+        let digest = {
+            let entry = inner.digests.get_mut(&id).unwrap();
+            entry.1 -= 1;
+
+            if entry.1 > 0 {
+                return;
+            }
+
+            entry.0
+        };
+
+        inner.digests.remove(&id);
+        inner.handles.remove(&digest);
+    }
+
     /// Acquire a read lock and resolve IDs into digests.
     pub fn map_ids_to_digests<'a, I>(&'a self, iter: I) -> impl Iterator<Item = Sha3Digest> + 'a
     where
@@ -48,25 +83,14 @@ impl Mapping {
         I::IntoIter: 'a,
     {
         let inner = self.inner.read().unwrap();
-        iter.into_iter().map(move |id| inner.digests[&id])
-    }
-
-    /// Acquire a write lock and resolve digests to IDs, reserving new IDs for as of yet unseen
-    /// digests.
-    pub fn map_digests_to_ids<'a, I>(&'a self, iter: I) -> impl Iterator<Item = RawHandle> + 'a
-    where
-        I: IntoIterator<Item = Sha3Digest>,
-        I::IntoIter: 'a,
-    {
-        let mut inner = self.inner.write().unwrap();
-        iter.into_iter()
-            .map(move |digest| inner.reserve(digest).unwrap_or_else(|e| e))
+        iter.into_iter().map(move |id| inner.digests[&id].0)
     }
 
     /// Get the digest corresponding to a given ID.
     ///
-    /// The invariant that all IDs have corresponding digests is preserved, so we may simply index.
+    /// The invariant that all live IDs have corresponding digests is preserved, so we may simply
+    /// index.
     pub fn digest(&self, id: RawHandle) -> Sha3Digest {
-        self.inner.read().unwrap().digests[&id]
+        self.inner.read().unwrap().digests[&id].0
     }
 }
@@ -4,81 +4,104 @@ use failure::*;
 use futures::prelude::*;
 use im::List;
 
-use object::{ObjectRef, TreeBuilder};
+use object::{EntryAttrs, ObjectRef, Tree, TreeBuilder};
 use path::ObjectPath;
 use store::prelude::*;
 
+/// What a batch does to a single path.
+#[derive(Debug, Clone)]
+enum Edit<B: Backend> {
+    Add(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>),
+    Delete,
+    SetAttrs(EntryAttrs<Handle<B>>),
+}
+
 #[derive(Debug, Clone)]
 enum Node<B: Backend> {
-    Add(ObjectRef<Handle<B>>),
+    Add(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>),
     Delete,
-    Branch(HashMap<Arc<String>, Node<B>>),
+
+    // A subtree with changes somewhere beneath it, along with new attributes for the subtree
+    // itself if they are to change, too.
+    Branch(HashMap<Arc<String>, Node<B>>, Option<EntryAttrs<Handle<B>>>),
 }
 
 impl<B: Backend> Node<B> {
     fn new() -> Self {
-        Node::Branch(HashMap::new())
+        Node::Branch(HashMap::new(), None)
     }
 
     fn singleton(key: Arc<String>, node: Self) -> Self {
         let mut hash_map = HashMap::new();
         hash_map.insert(key, node);
-        Node::Branch(hash_map)
+        Node::Branch(hash_map, None)
     }
 
-    fn leaf(objref: Option<ObjectRef<Handle<B>>>) -> Self {
-        match objref {
-            Some(objref) => Node::Add(objref),
-            None => Node::Delete,
+    fn leaf(edit: Edit<B>) -> Self {
+        match edit {
+            Edit::Add(objref, attrs) => Node::Add(objref, attrs),
+            Edit::Delete => Node::Delete,
+            Edit::SetAttrs(attrs) => Node::Branch(HashMap::new(), Some(attrs)),
         }
     }
 
-    fn chain(path: List<String>, value: Option<ObjectRef<Handle<B>>>) -> Self {
+    fn chain(path: List<String>, edit: Edit<B>) -> Self {
         match path.uncons() {
-            Some((head, tail)) => Self::singleton(head, Self::chain(tail, value)),
-            None => Self::leaf(value),
+            Some((head, tail)) => Self::singleton(head, Self::chain(tail, edit)),
+            None => Self::leaf(edit),
         }
     }
 
+    fn expand(tree: Tree<Handle<B>>) -> HashMap<Arc<String>, Self> {
+        tree.into_iter_with_attrs()
+            .map(|(k, v, attrs)| (Arc::new(k), Self::leaf(Edit::Add(v, attrs))))
+            .collect()
+    }
+
     #[async]
     fn do_insert(
         head: Arc<String>,
         tail: List<String>,
-        value: Option<ObjectRef<Handle<B>>>,
+        edit: Edit<B>,
         mut map: HashMap<Arc<String>, Self>,
     ) -> Result<HashMap<Arc<String>, Self>, Error> {
         match map.remove(&head) {
             Some(entry) => {
-                let node = await!(entry.insert(tail, value))?;
+                let node = await!(entry.insert(tail, edit))?;
                 map.insert(head, node);
             }
             None => {
-                map.insert(head, Self::chain(tail, value));
+                map.insert(head, Self::chain(tail, edit));
             }
         };
         Ok(map)
     }
 
     #[async(boxed)]
-    fn insert(
-        self,
-        path: List<String>,
-        value: Option<ObjectRef<Handle<B>>>,
-    ) -> Result<Self, Error> {
+    fn insert(self, path: List<String>, edit: Edit<B>) -> Result<Self, Error> {
         match (path.uncons(), self) {
-            (Some((head, tail)), Node::Add(ObjectRef::Tree(tree_ref))) => {
+            (Some((head, tail)), Node::Add(ObjectRef::Tree(tree_ref), attrs)) => {
                 let tree = await!(tree_ref.fetch()).context("Error fetching unloaded branch node")?;
-                let mut map = tree.into_iter()
-                    .map(|(k, v)| (Arc::new(k), Self::leaf(Some(v))))
-                    .collect::<HashMap<_, _>>();
-                let new_map = await!(Self::do_insert(head, tail, value, map))?;
-                Ok(Node::Branch(new_map))
+                let mut map = Self::expand(tree);
+                let new_map = await!(Self::do_insert(head, tail, edit, map))?;
+                Ok(Node::Branch(new_map, Some(attrs)))
             }
-            (Some((head, tail)), Node::Branch(mut map)) => Ok(Node::Branch(await!(
-                Self::do_insert(head, tail, value, map)
-            )?)),
-            (Some((head, tail)), _) => Ok(Self::singleton(head, Self::chain(tail, value))),
-            (None, _) => Ok(Node::leaf(value)),
+            (Some((head, tail)), Node::Branch(mut map, attrs)) => Ok(Node::Branch(
+                await!(Self::do_insert(head, tail, edit, map))?,
+                attrs,
+            )),
+            (Some((head, tail)), _) => Ok(Self::singleton(head, Self::chain(tail, edit))),
+
+            // Setting the attributes of a subtree leaves its entries as they are.
+            (None, Node::Add(ObjectRef::Tree(tree_ref), _)) => match edit {
+                Edit::SetAttrs(attrs) => Ok(Node::Add(ObjectRef::Tree(tree_ref), attrs)),
+                edit => Ok(Node::leaf(edit)),
+            },
+            (None, Node::Branch(map, _)) => match edit {
+                Edit::SetAttrs(attrs) => Ok(Node::Branch(map, Some(attrs))),
+                edit => Ok(Node::leaf(edit)),
+            },
+            (None, _) => Ok(Node::leaf(edit)),
         }
     }
 
@@ -87,6 +110,7 @@ impl<B: Backend> Node<B> {
         head: Arc<String>,
         tail: List<String>,
         mut map: HashMap<Arc<String>, Self>,
+        attrs: Option<EntryAttrs<Handle<B>>>,
     ) -> Result<Self, Error> {
         if tail.is_empty() {
             map.remove(&head);
@@ -95,21 +119,21 @@ impl<B: Backend> Node<B> {
             map.insert(head, node);
         }
 
-        Ok(Node::Branch(map))
+        Ok(Node::Branch(map, attrs))
     }
 
     #[allow(dead_code)]
     #[async(boxed)]
     fn remove(self, path: List<String>) -> Result<Self, Error> {
         match (path.uncons(), self) {
-            (Some((head, tail)), Node::Add(ObjectRef::Tree(tree_ref))) => {
+            (Some((head, tail)), Node::Add(ObjectRef::Tree(tree_ref), attrs)) => {
                 let tree = await!(tree_ref.fetch())?;
-                let mut map = tree.into_iter()
-                    .map(|(k, v)| (Arc::new(k), Self::leaf(Some(v))))
-                    .collect::<HashMap<_, _>>();
-                await!(Self::do_remove(head, tail, map))
+                let mut map = Self::expand(tree);
+                await!(Self::do_remove(head, tail, map, Some(attrs)))
+            }
+            (Some((head, tail)), Node::Branch(map, attrs)) => {
+                await!(Self::do_remove(head, tail, map, attrs))
             }
-            (Some((head, tail)), Node::Branch(map)) => await!(Self::do_remove(head, tail, map)),
             (Some(_), _) => bail!("No such object to remove!"),
             (None, _) => Ok(Self::new()),
         }
@@ -118,15 +142,20 @@ impl<B: Backend> Node<B> {
 
 #[derive(Debug, Clone)]
 pub enum Operation<B: Backend> {
-    Add(ObjectPath, ObjectRef<Handle<B>>),
+    Add(ObjectPath, ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>),
     Delete(ObjectPath),
+
+    /// Set the attributes of the subtree at a path, creating it if need be, without touching its
+    /// entries.
+    SetAttrs(ObjectPath, EntryAttrs<Handle<B>>),
 }
 
 impl<B: Backend> Operation<B> {
     pub fn as_object_path(&self) -> &ObjectPath {
         match *self {
-            Operation::Add(ref object_path, _) => object_path,
+            Operation::Add(ref object_path, _, _) => object_path,
             Operation::Delete(ref object_path) => object_path,
+            Operation::SetAttrs(ref object_path, _) => object_path,
         }
     }
 }
@@ -134,28 +163,41 @@ impl<B: Backend> Operation<B> {
 #[derive(Debug, Clone)]
 pub struct Batch<B: Backend> {
     root: HashMap<Arc<String>, Node<B>>,
+
+    // Whether subtrees left empty by the batch are kept rather than removed from their parents.
+    keep_empty: bool,
 }
 
 impl<B: Backend> Batch<B> {
     pub fn new() -> Self {
         Self {
             root: HashMap::new(),
+            keep_empty: false,
         }
     }
 
+    /// Keep subtrees which end up empty after running the batch, rather than removing them. This
+    /// is how empty directories are preserved; it is off by default, since trees built with and
+    /// without it differ.
+    pub fn keep_empty(self, keep_empty: bool) -> Self {
+        Self { keep_empty, ..self }
+    }
+
     #[async]
     pub fn add(self, op: Operation<B>) -> Result<Self, Error> {
-        let (path, value) = match op {
-            Operation::Add(path, value) => (path, Some(value)),
-            Operation::Delete(path) => (path, None),
+        let (path, edit) = match op {
+            Operation::Add(path, value, attrs) => (path, Edit::Add(value, attrs)),
+            Operation::Delete(path) => (path, Edit::Delete),
+            Operation::SetAttrs(path, attrs) => (path, Edit::SetAttrs(attrs)),
         };
         let (head, tail) = path.inner
             .uncons()
             .ok_or_else(|| format_err!("Cannot replace or delete the root node!"))?;
-        let root = await!(Node::do_insert(head, tail, value, self.root))
+        let keep_empty = self.keep_empty;
+        let root = await!(Node::do_insert(head, tail, edit, self.root))
             .context("Error while inserting operation into batch trie")?;
 
-        Ok(Self { root })
+        Ok(Self { root, keep_empty })
     }
 
     #[async]
@@ -164,7 +206,8 @@ impl<B: Backend> Batch<B> {
         store: Store<B>,
         tree_builder: TreeBuilder<Handle<B>>,
     ) -> Result<TreeBuilder<Handle<B>>, Error> {
-        Ok(await!(self.into_iter().run(store, tree_builder))?)
+        let keep_empty = self.keep_empty;
+        Ok(await!(self.into_iter().run(store, tree_builder, keep_empty))?)
     }
 }
 
@@ -178,9 +221,10 @@ impl<B: Backend> IntoIterator for Batch<B> {
 }
 
 pub enum BatchedOp<B: Backend> {
-    Add(ObjectRef<Handle<B>>),
+    Add(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>),
     Delete,
-    Recurse(BatchIter<B>),
+    /// Changes beneath a subtree, along with its new attributes if they change, too.
+    Recurse(BatchIter<B>, Option<EntryAttrs<Handle<B>>>),
 }
 
 pub struct BatchIter<B: Backend>(<HashMap<Arc<String>, Node<B>> as IntoIterator>::IntoIter);
@@ -190,9 +234,12 @@ impl<B: Backend> Iterator for BatchIter<B> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.0.next().map(|(name, node)| match node {
-            Node::Add(objref) => (name, BatchedOp::Add(objref)),
+            Node::Add(objref, attrs) => (name, BatchedOp::Add(objref, attrs)),
             Node::Delete => (name, BatchedOp::Delete),
-            Node::Branch(map) => (name, BatchedOp::Recurse(BatchIter(map.into_iter()))),
+            Node::Branch(map, attrs) => (
+                name,
+                BatchedOp::Recurse(BatchIter(map.into_iter()), attrs),
+            ),
         })
     }
 }
@@ -204,31 +251,40 @@ impl<B: Backend> BatchIter<B> {
         self,
         store: Store<B>,
         mut tree_builder: TreeBuilder<Handle<B>>,
+        keep_empty: bool,
     ) -> Result<TreeBuilder<Handle<B>>, Error> {
         for (name, batched_op) in self {
             match batched_op {
-                BatchedOp::Add(objref) => {
-                    tree_builder.insert(
+                BatchedOp::Add(objref, attrs) => {
+                    tree_builder.insert_with_attrs(
                         Arc::try_unwrap(name).unwrap_or_else(|arcd| (*arcd).clone()),
                         objref,
+                        attrs,
                     );
                 }
                 BatchedOp::Delete => {
                     tree_builder.remove(name.as_ref());
                 }
-                BatchedOp::Recurse(batch_iter) => {
-                    let child_builder = match tree_builder.remove(name.as_ref()) {
-                        Some(ObjectRef::Tree(tree_ref)) => await!(tree_ref.fetch())?.diverge(),
-                        _ => TreeBuilder::new(),
+                BatchedOp::Recurse(batch_iter, new_attrs) => {
+                    // A subtree keeps its attributes unless given new ones; anything replaced by
+                    // a subtree loses its own.
+                    let old_attrs = tree_builder.attrs(name.as_ref());
+                    let (child_builder, old_attrs) = match tree_builder.remove(name.as_ref()) {
+                        Some(ObjectRef::Tree(tree_ref)) => {
+                            (await!(tree_ref.fetch())?.diverge(), old_attrs)
+                        }
+                        _ => (TreeBuilder::new(), EntryAttrs::default()),
                     };
 
-                    let child_built = await!(batch_iter.run(store.clone(), child_builder))?;
+                    let child_built =
+                        await!(batch_iter.run(store.clone(), child_builder, keep_empty))?;
 
-                    if !child_built.is_empty() {
+                    if keep_empty || !child_built.is_empty() {
                         let child_ref = await!(child_built.as_tree().send(&store))?;
-                        tree_builder.insert(
+                        tree_builder.insert_with_attrs(
                             Arc::try_unwrap(name).unwrap_or_else(|arcd| (*arcd).clone()),
                             ObjectRef::Tree(child_ref),
+                            new_attrs.unwrap_or(old_attrs),
                         );
                     }
                 }
@@ -238,3 +294,81 @@ impl<B: Backend> BatchIter<B> {
         Ok(tree_builder)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use object::SmallBuilder;
+    use store::dummy::MemoryBackend;
+
+    /// A tree holding `dir/file` and nothing else, along with the handle of `file`.
+    fn nested(
+        store: &Store<MemoryBackend>,
+    ) -> (Tree<Handle<MemoryBackend>>, ObjectRef<Handle<MemoryBackend>>) {
+        let small_ref = SmallBuilder::new().as_small().send(store).wait().unwrap();
+        let file_ref = ObjectRef::Small(small_ref);
+        let mut dir_builder = TreeBuilder::new();
+        dir_builder.insert("file".to_owned(), file_ref.clone());
+        let dir_ref = dir_builder.as_tree().send(store).wait().unwrap();
+
+        let mut root_builder = TreeBuilder::new();
+        root_builder.insert("dir".to_owned(), ObjectRef::Tree(dir_ref));
+        (root_builder.into_tree(), file_ref)
+    }
+
+    fn delete_file(
+        store: &Store<MemoryBackend>,
+        keep_empty: bool,
+    ) -> TreeBuilder<Handle<MemoryBackend>> {
+        let (root, _) = nested(store);
+        let path = ObjectPath::from_path("dir/file").unwrap();
+        let batch = Batch::new()
+            .keep_empty(keep_empty)
+            .add(Operation::Delete(path))
+            .wait()
+            .unwrap();
+        batch.run(store.clone(), root.diverge()).wait().unwrap()
+    }
+
+    #[test]
+    fn emptied_subtrees_are_removed_by_default() {
+        let store = Store::new(MemoryBackend::default());
+        let built = delete_file(&store, false);
+        assert!(built.as_tree().is_empty());
+    }
+
+    #[test]
+    fn emptied_subtrees_are_kept_if_asked() {
+        let store = Store::new(MemoryBackend::default());
+        let built = delete_file(&store, true);
+        match built.as_tree().get("dir") {
+            Some(&ObjectRef::Tree(ref dir_ref)) => {
+                assert!(dir_ref.fetch().wait().unwrap().is_empty())
+            }
+            other => panic!("Expected an empty subtree, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keep_empty_survives_adding_operations() {
+        let store = Store::new(MemoryBackend::default());
+        let (root, file_ref) = nested(&store);
+        let batch = Batch::new()
+            .keep_empty(true)
+            .add(Operation::Add(
+                ObjectPath::from_path("other/file").unwrap(),
+                file_ref,
+                EntryAttrs::default(),
+            ))
+            .and_then(|batch| {
+                batch.add(Operation::Delete(ObjectPath::from_path("dir/file").unwrap()))
+            })
+            .wait()
+            .unwrap();
+        let built = batch.run(store.clone(), root.diverge()).wait().unwrap();
+
+        assert!(built.as_tree().get("dir").is_some());
+        assert!(built.as_tree().get("other").is_some());
+    }
+}
@@ -8,7 +8,20 @@ use digest::prelude::*;
 
 const NUL: u8 = 0;
 
-pub fn encode<W: Write, D: Digest>(w: &mut W, blob: &[u8], refs: &[D]) -> Result<(), Error> {
+/// Write the canonical form of an object.
+///
+/// The canonical form consists of a header (the digest name and size, the length of the blob, and
+/// the number of refs and arguments), the digest of the blob, the digests of the refs, and finally
+/// the digests of the arguments. Refs are the structural children of an object (the chunks of a
+/// large blob, the entries of a tree, the subtree and parents of a commit) while arguments are
+/// objects attached to it which are hashed separately, such as metadata. Objects without
+/// arguments have exactly the same canonical form as they did before arguments were introduced.
+pub fn encode<W: Write, D: Digest>(
+    w: &mut W,
+    blob: &[u8],
+    refs: &[D],
+    args: &[D],
+) -> Result<(), Error> {
     let hash_name_bytes = D::SIGNATURE.name.as_bytes();
     ensure!(
         memchr::memchr(NUL, hash_name_bytes).is_none(),
@@ -20,13 +33,17 @@ pub fn encode<W: Write, D: Digest>(w: &mut W, blob: &[u8], refs: &[D]) -> Result
     leb128::write::unsigned(w, D::SIGNATURE.size as u64)?;
     leb128::write::unsigned(w, blob.len() as u64)?;
     leb128::write::unsigned(w, refs.len() as u64)?;
-    leb128::write::unsigned(w, 0)?;
+    leb128::write::unsigned(w, args.len() as u64)?;
     w.write_all(D::digest(blob).as_bytes())?;
 
     for digest in refs {
         w.write_all(digest.as_bytes())?;
     }
 
+    for digest in args {
+        w.write_all(digest.as_bytes())?;
+    }
+
     Ok(())
 }
 
@@ -48,7 +65,7 @@ pub fn decode<R: Read>(r: &mut R) -> Result<PartialItem, Error> {
     let hash_size = leb128::read::unsigned(&mut buf_reader)? as usize;
     let blob_len = leb128::read::unsigned(&mut buf_reader)? as usize;
     let ref_count = leb128::read::unsigned(&mut buf_reader)? as usize;
-    let _num_args = leb128::read::unsigned(&mut buf_reader)? as usize;
+    let arg_count = leb128::read::unsigned(&mut buf_reader)? as usize;
 
     let mut digests = Vec::new();
     buf_reader.read_to_end(&mut digests)?;
@@ -58,6 +75,7 @@ pub fn decode<R: Read>(r: &mut R) -> Result<PartialItem, Error> {
         hash_size,
         blob_len,
         ref_count,
+        arg_count,
         digests,
     })
 }
@@ -67,6 +85,7 @@ pub struct PartialItem {
     hash_size: usize,
     blob_len: usize,
     ref_count: usize,
+    arg_count: usize,
     digests: Vec<u8>,
 }
 
@@ -91,15 +110,25 @@ impl PartialItem {
             size,
         );
 
-        let (blob_digest_bytes, ref_bytes) = self.digests.split_at(size);
+        ensure!(
+            self.digests.len() == size * (1 + self.ref_count + self.arg_count),
+            "Expected {} refs and {} args, but found {} bytes of digests",
+            self.ref_count,
+            self.arg_count,
+            self.digests.len(),
+        );
+
+        let (blob_digest_bytes, digest_bytes) = self.digests.split_at(size);
+        let (ref_bytes, arg_bytes) = digest_bytes.split_at(size * self.ref_count);
         let blob_digest = D::from_bytes(blob_digest_bytes);
-        assert!(ref_bytes.len() % size == 0 && ref_bytes.len() / size == self.ref_count);
         let refs = ref_bytes.chunks(size).map(D::from_bytes).collect();
+        let args = arg_bytes.chunks(size).map(D::from_bytes).collect();
 
         Ok(Item {
             blob_len: self.blob_len,
             blob_digest,
             refs,
+            args,
         })
     }
 }
@@ -108,6 +137,7 @@ pub struct Item<D: Digest> {
     pub blob_len: usize,
     pub blob_digest: D,
     pub refs: Vec<D>,
+    pub args: Vec<D>,
 }
 
 #[cfg(test)]
@@ -117,6 +147,7 @@ mod tests {
     use proptest::prelude::*;
 
     use digest::Sha3Digest;
+    use object::{decode::unescape_literal, encode::escape_literal};
 
     prop_compose! {
         fn arb_sha3()
@@ -128,15 +159,45 @@ mod tests {
     proptest! {
         #[test]
         fn roundtrip_canonical_sha3(ref bytes in prop::collection::vec(any::<u8>(), 0..1024),
-                                    ref digests in prop::collection::vec(arb_sha3(), 0..1024)) {
+                                    ref digests in prop::collection::vec(arb_sha3(), 0..1024),
+                                    ref args in prop::collection::vec(arb_sha3(), 0..16)) {
             let mut buf = Vec::new();
-            encode::<_, Sha3Digest>(&mut buf, bytes, digests).unwrap();
+            encode::<_, Sha3Digest>(&mut buf, bytes, digests, args).unwrap();
             let partial = decode(&mut &buf[..]).unwrap();
             assert_eq!(partial.hash_name(), Sha3Digest::SIGNATURE.name);
             assert_eq!(partial.hash_size(), Sha3Digest::SIGNATURE.size);
             let item = partial.finish::<Sha3Digest>().unwrap();
             assert_eq!(item.blob_len, bytes.len());
             assert_eq!(&item.refs, digests);
+            assert_eq!(&item.args, args);
+        }
+
+        #[test]
+        fn args_distinguished_from_refs(ref bytes in prop::collection::vec(any::<u8>(), 0..1024),
+                                        ref digest in arb_sha3()) {
+            let mut as_ref = Sha3Digest::writer();
+            encode::<_, Sha3Digest>(&mut as_ref, bytes, &[digest.clone()], &[]).unwrap();
+            let mut as_arg = Sha3Digest::writer();
+            encode::<_, Sha3Digest>(&mut as_arg, bytes, &[], &[digest.clone()]).unwrap();
+            assert!(as_ref.finish() != as_arg.finish());
+        }
+
+        #[test]
+        fn roundtrip_literal(ref string in "(?s).*") {
+            let escaped = escape_literal(string);
+            assert!(escaped.is_ascii() && !escaped.contains('\n'));
+            assert_eq!(&unescape_literal(&escaped).unwrap(), string);
+        }
+
+        #[test]
+        fn decode_legacy_literal(ref string in "(?s).*") {
+            // Commits used to be written with `ascii::escape_default`, byte by byte.
+            let escaped = string
+                .bytes()
+                .flat_map(::std::ascii::escape_default)
+                .map(char::from)
+                .collect::<String>();
+            assert_eq!(&unescape_literal(&escaped).unwrap(), string);
         }
     }
 }
@@ -0,0 +1,639 @@
+//! Comparing two trees.
+//!
+//! Subtrees which are the same on both sides share a handle, so they are skipped without ever
+//! being fetched. Only the parts of the trees which actually differ are walked.
+//!
+//! Since objects are addressed by content, renames and copies are found exactly by matching
+//! digests. An added entry with the same digest as a removed one is a rename. An added entry with
+//! the same digest as the old version of a modified or renamed entry is a copy; as with
+//! `git diff -C`, unchanged entries are never considered as sources of copies. Renamed and copied
+//! directories are found as a whole when their contents are identical, and file by file otherwise.
+//! Directories are only opened up while there is something left for their contents to match;
+//! otherwise they are reported as added or removed as a whole, without ever being walked.
+
+use std::{collections::{HashMap, VecDeque}, hash::Hash};
+
+use failure::Error;
+use futures::{stream, prelude::*};
+
+use digest::Digest;
+use object::{EntryAttrs, ObjectRef, Tree, TreeRef};
+use path::ObjectPath;
+use store::prelude::*;
+
+/// An entry of a tree: an object along with the attributes recorded for it in its parent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Entry<H> {
+    pub objref: ObjectRef<H>,
+    pub attrs: EntryAttrs<H>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<H> {
+    Added {
+        path: ObjectPath,
+        new: Entry<H>,
+    },
+    Removed {
+        path: ObjectPath,
+        old: Entry<H>,
+    },
+
+    /// The entry at a path was changed, either in content or in attributes. Paths which changed
+    /// between a directory and anything else are reported as removed and added instead.
+    Modified {
+        path: ObjectPath,
+        old: Entry<H>,
+        new: Entry<H>,
+    },
+
+    /// An entry was moved with its content intact, though its attributes may have changed.
+    Renamed {
+        from: ObjectPath,
+        to: ObjectPath,
+        old: Entry<H>,
+        new: Entry<H>,
+    },
+
+    /// An entry was added with the same content as another entry which also still exists.
+    Copied {
+        from: ObjectPath,
+        to: ObjectPath,
+        old: Entry<H>,
+        new: Entry<H>,
+    },
+}
+
+impl<H> Change<H> {
+    /// The path of the changed entry in the new tree, or in the old tree if it was removed.
+    pub fn path(&self) -> &ObjectPath {
+        match *self {
+            Change::Added { ref path, .. }
+            | Change::Removed { ref path, .. }
+            | Change::Modified { ref path, .. } => path,
+            Change::Renamed { ref to, .. } | Change::Copied { ref to, .. } => to,
+        }
+    }
+}
+
+/// Stream the changes between two trees, using digests of type `D` to find renames and copies.
+///
+/// Modifications are streamed as soon as they are found, in no particular order. Renames, copies,
+/// additions and removals can only be told apart once both trees have been walked, so they follow.
+pub fn diff_trees<B: Backend, D: Digest>(
+    old: TreeRef<Handle<B>>,
+    new: TreeRef<Handle<B>>,
+) -> impl Stream<Item = Change<Handle<B>>, Error = Error> {
+    do_diff_trees::<B, D>(old, new)
+}
+
+/// Pair up the entries of two trees by name, in order.
+fn merge_entries<H>(
+    old: Tree<H>,
+    new: Tree<H>,
+) -> Vec<(String, Option<Entry<H>>, Option<Entry<H>>)> {
+    let mut old_iter = old.into_iter_with_attrs().peekable();
+    let mut new_iter = new.into_iter_with_attrs().peekable();
+    let mut merged = Vec::new();
+
+    loop {
+        let take_old = match (old_iter.peek(), new_iter.peek()) {
+            (Some(&(ref old_name, _, _)), Some(&(ref new_name, _, _))) => {
+                Some(old_name <= new_name)
+            }
+            (Some(_), None) => Some(true),
+            (None, Some(_)) => Some(false),
+            (None, None) => None,
+        };
+
+        let pair = match take_old {
+            Some(true) => {
+                let (name, objref, attrs) = old_iter.next().unwrap();
+                let old_entry = Entry { objref, attrs };
+                let same_name = new_iter.peek().map(|&(ref n, _, _)| n == &name) == Some(true);
+                if same_name {
+                    let (_, objref, attrs) = new_iter.next().unwrap();
+                    (name, Some(old_entry), Some(Entry { objref, attrs }))
+                } else {
+                    (name, Some(old_entry), None)
+                }
+            }
+            Some(false) => {
+                let (name, objref, attrs) = new_iter.next().unwrap();
+                (name, None, Some(Entry { objref, attrs }))
+            }
+            None => break,
+        };
+
+        merged.push(pair);
+    }
+
+    merged
+}
+
+/// An added or removed entry waiting to be matched up, along with its digest.
+#[derive(Debug, Clone)]
+struct Pending<H, K> {
+    path: ObjectPath,
+    entry: Entry<H>,
+    key: K,
+}
+
+/// Match added entries against removed entries and sources of copies with the same key, returning
+/// the resulting renames and copies along with whatever is left unmatched on either side.
+///
+/// Renames are preferred over copies, and entries are matched in path order. The old side of every
+/// rename becomes a source of copies.
+fn pair_up<H: Clone, K: Hash + Eq + Clone>(
+    mut added: Vec<Pending<H, K>>,
+    mut removed: Vec<Pending<H, K>>,
+    sources: &mut HashMap<K, (ObjectPath, Entry<H>)>,
+) -> (Vec<Change<H>>, Vec<Pending<H, K>>, Vec<Pending<H, K>>) {
+    added.sort_by(|a, b| a.path.cmp(&b.path));
+    removed.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut by_key = HashMap::new();
+    for pending in removed {
+        by_key
+            .entry(pending.key.clone())
+            .or_insert_with(VecDeque::new)
+            .push_back(pending);
+    }
+
+    let mut changes = Vec::new();
+    let mut unrenamed = Vec::new();
+    for pending in added {
+        let renamed_from = by_key
+            .get_mut(&pending.key)
+            .and_then(|removed: &mut VecDeque<Pending<H, K>>| removed.pop_front());
+        match renamed_from {
+            Some(old) => {
+                let source = (old.path.clone(), old.entry.clone());
+                sources.entry(old.key).or_insert(source);
+                changes.push(Change::Renamed {
+                    from: old.path,
+                    to: pending.path,
+                    old: old.entry,
+                    new: pending.entry,
+                });
+            }
+            None => unrenamed.push(pending),
+        }
+    }
+
+    let mut unmatched_added = Vec::new();
+    for pending in unrenamed {
+        match sources.get(&pending.key).cloned() {
+            Some((from, old)) => changes.push(Change::Copied {
+                from,
+                to: pending.path,
+                old,
+                new: pending.entry,
+            }),
+            None => unmatched_added.push(pending),
+        }
+    }
+
+    let mut unmatched_removed = by_key
+        .into_iter()
+        .flat_map(|(_, removed)| removed)
+        .collect::<Vec<_>>();
+    unmatched_removed.sort_by(|a, b| a.path.cmp(&b.path));
+
+    (changes, unmatched_added, unmatched_removed)
+}
+
+fn is_tree<H, K>(pending: &Pending<H, K>) -> bool {
+    match pending.entry.objref {
+        ObjectRef::Tree(_) => true,
+        _ => false,
+    }
+}
+
+#[async]
+fn digest_entries<B: Backend, D: Digest>(
+    entries: Vec<(ObjectPath, Entry<Handle<B>>)>,
+) -> Result<Vec<Pending<Handle<B>, ObjectRef<D>>>, Error> {
+    let futures = entries.into_iter().map(|(path, entry)| {
+        let future_digest = entry.objref.digest::<D>();
+        future_digest.map(move |key| Pending { path, entry, key })
+    });
+
+    Ok(await!(stream::futures_ordered(futures).collect())?)
+}
+
+#[async]
+fn expand<B: Backend>(
+    path: ObjectPath,
+    tree_ref: TreeRef<Handle<B>>,
+) -> Result<Vec<(ObjectPath, Entry<Handle<B>>)>, Error> {
+    let tree = await!(tree_ref.fetch())?;
+
+    Ok(tree.into_iter_with_attrs()
+        .map(|(name, objref, attrs)| (path.push_back(name), Entry { objref, attrs }))
+        .collect())
+}
+
+#[async_stream(item = Change<Handle<B>>)]
+fn do_diff_trees<B: Backend, D: Digest>(
+    old_ref: TreeRef<Handle<B>>,
+    new_ref: TreeRef<Handle<B>>,
+) -> Result<(), Error> {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut modified = Vec::new();
+
+    let mut stack = vec![(ObjectPath::new(), old_ref, new_ref)];
+    while let Some((path, old_ref, new_ref)) = stack.pop() {
+        if old_ref == new_ref {
+            continue;
+        }
+
+        let (old_tree, new_tree) = await!(old_ref.fetch().join(new_ref.fetch()))?;
+        for (name, maybe_old, maybe_new) in merge_entries(old_tree, new_tree) {
+            let child_path = path.push_back(name);
+            match (maybe_old, maybe_new) {
+                (Some(old), None) => removed.push((child_path, old)),
+                (None, Some(new)) => added.push((child_path, new)),
+                (Some(old), Some(new)) => {
+                    if old == new {
+                        continue;
+                    }
+
+                    match (old.objref.clone(), new.objref.clone()) {
+                        (ObjectRef::Tree(old_child), ObjectRef::Tree(new_child)) => {
+                            stack.push((child_path, old_child, new_child));
+                        }
+                        (ObjectRef::Tree(_), _) | (_, ObjectRef::Tree(_)) => {
+                            removed.push((child_path.clone(), old));
+                            added.push((child_path, new));
+                        }
+                        _ => {
+                            modified.push((child_path.clone(), old.clone()));
+                            stream_yield!(Change::Modified {
+                                path: child_path,
+                                old,
+                                new,
+                            });
+                        }
+                    }
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+    }
+
+    let mut sources = HashMap::new();
+    for pending in await!(digest_entries::<B, D>(modified))? {
+        sources
+            .entry(pending.key)
+            .or_insert((pending.path, pending.entry));
+    }
+
+    let mut added = await!(digest_entries::<B, D>(added))?;
+    let mut removed = await!(digest_entries::<B, D>(removed))?;
+
+    // Match whole entries first. Directories which couldn't be matched as a whole are then opened
+    // up and their contents matched in turn, until nothing but unmatched data is left, or nothing
+    // is left on the other side to match against.
+    loop {
+        let (changes, unmatched_added, unmatched_removed) = pair_up(added, removed, &mut sources);
+        for change in changes {
+            stream_yield!(change);
+        }
+
+        // Added entries may be renames of removed ones or copies of sources; removed entries may
+        // only be renamed to added ones.
+        let added_may_match = !unmatched_removed.is_empty() || !sources.is_empty();
+        let removed_may_match = !unmatched_added.is_empty();
+
+        let (added_trees, added_data): (Vec<_>, Vec<_>) = if added_may_match {
+            unmatched_added.into_iter().partition(is_tree)
+        } else {
+            (Vec::new(), unmatched_added)
+        };
+        let (removed_trees, removed_data): (Vec<_>, Vec<_>) = if removed_may_match {
+            unmatched_removed.into_iter().partition(is_tree)
+        } else {
+            (Vec::new(), unmatched_removed)
+        };
+
+        // Whatever is left, directories included, is only added or removed.
+        if added_trees.is_empty() && removed_trees.is_empty() {
+            for pending in removed_data {
+                stream_yield!(Change::Removed {
+                    path: pending.path,
+                    old: pending.entry,
+                });
+            }
+
+            for pending in added_data {
+                stream_yield!(Change::Added {
+                    path: pending.path,
+                    new: pending.entry,
+                });
+            }
+
+            break;
+        }
+
+        added = added_data;
+        removed = removed_data;
+
+        for pending in added_trees {
+            let children = match pending.entry.objref.clone() {
+                ObjectRef::Tree(tree_ref) => await!(expand(pending.path.clone(), tree_ref))?,
+                _ => unreachable!(),
+            };
+
+            // An empty directory has no contents to match, so it stands on its own.
+            if children.is_empty() {
+                stream_yield!(Change::Added {
+                    path: pending.path,
+                    new: pending.entry,
+                });
+            } else {
+                added.extend(await!(digest_entries::<B, D>(children))?);
+            }
+        }
+
+        for pending in removed_trees {
+            let children = match pending.entry.objref.clone() {
+                ObjectRef::Tree(tree_ref) => await!(expand(pending.path.clone(), tree_ref))?,
+                _ => unreachable!(),
+            };
+
+            if children.is_empty() {
+                stream_yield!(Change::Removed {
+                    path: pending.path,
+                    old: pending.entry,
+                });
+            } else {
+                removed.extend(await!(digest_entries::<B, D>(children))?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Write, sync::atomic};
+
+    use digest::Sha3Digest;
+    use object::{FileMode, SmallBuilder, SmallRef, TreeBuilder};
+    use store::dummy::MemoryBackend;
+
+    fn data(handle: u64) -> ObjectRef<u64> {
+        ObjectRef::Small(SmallRef::new(handle, handle))
+    }
+
+    fn path(components: &[&str]) -> ObjectPath {
+        components
+            .iter()
+            .fold(ObjectPath::new(), |path, &c| path.push_back(c.to_owned()))
+    }
+
+    fn pending(components: &[&str], handle: u64) -> Pending<u64, u64> {
+        Pending {
+            path: path(components),
+            entry: Entry {
+                objref: data(handle),
+                attrs: EntryAttrs::default(),
+            },
+            key: handle,
+        }
+    }
+
+    fn send_data(store: &Store<MemoryBackend>, bytes: &[u8]) -> ObjectRef<Handle<MemoryBackend>> {
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(bytes).unwrap();
+        ObjectRef::Small(small_builder.as_small().send(store).wait().unwrap())
+    }
+
+    fn send_tree(
+        store: &Store<MemoryBackend>,
+        entries: Vec<(&str, ObjectRef<Handle<MemoryBackend>>)>,
+    ) -> ObjectRef<Handle<MemoryBackend>> {
+        let mut tree_builder = TreeBuilder::new();
+        for (name, objref) in entries {
+            tree_builder.insert(name.to_owned(), objref);
+        }
+        ObjectRef::Tree(tree_builder.as_tree().send(store).wait().unwrap())
+    }
+
+    /// Diff two trees, summarizing each change by its kind and path, in path order.
+    fn diff(
+        old: ObjectRef<Handle<MemoryBackend>>,
+        new: ObjectRef<Handle<MemoryBackend>>,
+    ) -> Vec<(&'static str, ObjectPath)> {
+        let (old_ref, new_ref) = match (old, new) {
+            (ObjectRef::Tree(old_ref), ObjectRef::Tree(new_ref)) => (old_ref, new_ref),
+            other => panic!("Expected two trees, got {:?}", other),
+        };
+
+        let mut summary = diff_trees::<_, Sha3Digest>(old_ref, new_ref)
+            .map(|change| {
+                let kind = match change {
+                    Change::Added { .. } => "added",
+                    Change::Removed { .. } => "removed",
+                    Change::Modified { .. } => "modified",
+                    Change::Renamed { .. } => "renamed",
+                    Change::Copied { .. } => "copied",
+                };
+                (kind, change.path().clone())
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        summary.sort_by(|a, b| a.1.cmp(&b.1));
+        summary
+    }
+
+    #[test]
+    fn merge_entries_pairs_by_name() {
+        let mut old = TreeBuilder::new();
+        old.insert("a".to_owned(), data(1));
+        old.insert("b".to_owned(), data(2));
+        old.insert("c".to_owned(), data(3));
+
+        let mut new = TreeBuilder::new();
+        new.insert("b".to_owned(), data(2));
+        new.insert("c".to_owned(), data(3));
+        new.set_mode("c", FileMode::Executable);
+        new.insert("d".to_owned(), data(4));
+
+        let merged = merge_entries(old.into_tree(), new.into_tree())
+            .into_iter()
+            .map(|(name, old, new)| {
+                (
+                    name,
+                    old.map(|entry| entry.attrs.mode),
+                    new.map(|entry| entry.attrs.mode),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            merged,
+            vec![
+                ("a".to_owned(), Some(FileMode::Regular), None),
+                (
+                    "b".to_owned(),
+                    Some(FileMode::Regular),
+                    Some(FileMode::Regular),
+                ),
+                (
+                    "c".to_owned(),
+                    Some(FileMode::Regular),
+                    Some(FileMode::Executable),
+                ),
+                ("d".to_owned(), None, Some(FileMode::Regular)),
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_up_prefers_renames_then_copies() {
+        let mut sources = HashMap::new();
+        sources.insert(
+            3,
+            (
+                path(&["modified"]),
+                Entry {
+                    objref: data(3),
+                    attrs: EntryAttrs::default(),
+                },
+            ),
+        );
+
+        let added = vec![
+            pending(&["moved"], 1),
+            pending(&["moved-too"], 1),
+            pending(&["copied"], 3),
+            pending(&["new"], 4),
+        ];
+        let removed = vec![pending(&["gone"], 1), pending(&["lost"], 2)];
+
+        let (changes, unmatched_added, unmatched_removed) = pair_up(added, removed, &mut sources);
+
+        let summary = changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Renamed { from, to, .. } => ("renamed", from, to),
+                Change::Copied { from, to, .. } => ("copied", from, to),
+                other => panic!("unexpected change {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("renamed", path(&["gone"]), path(&["moved"])),
+                ("copied", path(&["modified"]), path(&["copied"])),
+                ("copied", path(&["gone"]), path(&["moved-too"])),
+            ]
+        );
+
+        assert_eq!(
+            unmatched_added.into_iter().map(|p| p.path).collect::<Vec<_>>(),
+            vec![path(&["new"])]
+        );
+        assert_eq!(
+            unmatched_removed
+                .into_iter()
+                .map(|p| p.path)
+                .collect::<Vec<_>>(),
+            vec![path(&["lost"])]
+        );
+    }
+
+    #[test]
+    fn added_directories_are_reported_whole_when_nothing_was_removed() {
+        let backend = MemoryBackend::default();
+        let loads = backend.loads();
+        let store = Store::new(backend);
+
+        let kept = send_data(&store, b"kept");
+        let sub = send_tree(&store, vec![("y", send_data(&store, b"y"))]);
+        let dir = send_tree(&store, vec![("x", send_data(&store, b"x")), ("sub", sub)]);
+        let old = send_tree(&store, vec![("kept", kept.clone())]);
+        let new = send_tree(&store, vec![("kept", kept), ("dir", dir)]);
+
+        assert_eq!(diff(old, new), vec![("added", path(&["dir"]))]);
+
+        // Only the two roots were ever fetched.
+        assert_eq!(loads.load(atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn removed_directories_are_reported_whole_when_nothing_was_added() {
+        let backend = MemoryBackend::default();
+        let loads = backend.loads();
+        let store = Store::new(backend);
+
+        let sub = send_tree(&store, vec![("y", send_data(&store, b"y"))]);
+        let dir = send_tree(&store, vec![("x", send_data(&store, b"x")), ("sub", sub)]);
+        let old = send_tree(&store, vec![("dir", dir), ("file", send_data(&store, b"old"))]);
+        let new = send_tree(&store, vec![("file", send_data(&store, b"new"))]);
+
+        assert_eq!(
+            diff(old, new),
+            vec![("removed", path(&["dir"])), ("modified", path(&["file"]))]
+        );
+        assert_eq!(loads.load(atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn directories_are_opened_up_to_find_renamed_contents() {
+        let store = Store::new(MemoryBackend::default());
+
+        let moved = send_data(&store, b"moved");
+        let old_dir = send_tree(
+            &store,
+            vec![("x", moved.clone()), ("y", send_data(&store, b"dropped"))],
+        );
+        let new_dir = send_tree(
+            &store,
+            vec![("x", moved), ("z", send_data(&store, b"created"))],
+        );
+        let old = send_tree(&store, vec![("old", old_dir)]);
+        let new = send_tree(&store, vec![("new", new_dir)]);
+
+        assert_eq!(
+            diff(old, new),
+            vec![
+                ("renamed", path(&["new", "x"])),
+                ("added", path(&["new", "z"])),
+                ("removed", path(&["old", "y"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn added_directories_are_opened_up_to_find_copies() {
+        let store = Store::new(MemoryBackend::default());
+
+        let original = send_data(&store, b"original");
+        let dir = send_tree(
+            &store,
+            vec![("copy", original.clone()), ("other", send_data(&store, b"other"))],
+        );
+        let old = send_tree(&store, vec![("file", original)]);
+        let new = send_tree(
+            &store,
+            vec![("dir", dir), ("file", send_data(&store, b"edited"))],
+        );
+
+        assert_eq!(
+            diff(old, new),
+            vec![
+                ("copied", path(&["dir", "copy"])),
+                ("added", path(&["dir", "other"])),
+                ("modified", path(&["file"])),
+            ]
+        );
+    }
+}
@@ -5,7 +5,7 @@ use futures::{future::Shared, prelude::*};
 use im::List;
 use parking_lot::RwLock;
 
-use object::{FutureTree, ObjectRef, Tree, TreeRef};
+use object::{EntryAttrs, FutureTree, ObjectRef, Tree, TreeRef};
 use path::ObjectPath;
 use store::prelude::*;
 
@@ -27,6 +27,7 @@ impl<B: Backend> From<TreeRef<Handle<B>>> for Hierarchy<B> {
         Self {
             root: Some(Arc::new(RwLock::new(Node {
                 objref: ObjectRef::Tree(tree_ref),
+                attrs: EntryAttrs::default(),
                 state: NodeState::UnPolled,
             }))),
         }
@@ -42,6 +43,16 @@ impl<B: Backend> Hierarchy<B> {
         &self,
         path: ObjectPath,
     ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
+        self.get_with_attrs(path)
+            .map(|found| found.map(|(objref, _)| objref))
+    }
+
+    /// Look up an object along with the attributes of its entry in the parent tree.
+    pub fn get_with_attrs(
+        &self,
+        path: ObjectPath,
+    ) -> impl Future<Item = Option<(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>)>, Error = Error>
+    {
         let root = self.root.clone();
 
         async_block! {
@@ -146,6 +157,7 @@ enum Secondary<B: Backend> {
 #[derive(Debug, Clone)]
 struct Node<B: Backend> {
     objref: ObjectRef<Handle<B>>,
+    attrs: EntryAttrs<Handle<B>>,
     state: NodeState<B>,
 }
 
@@ -154,9 +166,12 @@ impl<B: Backend> Node<B> {
     fn get(
         this: Arc<RwLock<Self>>,
         path: List<String>,
-    ) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
+    ) -> Result<Option<(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>)>, Error> {
         match path.uncons() {
-            None => Ok(Some(this.read().objref.clone())),
+            None => {
+                let node = this.read();
+                Ok(Some((node.objref.clone(), node.attrs.clone())))
+            }
             Some((head, tail)) => {
                 let primary = {
                     let read_guard = this.read();
@@ -193,6 +208,7 @@ impl<B: Backend> Node<B> {
                                                 Arc::new(k.to_owned()),
                                                 Arc::new(RwLock::new(Self {
                                                     objref: v.to_owned(),
+                                                    attrs: shared_tree.attrs(k),
                                                     state: NodeState::UnPolled,
                                                 })),
                                             )
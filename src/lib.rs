@@ -19,19 +19,22 @@ extern crate futures_await as futures;
 extern crate hex;
 #[macro_use]
 extern crate im;
+#[macro_use]
+extern crate lazy_static;
 extern crate leb128;
 extern crate memchr;
 #[macro_use]
 extern crate nom;
-extern crate ntriple;
 extern crate parking_lot;
 extern crate sha3;
 extern crate uuid;
 
 pub mod batch;
 pub mod canonical;
+pub mod diff;
 pub mod digest;
 pub mod hierarchy;
+pub mod merge;
 pub mod object;
 pub mod path;
 pub mod split;
@@ -0,0 +1,702 @@
+//! Three-way merging of trees.
+//!
+//! Each entry is merged by comparing both sides against their common base. Wherever one side left
+//! an entry as it was in the base, the other side's version wins; subtrees which are resolved this
+//! way are reused as they are, without being fetched. Only subtrees changed on both sides are
+//! opened up and merged entry by entry.
+//!
+//! Entries which can't be merged are reported as conflicts. The merged tree still has to hold
+//! something at a conflicted path, so it keeps our version of the entry if we have one, and
+//! otherwise theirs.
+//!
+//! The attributes of directories and the named metadata of trees are merged the same way, except
+//! that where both sides changed them, ours are kept rather than reported as a conflict.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use failure::Error;
+use futures::prelude::*;
+
+use diff::Entry;
+use object::{EntryAttrs, ObjectRef, Tree, TreeBuilder, TreeRef};
+use path::ObjectPath;
+use store::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConflictKind {
+    /// Both sides added different entries where the base had none.
+    AddAdd,
+
+    /// Both sides changed an entry, in different ways.
+    ModifyModify,
+
+    /// One side changed an entry which the other side deleted.
+    ModifyDelete,
+
+    /// One side has a directory where the other side has something else.
+    TypeChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict<H> {
+    pub path: ObjectPath,
+    pub kind: ConflictKind,
+    pub base: Option<Entry<H>>,
+    pub ours: Option<Entry<H>>,
+    pub theirs: Option<Entry<H>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Merge<H> {
+    /// The merged tree, holding our version of every conflicted entry.
+    pub tree: TreeBuilder<H>,
+
+    /// Conflicts, ordered by path.
+    pub conflicts: Vec<Conflict<H>>,
+}
+
+impl<H> Merge<H> {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merge two trees descended from a common base. Without a base, as for two histories which
+/// share no commits, everything present on both sides is treated as added by both.
+///
+/// Subtrees merged entry by entry are sent to the store, since the merged tree refers to them.
+/// The merged tree itself is returned unsent, so that conflicts can be resolved first. Subtrees
+/// left empty by the merge are dropped unless `keep_empty` is set.
+pub fn merge_trees<B: Backend>(
+    store: Store<B>,
+    base: Option<TreeRef<Handle<B>>>,
+    ours: TreeRef<Handle<B>>,
+    theirs: TreeRef<Handle<B>>,
+    keep_empty: bool,
+) -> impl Future<Item = Merge<Handle<B>>, Error = Error> {
+    async_block! {
+        let trivial = if ours == theirs || Some(&theirs) == base.as_ref() {
+            Some(ours.clone())
+        } else if Some(&ours) == base.as_ref() {
+            Some(theirs.clone())
+        } else {
+            None
+        };
+
+        match trivial {
+            Some(tree_ref) => Ok(Merge {
+                tree: await!(tree_ref.fetch())?.diverge(),
+                conflicts: Vec::new(),
+            }),
+            None => await!(merge_subtrees(
+                store,
+                keep_empty,
+                ObjectPath::new(),
+                base,
+                ours,
+                theirs
+            )),
+        }
+    }
+}
+
+/// What to do with a single entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Resolution<H> {
+    /// Use this entry, or leave the path empty.
+    Take(Option<Entry<H>>),
+
+    /// Both sides changed a directory; merge their contents, and give the result these
+    /// attributes.
+    Recurse {
+        base: Option<TreeRef<H>>,
+        ours: TreeRef<H>,
+        theirs: TreeRef<H>,
+        attrs: EntryAttrs<H>,
+    },
+
+    /// The entry can't be merged. Keep the given entry in its place.
+    Conflict(Entry<H>, ConflictKind),
+}
+
+/// Pick whichever value changed relative to the base, if only one did.
+fn merge3<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+    if ours == theirs || theirs == base {
+        Some(ours.clone())
+    } else if ours == base {
+        Some(theirs.clone())
+    } else {
+        None
+    }
+}
+
+fn resolve<H: Clone + PartialEq>(
+    base: Option<&Entry<H>>,
+    ours: Option<&Entry<H>>,
+    theirs: Option<&Entry<H>>,
+) -> Resolution<H> {
+    if ours == theirs || theirs == base {
+        return Resolution::Take(ours.cloned());
+    } else if ours == base {
+        return Resolution::Take(theirs.cloned());
+    }
+
+    // Both sides changed the entry. If only one side deleted it, the other must have modified it,
+    // since neither side matches the base.
+    let (ours, theirs) = match (ours, theirs) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        (Some(ours), None) => {
+            return Resolution::Conflict(ours.clone(), ConflictKind::ModifyDelete);
+        }
+        (None, Some(theirs)) => {
+            return Resolution::Conflict(theirs.clone(), ConflictKind::ModifyDelete);
+        }
+        (None, None) => unreachable!("ours and theirs are different"),
+    };
+
+    match (&ours.objref, &theirs.objref) {
+        (&ObjectRef::Tree(ref our_tree), &ObjectRef::Tree(ref their_tree)) => {
+            let (base_tree, base_attrs) = match base {
+                Some(&Entry {
+                    objref: ObjectRef::Tree(ref base_tree),
+                    ref attrs,
+                }) => (Some(base_tree.clone()), attrs.clone()),
+                _ => (None, EntryAttrs::default()),
+            };
+
+            Resolution::Recurse {
+                base: base_tree,
+                ours: our_tree.clone(),
+                theirs: their_tree.clone(),
+                attrs: merge3(&base_attrs, &ours.attrs, &theirs.attrs)
+                    .unwrap_or_else(|| ours.attrs.clone()),
+            }
+        }
+        (&ObjectRef::Tree(_), _) | (_, &ObjectRef::Tree(_)) => {
+            Resolution::Conflict(ours.clone(), ConflictKind::TypeChange)
+        }
+        _ => match base {
+            None => Resolution::Conflict(ours.clone(), ConflictKind::AddAdd),
+
+            // Changes to an entry's content and to its attributes merge separately, so one side
+            // may change a file while the other makes it executable.
+            Some(base) => match (
+                merge3(&base.objref, &ours.objref, &theirs.objref),
+                merge3(&base.attrs, &ours.attrs, &theirs.attrs),
+            ) {
+                (Some(objref), Some(attrs)) => Resolution::Take(Some(Entry { objref, attrs })),
+                _ => Resolution::Conflict(ours.clone(), ConflictKind::ModifyModify),
+            },
+        },
+    }
+}
+
+/// Merge the named metadata of three trees, key by key.
+fn merge_metadata<H: Clone + PartialEq>(
+    base: &BTreeMap<String, ObjectRef<H>>,
+    ours: &BTreeMap<String, ObjectRef<H>>,
+    theirs: &BTreeMap<String, ObjectRef<H>>,
+) -> BTreeMap<String, ObjectRef<H>> {
+    let names = base.keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect::<BTreeSet<_>>();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let our_value = ours.get(name);
+            merge3(&base.get(name), &our_value, &theirs.get(name))
+                .unwrap_or(our_value)
+                .map(|objref| (name.clone(), objref.clone()))
+        })
+        .collect()
+}
+
+fn into_entries<H>(tree: Tree<H>) -> BTreeMap<String, Entry<H>> {
+    tree.into_iter_with_attrs()
+        .map(|(name, objref, attrs)| (name, Entry { objref, attrs }))
+        .collect()
+}
+
+fn insert_entry<H>(tree: &mut TreeBuilder<H>, name: String, entry: Entry<H>) {
+    tree.insert_with_attrs(name, entry.objref, entry.attrs);
+}
+
+#[async(boxed)]
+fn merge_subtrees<B: Backend>(
+    store: Store<B>,
+    keep_empty: bool,
+    path: ObjectPath,
+    base_ref: Option<TreeRef<Handle<B>>>,
+    our_ref: TreeRef<Handle<B>>,
+    their_ref: TreeRef<Handle<B>>,
+) -> Result<Merge<Handle<B>>, Error> {
+    let future_base = base_ref.map(|tree_ref| tree_ref.fetch());
+    let (base_tree, our_tree, their_tree) =
+        await!(future_base.join3(our_ref.fetch(), their_ref.fetch()))?;
+
+    let mut tree = TreeBuilder::new();
+    *tree.as_metadata_mut() = merge_metadata(
+        base_tree
+            .as_ref()
+            .map_or(&BTreeMap::new(), Tree::as_metadata),
+        our_tree.as_metadata(),
+        their_tree.as_metadata(),
+    );
+
+    let base = base_tree.map(into_entries).unwrap_or_default();
+    let ours = into_entries(our_tree);
+    let theirs = into_entries(their_tree);
+
+    let names = base.keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut merge = Merge {
+        tree,
+        conflicts: Vec::new(),
+    };
+
+    for name in names {
+        let child_path = path.push_back(name.clone());
+        let resolution = resolve(base.get(&name), ours.get(&name), theirs.get(&name));
+
+        match resolution {
+            Resolution::Take(Some(entry)) => insert_entry(&mut merge.tree, name, entry),
+            Resolution::Take(None) => {}
+            Resolution::Recurse {
+                base: base_child,
+                ours: our_child,
+                theirs: their_child,
+                attrs,
+            } => {
+                let child = await!(merge_subtrees(
+                    store.clone(),
+                    keep_empty,
+                    child_path,
+                    base_child,
+                    our_child,
+                    their_child
+                ))?;
+                merge.conflicts.extend(child.conflicts);
+
+                // Unless empty directories are kept, a directory emptied by both sides'
+                // deletions is gone, just as if either side had deleted it outright.
+                if keep_empty || !child.tree.is_empty() {
+                    let tree_ref = await!(child.tree.as_tree().send(&store))?;
+                    merge
+                        .tree
+                        .insert_with_attrs(name, ObjectRef::Tree(tree_ref), attrs);
+                }
+            }
+            Resolution::Conflict(entry, kind) => {
+                merge.conflicts.push(Conflict {
+                    path: child_path,
+                    kind,
+                    base: base.get(&name).cloned(),
+                    ours: ours.get(&name).cloned(),
+                    theirs: theirs.get(&name).cloned(),
+                });
+                insert_entry(&mut merge.tree, name, entry);
+            }
+        }
+    }
+
+    Ok(merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Write, sync::atomic};
+
+    use object::{FileMode, SmallBuilder, SmallRef};
+    use store::dummy::MemoryBackend;
+
+    fn file(handle: u64) -> Entry<u64> {
+        Entry {
+            objref: ObjectRef::Small(SmallRef::new(handle, handle)),
+            attrs: EntryAttrs::default(),
+        }
+    }
+
+    fn dir(handle: u64) -> Entry<u64> {
+        Entry {
+            objref: ObjectRef::Tree(TreeRef::new(handle)),
+            attrs: EntryAttrs::default(),
+        }
+    }
+
+    fn executable(entry: Entry<u64>) -> Entry<u64> {
+        Entry {
+            attrs: FileMode::Executable.into(),
+            ..entry
+        }
+    }
+
+    #[test]
+    fn one_sided_changes_win() {
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&file(1)), Some(&file(2))),
+            Resolution::Take(Some(file(2)))
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&file(2)), Some(&file(1))),
+            Resolution::Take(Some(file(2)))
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&file(1)), None),
+            Resolution::Take(None)
+        );
+        assert_eq!(
+            resolve(None, None, Some(&dir(3))),
+            Resolution::Take(Some(dir(3)))
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&file(2)), Some(&file(2))),
+            Resolution::Take(Some(file(2)))
+        );
+    }
+
+    #[test]
+    fn content_and_attributes_merge_separately() {
+        assert_eq!(
+            resolve(
+                Some(&file(1)),
+                Some(&file(2)),
+                Some(&executable(file(1)))
+            ),
+            Resolution::Take(Some(executable(file(2))))
+        );
+    }
+
+    #[test]
+    fn directories_changed_on_both_sides_recurse() {
+        assert_eq!(
+            resolve(Some(&dir(1)), Some(&dir(2)), Some(&dir(3))),
+            Resolution::Recurse {
+                base: Some(TreeRef::new(1)),
+                ours: TreeRef::new(2),
+                theirs: TreeRef::new(3),
+                attrs: EntryAttrs::default(),
+            }
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&dir(2)), Some(&dir(3))),
+            Resolution::Recurse {
+                base: None,
+                ours: TreeRef::new(2),
+                theirs: TreeRef::new(3),
+                attrs: EntryAttrs::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        assert_eq!(
+            resolve(None, Some(&file(1)), Some(&file(2))),
+            Resolution::Conflict(file(1), ConflictKind::AddAdd)
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&file(2)), Some(&file(3))),
+            Resolution::Conflict(file(2), ConflictKind::ModifyModify)
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), None, Some(&file(2))),
+            Resolution::Conflict(file(2), ConflictKind::ModifyDelete)
+        );
+        assert_eq!(
+            resolve(Some(&file(1)), Some(&dir(2)), Some(&file(3))),
+            Resolution::Conflict(dir(2), ConflictKind::TypeChange)
+        );
+        assert_eq!(
+            resolve(None, Some(&file(2)), Some(&dir(3))),
+            Resolution::Conflict(file(2), ConflictKind::TypeChange)
+        );
+    }
+
+    type MemoryRef = ObjectRef<Handle<MemoryBackend>>;
+
+    fn send_data(store: &Store<MemoryBackend>, bytes: &[u8]) -> MemoryRef {
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(bytes).unwrap();
+        ObjectRef::Small(small_builder.as_small().send(store).wait().unwrap())
+    }
+
+    fn plain(objref: MemoryRef) -> Entry<Handle<MemoryBackend>> {
+        Entry {
+            objref,
+            attrs: EntryAttrs::default(),
+        }
+    }
+
+    fn send_tree(
+        store: &Store<MemoryBackend>,
+        entries: Vec<(&str, Entry<Handle<MemoryBackend>>)>,
+    ) -> MemoryRef {
+        let mut tree_builder = TreeBuilder::new();
+        for (name, entry) in entries {
+            tree_builder.insert_with_attrs(name.to_owned(), entry.objref, entry.attrs);
+        }
+        ObjectRef::Tree(tree_builder.as_tree().send(store).wait().unwrap())
+    }
+
+    fn tree_ref(objref: MemoryRef) -> TreeRef<Handle<MemoryBackend>> {
+        match objref {
+            ObjectRef::Tree(tree_ref) => tree_ref,
+            other => panic!("Expected a tree, got {:?}", other),
+        }
+    }
+
+    fn merge(
+        store: &Store<MemoryBackend>,
+        base: MemoryRef,
+        ours: MemoryRef,
+        theirs: MemoryRef,
+        keep_empty: bool,
+    ) -> Merge<Handle<MemoryBackend>> {
+        merge_trees(
+            store.clone(),
+            Some(tree_ref(base)),
+            tree_ref(ours),
+            tree_ref(theirs),
+            keep_empty,
+        ).wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn merge_combines_changes_from_both_sides() {
+        let store = Store::new(MemoryBackend::default());
+
+        let x = send_data(&store, b"x");
+        let y = send_data(&store, b"y");
+        let z = send_data(&store, b"z");
+        let description = send_data(&store, b"description");
+
+        let base = send_tree(
+            &store,
+            vec![("x", plain(x.clone())), ("y", plain(y.clone()))],
+        );
+        let ours = send_tree(&store, vec![("x", plain(x.clone()))]);
+        let theirs = {
+            let mut tree_builder = TreeBuilder::new();
+            tree_builder.insert("x".to_owned(), x.clone());
+            tree_builder.insert("y".to_owned(), y);
+            tree_builder.insert("z".to_owned(), z.clone());
+            tree_builder
+                .as_metadata_mut()
+                .insert("description".to_owned(), description.clone());
+            ObjectRef::Tree(tree_builder.as_tree().send(&store).wait().unwrap())
+        };
+
+        let merged = merge(&store, base, ours, theirs, false);
+        assert!(merged.is_clean());
+        assert_eq!(
+            merged.tree.iter().collect::<Vec<_>>(),
+            vec![(&"x".to_owned(), &x), (&"z".to_owned(), &z)]
+        );
+        assert_eq!(
+            merged.tree.as_metadata().get("description"),
+            Some(&description)
+        );
+    }
+
+    #[test]
+    fn nested_conflicts_keep_our_side_and_directory_attributes() {
+        let store = Store::new(MemoryBackend::default());
+
+        let posix = send_data(&store, b"posix");
+        let dir_attrs = EntryAttrs {
+            mode: FileMode::Regular,
+            posix: Some(posix),
+        };
+        fn subdir(
+            store: &Store<MemoryBackend>,
+            f: &[u8],
+            g: &[u8],
+            attrs: EntryAttrs<Handle<MemoryBackend>>,
+        ) -> Entry<Handle<MemoryBackend>> {
+            let objref = send_tree(
+                store,
+                vec![
+                    ("f", plain(send_data(store, f))),
+                    ("g", plain(send_data(store, g))),
+                ],
+            );
+            Entry { objref, attrs }
+        }
+
+        let base = send_tree(
+            &store,
+            vec![("d", subdir(&store, b"f", b"g", EntryAttrs::default()))],
+        );
+        let ours = send_tree(
+            &store,
+            vec![("d", subdir(&store, b"ours", b"g", dir_attrs.clone()))],
+        );
+        let theirs = send_tree(
+            &store,
+            vec![("d", subdir(&store, b"theirs", b"g2", EntryAttrs::default()))],
+        );
+
+        let merged = merge(&store, base, ours, theirs, false);
+        assert_eq!(merged.conflicts.len(), 1);
+        let conflict = &merged.conflicts[0];
+        assert_eq!(
+            conflict.path,
+            ObjectPath::new()
+                .push_back("d".to_owned())
+                .push_back("f".to_owned())
+        );
+        assert_eq!(conflict.kind, ConflictKind::ModifyModify);
+        assert_eq!(
+            conflict.theirs.as_ref().map(|entry| &entry.objref),
+            Some(&send_data(&store, b"theirs"))
+        );
+
+        assert_eq!(merged.tree.as_tree().attrs("d"), dir_attrs);
+        let d = tree_ref(merged.tree["d"].clone()).fetch().wait().unwrap();
+        assert_eq!(d["f"], send_data(&store, b"ours"));
+        assert_eq!(d["g"], send_data(&store, b"g2"));
+    }
+
+    #[test]
+    fn mode_changes_merge_with_content_changes_in_subtrees() {
+        let store = Store::new(MemoryBackend::default());
+
+        let f1 = send_data(&store, b"f1");
+        let f2 = send_data(&store, b"f2");
+        let other = send_data(&store, b"other");
+        let executable = |objref: MemoryRef| Entry {
+            objref,
+            attrs: FileMode::Executable.into(),
+        };
+
+        let base = send_tree(
+            &store,
+            vec![(
+                "d",
+                plain(send_tree(&store, vec![("f", plain(f1.clone()))])),
+            )],
+        );
+        let ours = send_tree(
+            &store,
+            vec![(
+                "d",
+                plain(send_tree(&store, vec![("f", executable(f1.clone()))])),
+            )],
+        );
+        let theirs = send_tree(
+            &store,
+            vec![(
+                "d",
+                plain(send_tree(
+                    &store,
+                    vec![("f", plain(f2.clone())), ("o", plain(other))],
+                )),
+            )],
+        );
+
+        let merged = merge(&store, base, ours, theirs, false);
+        assert!(merged.is_clean());
+        let d = tree_ref(merged.tree["d"].clone()).fetch().wait().unwrap();
+        assert_eq!(d["f"], f2);
+        assert_eq!(d.mode("f"), FileMode::Executable);
+    }
+
+    #[test]
+    fn subtrees_changed_on_one_side_are_reused_without_fetching() {
+        let backend = MemoryBackend::default();
+        let loads = backend.loads();
+        let store = Store::new(backend);
+
+        let unchanged = send_tree(&store, vec![("u", plain(send_data(&store, b"u")))]);
+        let old_dir = send_tree(&store, vec![("v", plain(send_data(&store, b"v")))]);
+        let new_dir = send_tree(&store, vec![("v", plain(send_data(&store, b"v2")))]);
+
+        let base = send_tree(
+            &store,
+            vec![
+                ("a", plain(unchanged.clone())),
+                ("b", plain(old_dir.clone())),
+                ("x", plain(send_data(&store, b"x"))),
+            ],
+        );
+        let ours = send_tree(
+            &store,
+            vec![
+                ("a", plain(unchanged.clone())),
+                ("b", plain(old_dir)),
+                ("x", plain(send_data(&store, b"x2"))),
+            ],
+        );
+        let theirs = send_tree(
+            &store,
+            vec![
+                ("a", plain(unchanged.clone())),
+                ("b", plain(new_dir.clone())),
+                ("x", plain(send_data(&store, b"x"))),
+            ],
+        );
+
+        let merged = merge(&store, base, ours, theirs, false);
+        assert!(merged.is_clean());
+        assert_eq!(merged.tree["a"], unchanged);
+        assert_eq!(merged.tree["b"], new_dir);
+        assert_eq!(merged.tree["x"], send_data(&store, b"x2"));
+
+        // Only the three roots were ever fetched.
+        assert_eq!(loads.load(atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn directories_emptied_by_the_merge_are_only_kept_if_asked() {
+        let store = Store::new(MemoryBackend::default());
+
+        let x = send_data(&store, b"x");
+        let y = send_data(&store, b"y");
+        let keep = plain(send_data(&store, b"keep"));
+
+        let base = send_tree(
+            &store,
+            vec![
+                (
+                    "d",
+                    plain(send_tree(
+                        &store,
+                        vec![("x", plain(x.clone())), ("y", plain(y.clone()))],
+                    )),
+                ),
+                ("keep", keep.clone()),
+            ],
+        );
+        let ours = send_tree(
+            &store,
+            vec![
+                ("d", plain(send_tree(&store, vec![("y", plain(y))]))),
+                ("keep", keep.clone()),
+            ],
+        );
+        let theirs = send_tree(
+            &store,
+            vec![
+                ("d", plain(send_tree(&store, vec![("x", plain(x))]))),
+                ("keep", keep),
+            ],
+        );
+
+        let dropped = merge(&store, base.clone(), ours.clone(), theirs.clone(), false);
+        assert!(dropped.is_clean());
+        assert!(!dropped.tree.contains_key("d"));
+
+        let kept = merge(&store, base, ours, theirs, true);
+        assert!(kept.is_clean());
+        assert_eq!(kept.tree["d"], send_tree(&store, Vec::new()));
+    }
+}
@@ -1,14 +1,15 @@
-use std::{usize, borrow::Borrow, collections::BTreeMap, io::{BufRead, Cursor, Read},
+use std::{char, usize, borrow::Borrow, collections::BTreeMap, io::Read,
           str::{self, FromStr}};
 
 use chrono::prelude::*;
 use failure::{self, Error};
 use nom::{digit, rest, IResult};
-use ntriple::{self, Object, Predicate, Subject};
 
-use object::{Commit, CommitAuthor, CommitBuilder, CommitRef, Large, LargeRef, ObjectKind,
-             ObjectRef, Small, SmallRef, Tree, TreeRef,
-             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
+use object::{Commit, CommitAuthor, CommitBuilder, CommitCommitter, CommitRef, FileMode, Large, LargeRef,
+             Object, ObjectHeader, ObjectKind, ObjectRef, Small, SmallRef, Tree, TreeRef,
+             FORMAT_VERSION, HEADER_MAGIC,
+             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, ATTACA_POSIX_METADATA,
+                        ATTACA_PROPERTY_KEY, ATTACA_PROPERTY_VALUE, FOAF_MBOX, FOAF_NAME}};
 use store::prelude::*;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -82,10 +83,105 @@ named!(
   )
 );
 
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(header_kind<ObjectHeader>,
+  alt_complete!(
+    do_parse!(
+      tag!(b"large ") >>
+      size: parse_u64 >>
+      tag!(b" ") >>
+      depth: parse_u8 >>
+      (ObjectHeader::Large { size, depth })
+    ) |
+    value!(ObjectHeader::Tree, tag!(b"tree")) |
+    value!(ObjectHeader::Commit, tag!(b"commit"))
+  )
+);
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(object_header<(u64, ObjectHeader)>,
+  do_parse!(
+    tag!(HEADER_MAGIC) >>
+    version: parse_u64 >>
+    tag!(b" ") >>
+    header: header_kind >>
+    tag!(b"\n") >>
+    (version, header)
+  )
+);
+
+/// Split the type header off of an encoded object, returning `None` if the object is small or
+/// predates type headers.
+///
+/// Legacy large objects, trees and commits all begin with a digit, so they can never be mistaken
+/// for a headered object. Small objects are raw data, though, and may well begin with
+/// `HEADER_MAGIC`, so `small` never looks for a header at all.
+pub fn header(data: &[u8]) -> Result<Option<(ObjectHeader, &[u8])>, Error> {
+    if !data.starts_with(HEADER_MAGIC) {
+        return Ok(None);
+    }
+
+    match object_header(data) {
+        IResult::Done(body, (version, header)) => {
+            ensure!(
+                version == FORMAT_VERSION,
+                "Unsupported object format version {}!",
+                version
+            );
+            Ok(Some((header, body)))
+        }
+        _ => bail!("Malformed object header!"),
+    }
+}
+
+/// Strip the type header from an object whose kind is already known, checking that it agrees
+/// with the expected kind. Legacy objects without a header are passed through untouched.
+fn expect_header<'a>(data: &'a [u8], expected: &ObjectHeader) -> Result<&'a [u8], Error> {
+    match header(data)? {
+        Some((ref header, body)) if header == expected => Ok(body),
+        Some((header, _)) => bail!(
+            "Object header mismatch: expected {:?}, found {:?}!",
+            expected,
+            header
+        ),
+        None => Ok(data),
+    }
+}
+
+/// Decode an object of unknown kind, dispatching on its type header. An object without a header
+/// is decoded as small, since small objects are never headered. This means large objects, trees
+/// and commits written before type headers were introduced come out as small objects, and a small
+/// object whose data happens to begin with a well-formed header comes out as whatever that header
+/// describes; these must be decoded through a typed reference instead.
+pub fn object<B: Backend>(mut content: Content<B>) -> Result<Object<Handle<B>>, Error> {
+    let mut data = Vec::new();
+    content.read_to_end(&mut data)?;
+
+    let (header, body) = match header(&data)? {
+        Some((header, body)) => (header, body),
+        None => return Ok(Object::Small(small_body(&data))),
+    };
+
+    match header {
+        ObjectHeader::Large { size, depth } => {
+            Ok(Object::Large(large_body(content, body, size, depth)?))
+        }
+        ObjectHeader::Tree => Ok(Object::Tree(tree_body(content, body)?)),
+        ObjectHeader::Commit => Ok(Object::Commit(commit_body(content, body)?)),
+    }
+}
+
+/// Decode a small object, which is always raw data.
 pub fn small<B: Backend>(mut content: Content<B>) -> Result<Small, Error> {
     let mut data = Vec::new();
     content.read_to_end(&mut data)?;
-    Ok(Small { data })
+    Ok(small_body(&data))
+}
+
+fn small_body(body: &[u8]) -> Small {
+    Small {
+        data: body.to_owned(),
+    }
 }
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -101,19 +197,43 @@ named!(large_entry<(u64, u64, usize)>,
   )
 );
 
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(large_splitter<String>,
+  do_parse!(
+    tag!(b"splitter ") >>
+    splitter: map_res!(take_until_and_consume!(b"\n"), str::from_utf8) >>
+    (splitter.to_owned())
+  )
+);
+
 pub fn large<B: Backend>(
     mut content: Content<B>,
     size: u64,
     depth: u8,
 ) -> Result<Large<Handle<B>>, Error> {
-    assert!(depth > 0);
-
     let mut data = Vec::new();
     content.read_to_end(&mut data)?;
+    let body = expect_header(&data, &ObjectHeader::Large { size, depth })?;
+    large_body(content, body, size, depth)
+}
+
+fn large_body<B: Backend>(
+    content: Content<B>,
+    body: &[u8],
+    size: u64,
+    depth: u8,
+) -> Result<Large<Handle<B>>, Error> {
+    ensure!(depth > 0, "Bad large object: depth must be nonzero!");
+
     let refs = content.map(|r| r.borrow().to_owned()).collect::<Vec<_>>();
 
+    let (body, splitter) = match large_splitter(body) {
+        IResult::Done(rest, splitter) => (rest, Some(splitter)),
+        _ => (body, None),
+    };
+
     let ir: IResult<_, _> = terminated!(
-        data.as_slice(),
+        body,
         fold_many0!(
             large_entry,
             Ok(BTreeMap::new()),
@@ -126,10 +246,10 @@ pub fn large<B: Backend>(
                 let child_size = end - start;
                 let child_depth = depth - 1;
                 let objref = if child_depth == 0 {
-                    assert!(size <= usize::MAX as u64, "Unable to keep Small in memory!");
+                    assert!(child_size <= usize::MAX as u64, "Unable to keep Small in memory!");
                     ObjectRef::Small(SmallRef::new(child_size, reference))
                 } else {
-                    ObjectRef::Large(LargeRef::new(child_size, child_depth - 1, reference))
+                    ObjectRef::Large(LargeRef::new(child_size, child_depth, reference))
                 };
                 acc.insert(start, (end, objref));
                 Ok(acc)
@@ -141,6 +261,7 @@ pub fn large<B: Backend>(
     Ok(Large {
         size,
         depth,
+        splitter,
         entries: ir.to_result()??,
     })
 }
@@ -148,29 +269,73 @@ pub fn large<B: Backend>(
 #[derive(Debug)]
 enum TreeEntry {
     Tree,
-    Data(u64, u8),
+    Commit,
+    Data(u64, u8, FileMode),
 }
 
+impl TreeEntry {
+    fn mode(&self) -> FileMode {
+        match *self {
+            TreeEntry::Data(_, _, mode) => mode,
+            TreeEntry::Tree | TreeEntry::Commit => FileMode::Regular,
+        }
+    }
+
+    fn into_object_ref<H>(self, reference: H) -> ObjectRef<H> {
+        match self {
+            TreeEntry::Data(sz, 0, _) => ObjectRef::Small(SmallRef::new(sz, reference)),
+            TreeEntry::Data(sz, d, _) => ObjectRef::Large(LargeRef::new(sz, d, reference)),
+            TreeEntry::Tree => ObjectRef::Tree(TreeRef::new(reference)),
+            TreeEntry::Commit => ObjectRef::Commit(CommitRef::new(reference)),
+        }
+    }
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(entry_kind<TreeEntry>,
+  dbg_dmp!(alt_complete!(
+    do_parse!(
+      tag!(b" data ") >>
+      size: parse_u64 >>
+      tag!(b" ") >>
+      depth: parse_u8 >>
+      tag!(b" ") >>
+      (TreeEntry::Data(size, depth, FileMode::Regular))
+    ) |
+    do_parse!(
+      tag!(b" exec ") >>
+      size: parse_u64 >>
+      tag!(b" ") >>
+      depth: parse_u8 >>
+      tag!(b" ") >>
+      (TreeEntry::Data(size, depth, FileMode::Executable))
+    ) |
+    do_parse!(
+      tag!(b" link ") >>
+      size: parse_u64 >>
+      tag!(b" ") >>
+      depth: parse_u8 >>
+      tag!(b" ") >>
+      (TreeEntry::Data(size, depth, FileMode::Symlink))
+    ) |
+    do_parse!(
+      tag!(b" tree ") >>
+      (TreeEntry::Tree)
+    ) |
+    do_parse!(
+      tag!(b" commit ") >>
+      (TreeEntry::Commit)
+    )
+  ))
+);
+
 #[cfg_attr(rustfmt, rustfmt_skip)]
-named!(tree_entry<(&str, usize, TreeEntry)>,
+named!(child_entry<(&str, usize, TreeEntry)>,
   terminated!(
     netstring!(
       do_parse!(
         hd: handle >>
-        entry: dbg_dmp!(alt_complete!(
-          do_parse!(
-            tag!(b" data ") >>
-            size: parse_u64 >>
-            tag!(b" ") >>
-            depth: parse_u8 >>
-            tag!(b" ") >>
-            (TreeEntry::Data(size, depth))
-          ) |
-          do_parse!(
-            tag!(b" tree ") >>
-            (TreeEntry::Tree)
-          )
-        )) >>
+        entry: entry_kind >>
         name: map_res!(rest, str::from_utf8) >>
         (name, hd, entry)
       )
@@ -179,37 +344,133 @@ named!(tree_entry<(&str, usize, TreeEntry)>,
   )
 );
 
+// Metadata entries index into the arguments of an object rather than its refs, and are
+// distinguished from child entries by a leading `@`.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(metadata_entry<(&str, usize, TreeEntry)>,
+  terminated!(
+    netstring!(
+      do_parse!(
+        tag!(b"@") >>
+        arg: handle >>
+        entry: entry_kind >>
+        name: map_res!(rest, str::from_utf8) >>
+        (name, arg, entry)
+      )
+    ),
+    tag!(b"\n")
+  )
+);
+
+#[derive(Debug)]
+enum TreeItem<'a> {
+    Child(&'a str, usize, TreeEntry),
+    Metadata(&'a str, usize, TreeEntry),
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+named!(tree_entry<TreeItem>,
+  alt_complete!(
+      map!(metadata_entry, |(name, arg, entry)| TreeItem::Metadata(name, arg, entry))
+    | map!(child_entry, |(name, hd, entry)| TreeItem::Child(name, hd, entry))
+  )
+);
+
+/// The pieces of a tree as they're accumulated while parsing it.
+struct TreeParts<H> {
+    entries: BTreeMap<String, ObjectRef<H>>,
+    modes: BTreeMap<String, FileMode>,
+    posix: BTreeMap<String, ObjectRef<H>>,
+    metadata: BTreeMap<String, ObjectRef<H>>,
+}
+
+impl<H> Default for TreeParts<H> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            modes: BTreeMap::new(),
+            posix: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+/// If a metadata name refers to the POSIX metadata of an entry, the entry's name.
+fn posix_entry_name(name: &str) -> Option<&str> {
+    if name.starts_with(ATTACA_POSIX_METADATA) {
+        let rest = &name[ATTACA_POSIX_METADATA.len()..];
+        if rest.starts_with('/') {
+            return Some(&rest[1..]);
+        }
+    }
+
+    None
+}
+
 pub fn tree<B: Backend>(mut content: Content<B>) -> Result<Tree<Handle<B>>, Error> {
     let mut data = Vec::new();
     content.read_to_end(&mut data)?;
+    let body = expect_header(&data, &ObjectHeader::Tree)?;
+    tree_body(content, body)
+}
+
+fn tree_body<B: Backend>(mut content: Content<B>, body: &[u8]) -> Result<Tree<Handle<B>>, Error> {
+    let args = content.arguments().collect::<Vec<_>>();
     let refs = content.map(|r| r.borrow().to_owned()).collect::<Vec<_>>();
 
     let ir: IResult<_, _> = terminated!(
-        data.as_slice(),
+        body,
         fold_many0!(
             tree_entry,
-            Ok(BTreeMap::new()),
-            |acc_res: Result<BTreeMap<_, _>, Error>, (name, hd, entry)| {
-                let mut acc = acc_res?;
-                let reference = refs.get(hd)
-                    .cloned()
-                    .ok_or_else(|| failure::err_msg("Bad handle index!"))?;
-                acc.insert(
-                    String::from(name),
-                    match entry {
-                        TreeEntry::Data(sz, 0) => ObjectRef::Small(SmallRef::new(sz, reference)),
-                        TreeEntry::Data(sz, d) => ObjectRef::Large(LargeRef::new(sz, d, reference)),
-                        TreeEntry::Tree => ObjectRef::Tree(TreeRef::new(reference)),
-                    },
-                );
-                Ok(acc)
+            Ok(TreeParts::default()),
+            |acc_res: Result<TreeParts<Handle<B>>, Error>, item| {
+                let mut parts = acc_res?;
+                match item {
+                    TreeItem::Child(_, _, TreeEntry::Commit) => bail!(
+                        "Bad tree object: child with bad kind (not small, large or tree)"
+                    ),
+                    TreeItem::Child(name, hd, entry) => {
+                        let reference = refs.get(hd)
+                            .cloned()
+                            .ok_or_else(|| failure::err_msg("Bad handle index!"))?;
+                        if entry.mode() != FileMode::Regular {
+                            parts.modes.insert(String::from(name), entry.mode());
+                        }
+                        parts.entries.insert(String::from(name), entry.into_object_ref(reference));
+                    }
+                    TreeItem::Metadata(name, arg, entry) => {
+                        let reference = args.get(arg)
+                            .cloned()
+                            .ok_or_else(|| failure::err_msg("Bad argument index!"))?;
+                        let objref = entry.into_object_ref(reference);
+                        match posix_entry_name(name) {
+                            Some(entry_name) => {
+                                parts.posix.insert(String::from(entry_name), objref);
+                            }
+                            None => {
+                                parts.metadata.insert(String::from(name), objref);
+                            }
+                        }
+                    }
+                }
+                Ok(parts)
             }
         ),
         eof!()
     );
 
+    let TreeParts {
+        entries,
+        modes,
+        posix,
+        metadata,
+    } = ir.to_result()??;
+
     Ok(Tree {
-        entries: ir.to_result()??,
+        entries,
+        modes,
+        posix,
+        metadata,
     })
 }
 
@@ -224,87 +485,178 @@ named!(commit_header<(usize, usize)>,
   )
 );
 
-// TODO: Robust RDF formatting/parsing - current breaks for non-ASCII strings:
-// https://github.com/sdleffler/attaca/issues/25
-pub fn commit<B: Backend>(mut content: Content<B>) -> Result<Commit<Handle<B>>, Error> {
-    let mut bytes = {
-        let mut buf = Vec::new();
-        content.read_to_end(&mut buf)?;
-        Cursor::new(buf)
-    };
-    let mut refs = content;
+/// Parse a single N-Triples statement of the form `_:subject <predicate> "literal" .`, which is
+/// the only form written by `encode::commit`. Statements about anything other than a blank node
+/// aren't ours to interpret, and are skipped.
+fn triple(line: &str) -> Result<Option<(&str, &str, String)>, Error> {
+    let malformed = || format_err!("Malformed commit metadata: bad triple {:?}", line);
 
+    if !line.starts_with("_:") {
+        return Ok(None);
+    }
+    let rest = &line[2..];
+
+    let subject_end = rest.find(" <").ok_or_else(&malformed)?;
+    let (subject, rest) = (&rest[..subject_end], &rest[subject_end + 2..]);
+
+    let predicate_end = rest.find("> \"").ok_or_else(&malformed)?;
+    let (predicate, rest) = (&rest[..predicate_end], &rest[predicate_end + 3..]);
+
+    if !rest.ends_with("\" .") {
+        return Err(malformed());
+    }
+    let literal = unescape_literal(&rest[..rest.len() - 3])?;
+
+    Ok(Some((subject, predicate, literal)))
+}
+
+fn unescape_hex<I: Iterator<Item = char>>(chars: &mut I, digits: usize) -> Result<u32, Error> {
+    let hex = chars.take(digits).collect::<String>();
+    ensure!(
+        hex.len() == digits,
+        "Malformed commit metadata: truncated escape sequence"
+    );
+    Ok(u32::from_str_radix(&hex, 16)?)
+}
+
+/// Unescape the body of an N-Triples string literal.
+///
+/// Commits written before literals were escaped as N-Triples contain `\xNN` byte escapes, which
+/// are accepted so that their non-ASCII contents are recovered intact.
+pub fn unescape_literal(literal: &str) -> Result<String, Error> {
+    let mut bytes = Vec::with_capacity(literal.len());
+    let mut chars = literal.chars();
+
+    while let Some(c) = chars.next() {
+        let unescaped = match c {
+            '\\' => match chars.next() {
+                Some('t') => '\t',
+                Some('b') => '\x08',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('f') => '\x0c',
+                Some('"') => '"',
+                Some('\'') => '\'',
+                Some('\\') => '\\',
+                Some('u') => char::from_u32(unescape_hex(&mut chars, 4)?)
+                    .ok_or_else(|| format_err!("Malformed commit metadata: bad \\u escape"))?,
+                Some('U') => char::from_u32(unescape_hex(&mut chars, 8)?)
+                    .ok_or_else(|| format_err!("Malformed commit metadata: bad \\U escape"))?,
+                Some('x') => {
+                    bytes.push(unescape_hex(&mut chars, 2)? as u8);
+                    continue;
+                }
+                other => bail!(
+                    "Malformed commit metadata: bad escape sequence \\{:?}",
+                    other
+                ),
+            },
+            '"' => bail!("Malformed commit metadata: unescaped quote in literal"),
+            c => c,
+        };
+
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(unescaped.encode_utf8(&mut buf).as_bytes());
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+pub fn commit<B: Backend>(mut content: Content<B>) -> Result<Commit<Handle<B>>, Error> {
     let mut data = Vec::new();
-    bytes.read_until(b'\n', &mut data)?;
+    content.read_to_end(&mut data)?;
+    let body = expect_header(&data, &ObjectHeader::Commit)?;
+    commit_body(content, body)
+}
 
-    let (n_parents, n_meta) = commit_header(data.as_slice()).to_result()?;
+fn commit_body<B: Backend>(mut refs: Content<B>, body: &[u8]) -> Result<Commit<Handle<B>>, Error> {
+    let (remainder, (n_parents, n_meta)) = match commit_header(body) {
+        IResult::Done(remainder, counts) => (remainder, counts),
+        _ => bail!("Malformed commit: bad header!"),
+    };
 
     let mut commit_builder = CommitBuilder::new();
     commit_builder.subtree(TreeRef::new(refs.next()
         .ok_or_else(|| format_err!("Malformed commit: no subtree handle!"))?));
     commit_builder.parents(Iterator::take(&mut refs, n_parents).map(CommitRef::new));
 
-    // We don't have anything to do with these metadata refs yet, but the encoding has them, so we
-    // should at least consume them to be sure they're there.
-    let _meta_handles = Iterator::take(&mut refs, n_meta).for_each(|_| ());
+    let args = refs.arguments().collect::<Vec<_>>();
+
+    // Metadata entries come first, followed by the N-triples.
+    let (triples, meta_entries) = match count!(remainder, metadata_entry, n_meta) {
+        IResult::Done(triples, meta_entries) => (triples, meta_entries),
+        _ => bail!("Malformed commit: bad metadata entries!"),
+    };
+    for (name, arg, entry) in meta_entries {
+        let reference = args.get(arg)
+            .cloned()
+            .ok_or_else(|| failure::err_msg("Bad argument index!"))?;
+        commit_builder.metadata(String::from(name), entry.into_object_ref(reference));
+    }
 
-    let mut meta_string = String::new();
-    bytes.read_to_string(&mut meta_string)?;
+    let meta_string = str::from_utf8(triples)?;
 
     let mut author = CommitAuthor::new();
+    let (mut committer_name, mut committer_mbox, mut committer_timestamp) = (None, None, None);
+    let mut properties = BTreeMap::new();
 
     for line in meta_string.lines() {
-        let triple = match ntriple::parser::triple_line(line)? {
-            Some(triple) => triple,
-            None => continue,
-        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
-        let name = match triple.subject {
-            Subject::BNode(name) => name,
+        let (subject, predicate, object) = match triple(line)? {
+            Some(triple) => triple,
 
             // No-op if we don't recognize it.
-            _ => continue,
+            None => continue,
         };
 
-        match name.as_str() {
-            "this" => {
-                let Predicate::IriRef(iri) = triple.predicate;
-
-                let object = match triple.object {
-                    Object::Lit(literal) => literal.data,
-                    _ => bail!("Malformed commit metadata: expected commit message to have a literal object"),
-                };
-
-                match iri.as_str() {
-                    ATTACA_COMMIT_MESSAGE => {
-                        commit_builder.message(object);
-                    }
-                    ATTACA_COMMIT_TIMESTAMP => {
-                        let timestamp = DateTime::parse_from_rfc2822(&object)?;
-                        commit_builder.timestamp(timestamp);
-                    }
-                    _ => bail!(
-                        "Malformed commit metadata: invalid commit predicate <{}>",
-                        iri
-                    ),
-                };
-            }
-            "author" => {
-                let Predicate::IriRef(iri) = triple.predicate;
-
-                let object = match triple.object {
-                    Object::Lit(literal) => literal.data,
-                    _ => bail!(
-                        "Malformed commit metadata: expected author subject to be a literal object"
-                    ),
-                };
-
-                match iri.as_str() {
-                    FOAF_MBOX => author.mbox = Some(object),
-                    FOAF_NAME => author.name = Some(object),
+        match subject {
+            "this" => match predicate {
+                ATTACA_COMMIT_MESSAGE => {
+                    commit_builder.message(object);
+                }
+                ATTACA_COMMIT_TIMESTAMP => {
+                    let timestamp = DateTime::parse_from_rfc2822(&object)?;
+                    commit_builder.timestamp(timestamp);
+                }
+                _ => bail!(
+                    "Malformed commit metadata: invalid commit predicate <{}>",
+                    predicate
+                ),
+            },
+            "author" => match predicate {
+                FOAF_MBOX => author.mbox = Some(object),
+                FOAF_NAME => author.name = Some(object),
+                _ => bail!(
+                    "Malformed commit metadata: invalid author predicate <{}>",
+                    predicate
+                ),
+            },
+
+            "committer" => match predicate {
+                FOAF_MBOX => committer_mbox = Some(object),
+                FOAF_NAME => committer_name = Some(object),
+                ATTACA_COMMIT_TIMESTAMP => {
+                    committer_timestamp = Some(DateTime::parse_from_rfc2822(&object)?)
+                }
+                _ => bail!(
+                    "Malformed commit metadata: invalid committer predicate <{}>",
+                    predicate
+                ),
+            },
+            property if property.starts_with("property") => {
+                let index = usize::from_str(&property["property".len()..]).map_err(|_| {
+                    format_err!("Malformed commit metadata: bad property subject {:?}", property)
+                })?;
+                let entry = properties.entry(index).or_insert((None, None));
+                match predicate {
+                    ATTACA_PROPERTY_KEY => entry.0 = Some(object),
+                    ATTACA_PROPERTY_VALUE => entry.1 = Some(object),
                     _ => bail!(
-                        "Malformed commit metadata: invalid author predicate <{}>",
-                        iri
+                        "Malformed commit metadata: invalid property predicate <{}>",
+                        predicate
                     ),
                 }
             }
@@ -314,6 +666,29 @@ pub fn commit<B: Backend>(mut content: Content<B>) -> Result<Commit<Handle<B>>,
         }
     }
 
+    for (_, kv) in properties {
+        match kv {
+            (Some(key), Some(value)) => {
+                commit_builder.property(key, value);
+            }
+            _ => bail!("Malformed commit metadata: property without both a key and a value"),
+        }
+    }
+
+    match committer_timestamp {
+        Some(timestamp) => {
+            commit_builder.committer(CommitCommitter {
+                name: committer_name,
+                mbox: committer_mbox,
+                timestamp,
+            });
+        }
+        None => ensure!(
+            committer_name.is_none() && committer_mbox.is_none(),
+            "Malformed commit metadata: committer without a timestamp"
+        ),
+    }
+
     commit_builder.author(author);
     Ok(commit_builder.into_commit()?)
 }
@@ -336,5 +711,25 @@ mod tests {
             let battered_bytes = netstring!(buf.as_slice(), rest).to_full_result().unwrap();
             assert_eq!(battered_bytes, bytes.as_slice());
         }
+
+        #[test]
+        fn roundtrip_triple(ref string in "(?s).*") {
+            let line = format!(
+                "_:author <{}> \"{}\" .",
+                FOAF_NAME,
+                ::object::encode::escape_literal(string)
+            );
+            let (subject, predicate, object) = triple(&line).unwrap().unwrap();
+            assert_eq!(subject, "author");
+            assert_eq!(predicate, FOAF_NAME);
+            assert_eq!(&object, string);
+        }
+    }
+
+    #[test]
+    fn triples_about_other_subjects_are_skipped() {
+        let line = format!("<http://example.com/> <{}> \"someone\" .", FOAF_NAME);
+        assert!(triple(&line).unwrap().is_none());
+        assert!(triple("_:author <broken").is_err());
     }
 }
@@ -1,17 +1,52 @@
-use std::{ascii, usize, collections::{BTreeSet, HashMap}, io::Write};
+use std::{usize, collections::{BTreeSet, HashMap}, fmt::Display, io::Write};
 
 use failure::Error;
 
-use object::{Commit, Large, ObjectRef, Small, Tree,
-             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, FOAF_MBOX, FOAF_NAME}};
+use object::{Commit, FileMode, Large, ObjectHeader, ObjectRef, Small, Tree, FORMAT_VERSION,
+             HEADER_MAGIC,
+             metadata::{ATTACA_COMMIT_MESSAGE, ATTACA_COMMIT_TIMESTAMP, ATTACA_POSIX_METADATA,
+                        ATTACA_PROPERTY_KEY, ATTACA_PROPERTY_VALUE, FOAF_MBOX, FOAF_NAME}};
 use store::prelude::*;
 
+/// Write the versioned type header which begins every object other than a small one.
+pub fn header<W: Write>(w: &mut W, header: &ObjectHeader) -> Result<(), Error> {
+    w.write_all(HEADER_MAGIC)?;
+    write!(w, "{} ", FORMAT_VERSION)?;
+
+    match *header {
+        ObjectHeader::Large { size, depth } => write!(w, "large {} {}\n", size, depth)?,
+        ObjectHeader::Tree => write!(w, "tree\n")?,
+        ObjectHeader::Commit => write!(w, "commit\n")?,
+    }
+
+    Ok(())
+}
+
+/// Small objects are written as raw data, without a header, so that the digest of a chunk depends
+/// on nothing but its contents.
 pub fn small<B: Backend>(builder: &mut Builder<B>, object: &Small) -> Result<(), Error> {
     builder.write_all(&object.data)?;
     Ok(())
 }
 
 pub fn large<B: Backend>(builder: &mut Builder<B>, object: &Large<Handle<B>>) -> Result<(), Error> {
+    header(
+        builder,
+        &ObjectHeader::Large {
+            size: object.size,
+            depth: object.depth,
+        },
+    )?;
+
+    if let Some(ref splitter) = object.splitter {
+        ensure!(
+            !splitter.contains('\n'),
+            "Bad splitter tag {:?}: tags may not contain newlines!",
+            splitter
+        );
+        write!(builder, "splitter {}\n", splitter)?;
+    }
+
     let mut handles = HashMap::new();
 
     for (&start, &(end, ref reference)) in &object.entries {
@@ -46,7 +81,64 @@ pub fn large<B: Backend>(builder: &mut Builder<B>, object: &Large<Handle<B>>) ->
     Ok(())
 }
 
+fn entry_kind<W: Write, H>(w: &mut W, reference: &ObjectRef<H>) -> Result<(), Error> {
+    match *reference {
+        ObjectRef::Small(ref small) => write!(w, "data {} {}", small.size(), 0)?,
+        ObjectRef::Large(ref large) => write!(w, "data {} {}", large.size(), large.depth())?,
+        ObjectRef::Tree(_) => write!(w, "tree")?,
+        ObjectRef::Commit(_) => write!(w, "commit")?,
+    }
+
+    Ok(())
+}
+
+/// The kind keyword of a data entry in a tree, given its mode.
+fn mode_kind(mode: FileMode) -> &'static str {
+    match mode {
+        FileMode::Regular => "data",
+        FileMode::Executable => "exec",
+        FileMode::Symlink => "link",
+    }
+}
+
+/// Write named metadata objects as netstring entries of the form `@<arg> <kind> <name>`, pushing
+/// their handles as arguments of the object being built.
+fn metadata<'a, B, I, N>(builder: &mut Builder<B>, metadata: I) -> Result<(), Error>
+where
+    B: Backend,
+    I: IntoIterator<Item = (N, &'a ObjectRef<Handle<B>>)>,
+    N: Display,
+{
+    let mut handles = HashMap::new();
+
+    for (name, reference) in metadata {
+        let handle = reference.as_inner();
+        let id = match handles.get(handle) {
+            Some(&id) => id,
+            None => {
+                let new_id = handles.len();
+                handles.insert(handle, new_id);
+                builder.push_argument(handle.clone());
+                new_id
+            }
+        };
+
+        let mut buf = Vec::new();
+        write!(&mut buf, "@{} ", id)?;
+        entry_kind(&mut buf, reference)?;
+        write!(&mut buf, " {}", name)?;
+
+        write!(builder, "{}:", buf.len())?;
+        builder.write_all(&buf)?;
+        write!(builder, ",\n")?;
+    }
+
+    Ok(())
+}
+
 pub fn tree<B: Backend>(builder: &mut Builder<B>, object: &Tree<Handle<B>>) -> Result<(), Error> {
+    header(builder, &ObjectHeader::Tree)?;
+
     let mut handles = HashMap::new();
 
     for (name, reference) in &object.entries {
@@ -64,12 +156,22 @@ pub fn tree<B: Backend>(builder: &mut Builder<B>, object: &Tree<Handle<B>>) -> R
         let mut buf = Vec::new();
         write!(&mut buf, "{} ", id)?;
 
-        match *reference {
-            ObjectRef::Small(ref small) => write!(&mut buf, "data {} {}", small.size(), 0)?,
-            ObjectRef::Large(ref large) => {
-                write!(&mut buf, "data {} {}", large.size(), large.depth())?
+        // Executable files and symlinks are written with kinds of their own rather than alongside
+        // a mode, so that trees without any look exactly as they did before modes existed.
+        match (reference, object.mode(name)) {
+            (&ObjectRef::Small(_), FileMode::Regular)
+            | (&ObjectRef::Large(_), FileMode::Regular)
+            | (&ObjectRef::Tree(_), _) => entry_kind(&mut buf, reference)?,
+            (&ObjectRef::Small(ref small), mode) => {
+                write!(&mut buf, "{} {} {}", mode_kind(mode), small.size(), 0)?
             }
-            ObjectRef::Tree(_) => write!(&mut buf, "tree")?,
+            (&ObjectRef::Large(ref large), mode) => write!(
+                &mut buf,
+                "{} {} {}",
+                mode_kind(mode),
+                large.size(),
+                large.depth()
+            )?,
             _ => bail!("Bad tree object: child with bad kind (not small, large or tree)"),
         };
 
@@ -80,38 +182,76 @@ pub fn tree<B: Backend>(builder: &mut Builder<B>, object: &Tree<Handle<B>>) -> R
         write!(builder, ",\n")?;
     }
 
+    // Entries' POSIX metadata shares the tree's arguments with its named metadata, under names
+    // which can't be confused with it. Metadata left behind by entries since replaced or removed
+    // through `DerefMut` is never written, so it can't change the tree's digest.
+    let posix = object
+        .posix
+        .iter()
+        .filter(|&(name, _)| object.entries.contains_key(name))
+        .map(|(name, reference)| (format!("{}/{}", ATTACA_POSIX_METADATA, name), reference));
+    metadata(
+        builder,
+        object
+            .metadata
+            .iter()
+            .map(|(name, reference)| (name.clone(), reference))
+            .chain(posix),
+    )?;
+
     Ok(())
 }
 
-// TODO: Robust RDF formatting/parsing - current breaks for non-ASCII strings:
-// https://github.com/sdleffler/attaca/issues/25
+/// Escape a string as the body of an N-Triples string literal.
+///
+/// The result is pure ASCII: quotes, backslashes and the usual whitespace escapes are written as
+/// `ECHAR`s, and all other control and non-ASCII characters as `\uXXXX` or `\UXXXXXXXX`. Every
+/// string has exactly one escaped form, so commit digests are deterministic.
+pub fn escape_literal(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            ' '...'~' => escaped.push(c),
+            c if (c as u32) <= 0xFFFF => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push_str(&format!("\\U{:08X}", c as u32)),
+        }
+    }
+
+    escaped
+}
+
 pub fn commit<B: Backend>(
     builder: &mut Builder<B>,
     object: &Commit<Handle<B>>,
 ) -> Result<(), Error> {
-    fn rdf_literal(s: &str) -> Vec<u8> {
-        s.as_bytes()
-            .iter()
-            .cloned()
-            .flat_map(ascii::escape_default)
-            .collect::<Vec<_>>()
-    }
+    header(builder, &ObjectHeader::Commit)?;
 
     builder.push(object.subtree.as_inner().clone());
     for parent in &object.parents {
         builder.push(parent.as_inner().clone());
     }
 
-    // The `0` is for metadata refs; N-triples metadata does not yet use refs (since it's just
-    // author/message metadata) but it might eventually.
-    write!(builder, "{} {}\n", object.parents.len(), 0)?;
+    // Metadata objects are attached as arguments, and listed immediately after the header.
+    write!(
+        builder,
+        "{} {}\n",
+        object.parents.len(),
+        object.metadata.len()
+    )?;
+    metadata(builder, &object.metadata)?;
 
     let mut ntriples = BTreeSet::new();
 
     if let Some(name) = object.author.name.as_ref() {
         let mut buf = Vec::new();
         write!(&mut buf, "_:author <{}> \"", FOAF_NAME)?;
-        buf.write_all(&rdf_literal(name))?;
+        buf.write_all(escape_literal(name).as_bytes())?;
         write!(&mut buf, "\" .\n")?;
         ntriples.insert(buf);
     }
@@ -119,7 +259,31 @@ pub fn commit<B: Backend>(
     if let Some(mbox) = object.author.mbox.as_ref() {
         let mut buf = Vec::new();
         write!(&mut buf, "_:author <{}> \"", FOAF_MBOX)?;
-        buf.write_all(&rdf_literal(mbox))?;
+        buf.write_all(escape_literal(mbox).as_bytes())?;
+        write!(&mut buf, "\" .\n")?;
+        ntriples.insert(buf);
+    }
+
+    if let Some(committer) = object.committer.as_ref() {
+        if let Some(name) = committer.name.as_ref() {
+            let mut buf = Vec::new();
+            write!(&mut buf, "_:committer <{}> \"", FOAF_NAME)?;
+            buf.write_all(escape_literal(name).as_bytes())?;
+            write!(&mut buf, "\" .\n")?;
+            ntriples.insert(buf);
+        }
+
+        if let Some(mbox) = committer.mbox.as_ref() {
+            let mut buf = Vec::new();
+            write!(&mut buf, "_:committer <{}> \"", FOAF_MBOX)?;
+            buf.write_all(escape_literal(mbox).as_bytes())?;
+            write!(&mut buf, "\" .\n")?;
+            ntriples.insert(buf);
+        }
+
+        let mut buf = Vec::new();
+        write!(&mut buf, "_:committer <{}> \"", ATTACA_COMMIT_TIMESTAMP)?;
+        buf.write_all(escape_literal(&committer.timestamp.to_rfc2822()).as_bytes())?;
         write!(&mut buf, "\" .\n")?;
         ntriples.insert(buf);
     }
@@ -127,7 +291,7 @@ pub fn commit<B: Backend>(
     if let Some(message) = object.as_message() {
         let mut buf = Vec::new();
         write!(&mut buf, "_:this <{}> \"", ATTACA_COMMIT_MESSAGE)?;
-        buf.write_all(&rdf_literal(message))?;
+        buf.write_all(escape_literal(message).as_bytes())?;
         write!(&mut buf, "\" .\n")?;
         ntriples.insert(buf);
     }
@@ -135,7 +299,23 @@ pub fn commit<B: Backend>(
     {
         let mut buf = Vec::new();
         write!(&mut buf, "_:this <{}> \"", ATTACA_COMMIT_TIMESTAMP)?;
-        buf.write_all(&rdf_literal(&object.as_timestamp().to_rfc2822()))?;
+        buf.write_all(escape_literal(&object.as_timestamp().to_rfc2822()).as_bytes())?;
+        write!(&mut buf, "\" .\n")?;
+        ntriples.insert(buf);
+    }
+
+    // Each property gets its own blank node, numbered in the order the properties were added, so
+    // that keys need not be valid IRIs and may repeat.
+    for (i, (key, value)) in object.properties.iter().enumerate() {
+        let mut buf = Vec::new();
+        write!(&mut buf, "_:property{} <{}> \"", i, ATTACA_PROPERTY_KEY)?;
+        buf.write_all(escape_literal(key).as_bytes())?;
+        write!(&mut buf, "\" .\n")?;
+        ntriples.insert(buf);
+
+        let mut buf = Vec::new();
+        write!(&mut buf, "_:property{} <{}> \"", i, ATTACA_PROPERTY_VALUE)?;
+        buf.write_all(escape_literal(value).as_bytes())?;
         write!(&mut buf, "\" .\n")?;
         ntriples.insert(buf);
     }
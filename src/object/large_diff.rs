@@ -0,0 +1,328 @@
+//! Comparing two large objects chunk by chunk.
+//!
+//! Chunk boundaries are chosen by content, so an edit to a large file usually changes only the
+//! chunks around it. Everything else is shared with the old version, either chunk by chunk or as
+//! whole subtrees of the large object. Shared subtrees are recognized by handle and never fetched.
+
+use std::{collections::{HashMap, HashSet}, ops::Range};
+
+use failure::Error;
+use futures::prelude::*;
+
+use object::{Large, LargeRef, ObjectRef, SmallRef};
+use store::prelude::*;
+
+/// A single difference between an old and a new version of a large object. Ranges are absolute
+/// byte offsets into the old or new object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LargeChange<H> {
+    /// Bytes of the new object which also appear in the old one, sharing the same small object or
+    /// large subtree. They may have moved.
+    Reused {
+        new: Range<u64>,
+        old: Range<u64>,
+        objref: ObjectRef<H>,
+    },
+
+    /// Bytes of the new object held in a chunk which the old object doesn't have.
+    Added { new: Range<u64>, chunk: SmallRef<H> },
+
+    /// Bytes of the old object held in a chunk which the new object doesn't have.
+    Removed { old: Range<u64>, chunk: SmallRef<H> },
+}
+
+impl<H> LargeChange<H> {
+    /// The number of bytes covered by this change.
+    pub fn len(&self) -> u64 {
+        match *self {
+            LargeChange::Reused { ref new, .. } | LargeChange::Added { ref new, .. } => {
+                new.end - new.start
+            }
+            LargeChange::Removed { ref old, .. } => old.end - old.start,
+        }
+    }
+}
+
+/// Stream the differences between two large objects.
+///
+/// Reused and added ranges come first, in order, and together cover the whole of the new object.
+/// They are followed by the removed ranges of the old object, also in order.
+pub fn diff_large<B: Backend>(
+    old: LargeRef<Handle<B>>,
+    new: LargeRef<Handle<B>>,
+) -> impl Stream<Item = LargeChange<Handle<B>>, Error = Error> {
+    do_diff_large(old, new)
+}
+
+/// Everything we need to know about the old object to find what's shared with the new one.
+struct OldIndex<B: Backend> {
+    /// Every leaf of the old object, sorted by offset.
+    leaves: Vec<(Range<u64>, SmallRef<Handle<B>>)>,
+
+    /// The range of the first occurrence of each chunk.
+    chunks: HashMap<Handle<B>, Range<u64>>,
+
+    /// The range of the first occurrence of each large subtree, including the root.
+    subtrees: HashMap<Handle<B>, Range<u64>>,
+}
+
+impl<B: Backend> OldIndex<B> {
+    /// The leaves lying entirely within a range of the old object.
+    fn leaves_within(&self, range: Range<u64>) -> &[(Range<u64>, SmallRef<Handle<B>>)] {
+        let start = match self.leaves
+            .binary_search_by_key(&range.start, |&(ref leaf, _)| leaf.start)
+        {
+            Ok(i) | Err(i) => i,
+        };
+        let len = self.leaves[start..]
+            .iter()
+            .take_while(|&&(ref leaf, _)| leaf.end <= range.end)
+            .count();
+
+        &self.leaves[start..start + len]
+    }
+}
+
+#[async]
+fn index_large<B: Backend>(old_ref: LargeRef<Handle<B>>) -> Result<OldIndex<B>, Error> {
+    let mut index = OldIndex {
+        leaves: Vec::new(),
+        chunks: HashMap::new(),
+        subtrees: HashMap::new(),
+    };
+    index
+        .subtrees
+        .insert(old_ref.as_inner().clone(), 0..old_ref.size());
+
+    let old_large = await!(old_ref.fetch())?;
+    let mut stack = vec![(0u64, old_large.into_iter())];
+
+    loop {
+        let next = match stack.last_mut() {
+            Some(&mut (offset, ref mut iter)) => iter.next()
+                .map(|(range, objref)| (offset + range.start..offset + range.end, objref)),
+            None => break,
+        };
+
+        match next {
+            Some((range, ObjectRef::Small(small_ref))) => {
+                index
+                    .chunks
+                    .entry(small_ref.as_inner().clone())
+                    .or_insert_with(|| range.clone());
+                index.leaves.push((range, small_ref));
+            }
+            Some((range, ObjectRef::Large(large_ref))) => {
+                index
+                    .subtrees
+                    .entry(large_ref.as_inner().clone())
+                    .or_insert_with(|| range.clone());
+                let child: Large<Handle<B>> = await!(large_ref.fetch())?;
+                stack.push((range.start, child.into_iter()));
+            }
+            Some(_) => bail!("Large object has an entry which is not data!"),
+            None => {
+                stack.pop();
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+#[async_stream(item = LargeChange<Handle<B>>)]
+fn do_diff_large<B: Backend>(
+    old_ref: LargeRef<Handle<B>>,
+    new_ref: LargeRef<Handle<B>>,
+) -> Result<(), Error> {
+    if old_ref.as_inner() == new_ref.as_inner() {
+        stream_yield!(LargeChange::Reused {
+            new: 0..new_ref.size(),
+            old: 0..old_ref.size(),
+            objref: ObjectRef::Large(new_ref),
+        });
+        return Ok(());
+    }
+
+    let (index, new_large) = await!(index_large(old_ref).join(new_ref.fetch()))?;
+
+    // Handles of every old chunk which still appears somewhere in the new object.
+    let mut reused = HashSet::new();
+    let mut stack = vec![(0u64, new_large.into_iter())];
+
+    loop {
+        let next = match stack.last_mut() {
+            Some(&mut (offset, ref mut iter)) => iter.next()
+                .map(|(range, objref)| (offset + range.start..offset + range.end, objref)),
+            None => break,
+        };
+
+        let (range, objref) = match next {
+            Some(entry) => entry,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+
+        let found = match objref {
+            ObjectRef::Small(ref small_ref) => index.chunks.get(small_ref.as_inner()).cloned(),
+            ObjectRef::Large(ref large_ref) => index.subtrees.get(large_ref.as_inner()).cloned(),
+            _ => bail!("Large object has an entry which is not data!"),
+        };
+
+        match (found, objref) {
+            (Some(old), objref) => {
+                for &(_, ref chunk) in index.leaves_within(old.clone()) {
+                    reused.insert(chunk.as_inner().clone());
+                }
+
+                stream_yield!(LargeChange::Reused {
+                    new: range,
+                    old,
+                    objref,
+                });
+            }
+            (None, ObjectRef::Small(chunk)) => {
+                stream_yield!(LargeChange::Added { new: range, chunk });
+            }
+            (None, ObjectRef::Large(large_ref)) => {
+                let child = await!(large_ref.fetch())?;
+                stack.push((range.start, child.into_iter()));
+            }
+            (None, _) => unreachable!(),
+        }
+    }
+
+    for (old, chunk) in index.leaves {
+        if !reused.contains(chunk.as_inner()) {
+            stream_yield!(LargeChange::Removed { old, chunk });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use object::tests::send_large;
+    use store::dummy::MemoryBackend;
+
+    /// 200 bytes which make 20 distinct chunks of 10.
+    fn data() -> Vec<u8> {
+        (0..200).map(|i| i as u8).collect()
+    }
+
+    fn diff(
+        store: &Store<MemoryBackend>,
+        old: &[u8],
+        new: &[u8],
+        depth: u8,
+    ) -> Vec<LargeChange<Handle<MemoryBackend>>> {
+        let old_ref = send_large(store, old, 10, 5, depth).send(store).wait().unwrap();
+        let new_ref = send_large(store, new, 10, 5, depth).send(store).wait().unwrap();
+        diff_large(old_ref, new_ref).collect().wait().unwrap()
+    }
+
+    fn ranges(
+        changes: &[LargeChange<Handle<MemoryBackend>>],
+    ) -> (Vec<Range<u64>>, Vec<Range<u64>>, Vec<Range<u64>>) {
+        let (mut reused, mut added, mut removed) = (Vec::new(), Vec::new(), Vec::new());
+        for change in changes {
+            match *change {
+                LargeChange::Reused { ref new, .. } => reused.push(new.clone()),
+                LargeChange::Added { ref new, .. } => added.push(new.clone()),
+                LargeChange::Removed { ref old, .. } => removed.push(old.clone()),
+            }
+        }
+        (reused, added, removed)
+    }
+
+    #[test]
+    fn leaves_within_excludes_partial_leaves() {
+        let store = Store::new(MemoryBackend::default());
+        let old_ref = send_large(&store, &data(), 10, 5, 2).send(&store).wait().unwrap();
+        let index = index_large(old_ref).wait().unwrap();
+        let starts = |range: Range<u64>| {
+            index
+                .leaves_within(range)
+                .iter()
+                .map(|&(ref leaf, _)| leaf.start)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(index.leaves.len(), 20);
+        assert_eq!(starts(10..30), vec![10, 20]);
+        assert_eq!(starts(15..30), vec![20]);
+        assert_eq!(starts(0..25), vec![0, 10]);
+        assert_eq!(starts(195..200), Vec::<u64>::new());
+        assert_eq!(starts(0..200).len(), 20);
+    }
+
+    #[test]
+    fn identical_objects_are_reused_whole() {
+        let store = Store::new(MemoryBackend::default());
+        let changes = diff(&store, &data(), &data(), 2);
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(ranges(&changes), (vec![0..200], vec![], vec![]));
+    }
+
+    #[test]
+    fn edited_chunk_is_added_and_removed() {
+        let store = Store::new(MemoryBackend::default());
+        let mut new = data();
+        new[45] = 0xff;
+        let changes = diff(&store, &data(), &new, 1);
+        let (reused, added, removed) = ranges(&changes);
+
+        assert_eq!(reused.len(), 19);
+        assert_eq!(added, vec![40..50]);
+        assert_eq!(removed, vec![40..50]);
+        assert_eq!(changes.iter().map(LargeChange::len).sum::<u64>(), 210);
+    }
+
+    #[test]
+    fn chunks_of_reused_subtrees_are_not_removed() {
+        let store = Store::new(MemoryBackend::default());
+        let mut new = data();
+        new[45] = 0xff;
+        let changes = diff(&store, &data(), &new, 2);
+        let (reused, added, removed) = ranges(&changes);
+
+        // The first subtree is expanded chunk by chunk; the rest are reused whole.
+        assert_eq!(
+            reused,
+            vec![0..10, 10..20, 20..30, 30..40, 50..100, 100..150, 150..200]
+        );
+        assert_eq!(added, vec![40..50]);
+        assert_eq!(removed, vec![40..50]);
+    }
+
+    #[test]
+    fn moved_chunks_are_reused_and_dropped_chunks_removed() {
+        let store = Store::new(MemoryBackend::default());
+        let old = data();
+        // Swap the first two chunks and drop the last one.
+        let new = old[10..20]
+            .iter()
+            .chain(&old[0..10])
+            .chain(&old[20..190])
+            .cloned()
+            .collect::<Vec<_>>();
+        let changes = diff(&store, &old, &new, 1);
+
+        match changes[0] {
+            LargeChange::Reused { ref new, ref old, .. } => {
+                assert_eq!((new.clone(), old.clone()), (0..10, 10..20));
+            }
+            ref other => panic!("Expected a reused chunk, got {:?}", other),
+        }
+        let (reused, added, removed) = ranges(&changes);
+        assert_eq!(reused.len(), 19);
+        assert!(added.is_empty());
+        assert_eq!(removed, vec![190..200]);
+    }
+}
@@ -3,3 +3,10 @@ pub const ATTACA_COMMIT_TIMESTAMP: &'static str = "http://attaca.io/ontology/#co
 
 pub const FOAF_MBOX: &'static str = "http://xmlns.com/foaf/spec/#term_mbox";
 pub const FOAF_NAME: &'static str = "http://xmlns.com/foaf/spec/#term_name";
+
+pub const ATTACA_PROPERTY_KEY: &'static str = "http://attaca.io/ontology/#propertyKey";
+pub const ATTACA_PROPERTY_VALUE: &'static str = "http://attaca.io/ontology/#propertyValue";
+
+/// Prefix of the names under which trees encode the POSIX metadata of their entries, followed by
+/// a `/` and the entry's name.
+pub const ATTACA_POSIX_METADATA: &'static str = "http://attaca.io/ontology/#posixMetadata";
@@ -1,19 +1,29 @@
 pub mod decode;
 pub mod encode;
 pub mod metadata;
+pub mod posix;
 
-use std::{mem, borrow::Borrow, collections::{btree_map, BTreeMap, Bound}, io::{self, Read, Write},
-          ops::{Deref, DerefMut, Range}};
+mod large_diff;
+mod reader;
+
+pub use self::large_diff::{diff_large, LargeChange};
+pub use self::posix::Posix;
+pub use self::reader::LargeReader;
+
+use std::{mem, thread, borrow::Borrow, collections::{btree_map, BTreeMap, Bound},
+          io::{self, Read, Write}, ops::{Deref, DerefMut, Range},
+          sync::{Arc, Mutex}};
 
 use chrono::prelude::*;
 use failure::Error;
-use futures::{prelude::*, stream::FuturesOrdered};
+use futures::{stream, prelude::*, sync::{mpsc, oneshot}};
 
-use digest::prelude::*;
-use split::{Parameters, Splitter};
+use canonical;
+use digest::{prelude::*, Sha3Digest};
+use split::{Chunker, Parameters, Splitter};
 use store::prelude::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Object<H> {
     Small(Small),
     Large(Large<H>),
@@ -22,6 +32,27 @@ pub enum Object<H> {
 }
 
 impl<B: Backend> Object<Handle<B>> {
+    /// Fetch and decode an object of unknown kind using its type header, yielding both the
+    /// object and a correctly typed reference to it. Objects without a header are small.
+    pub fn fetch(handle: &Handle<B>) -> FutureAnyObject<B> {
+        FutureAnyObject {
+            handle: handle.clone(),
+            blocking: handle.load(),
+        }
+    }
+
+    /// Construct a reference to this object, given the handle it was fetched from.
+    pub fn to_ref(&self, handle: Handle<B>) -> ObjectRef<Handle<B>> {
+        match *self {
+            Object::Small(ref small) => ObjectRef::Small(SmallRef::new(small.size(), handle)),
+            Object::Large(ref large) => {
+                ObjectRef::Large(LargeRef::new(large.size(), large.depth(), handle))
+            }
+            Object::Tree(_) => ObjectRef::Tree(TreeRef::new(handle)),
+            Object::Commit(_) => ObjectRef::Commit(CommitRef::new(handle)),
+        }
+    }
+
     pub fn kind(&self) -> ObjectKind {
         match *self {
             Object::Small(_) => ObjectKind::Small,
@@ -112,6 +143,37 @@ pub enum ObjectKind {
     Commit,
 }
 
+/// The version of the object encoding written by `encode`. Objects written before type headers
+/// were introduced carry no header at all, and are still accepted by the typed decoders. Small
+/// objects never carry one.
+pub const FORMAT_VERSION: u64 = 1;
+
+/// The magic bytes which begin every object header.
+pub const HEADER_MAGIC: &[u8] = b"\0attaca ";
+
+/// The versioned type header prefixed to every encoded large object, tree and commit, which makes
+/// an object decodable given nothing but its handle. It has the form `\0attaca <version> <kind>\n`,
+/// where large objects also record their size and depth.
+///
+/// Small objects are left as raw data, so that chunks keep the digests they had before headers
+/// were introduced and still deduplicate against chunks stored back then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ObjectHeader {
+    Large { size: u64, depth: u8 },
+    Tree,
+    Commit,
+}
+
+impl ObjectHeader {
+    pub fn kind(&self) -> ObjectKind {
+        match *self {
+            ObjectHeader::Large { .. } => ObjectKind::Large,
+            ObjectHeader::Tree => ObjectKind::Tree,
+            ObjectHeader::Commit => ObjectKind::Commit,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ObjectRef<H> {
     Small(SmallRef<H>),
@@ -194,8 +256,48 @@ impl<B: Backend> ObjectRef<Handle<B>> {
             ObjectRef::Commit(ref commit_ref) => FutureObjectId::Commit(commit_ref.id()),
         }
     }
+
+    /// Stream the contents of a small or large object into a writer, in order, returning the
+    /// writer once done. Up to `WRITE_TO_LOOKAHEAD` chunks are fetched ahead of the one being
+    /// written. Fails for trees and commits, which have no contents as such.
+    pub fn write_to<W: Write + 'static>(
+        &self,
+        mut writer: W,
+    ) -> impl Future<Item = W, Error = Error> {
+        let objref = self.clone();
+
+        async_block! {
+            match objref {
+                ObjectRef::Small(small_ref) => {
+                    let small = await!(small_ref.fetch())?;
+                    writer.write_all(&small)?;
+                }
+                ObjectRef::Large(large_ref) => {
+                    let large = await!(large_ref.fetch())?;
+                    let smalls = large
+                        .leaves()
+                        .map(|small_ref| small_ref.fetch())
+                        .buffered(WRITE_TO_LOOKAHEAD);
+
+                    #[async]
+                    for small in smalls {
+                        writer.write_all(&small)?;
+                    }
+                }
+                ObjectRef::Tree(_) | ObjectRef::Commit(_) => {
+                    bail!("Only small and large objects have contents to write!")
+                }
+            }
+
+            writer.flush()?;
+            Ok(writer)
+        }
+    }
 }
 
+/// The number of chunks `ObjectRef::write_to` may fetch ahead of the one it is writing.
+const WRITE_TO_LOOKAHEAD: usize = 32;
+
 pub enum FutureResolvedDigestObject<B: Backend> {
     Small(FutureResolvedDigestSmall<B>),
     Large(FutureResolvedDigestLarge<B>),
@@ -297,6 +399,26 @@ impl<B: Backend> Future for FutureObject<B> {
     }
 }
 
+pub struct FutureAnyObject<B: Backend> {
+    handle: Handle<B>,
+    blocking: FutureContent<B>,
+}
+
+impl<B: Backend> Future for FutureAnyObject<B> {
+    type Item = (ObjectRef<Handle<B>>, Object<Handle<B>>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.blocking.poll()? {
+            Async::Ready(content) => {
+                let object = decode::object::<B>(content)?;
+                Ok(Async::Ready((object.to_ref(self.handle.clone()), object)))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
 pub struct FutureSmall<B: Backend>(FutureContent<B>);
 
 impl<B: Backend> Future for FutureSmall<B> {
@@ -477,6 +599,19 @@ impl Small {
         self.data.len() as u64
     }
 
+    /// Calculate the canonical encoding of this object, from which any store identifying objects
+    /// by `D` digests will derive its digest. This means hashing all of its data.
+    pub fn canonical<D: Digest>(&self) -> Result<Vec<u8>, Error> {
+        let mut encoded = Vec::new();
+        canonical::encode::<_, D>(&mut encoded, &self.data, &[], &[])?;
+        Ok(encoded)
+    }
+
+    /// Calculate the digest any store will assign to this object, without sending it.
+    pub fn digest<D: Digest>(&self) -> Result<D, Error> {
+        Ok(D::digest(&self.canonical::<D>()?))
+    }
+
     pub fn send<B: Backend>(&self, store: &Store<B>) -> FutureSmallHandle<B> {
         let mut builder = store.builder();
         FutureSmallHandle {
@@ -489,6 +624,25 @@ impl Small {
             size: self.size(),
         }
     }
+
+    /// Send this object to the store along with its canonical encoding, as calculated by
+    /// `canonical::<D>`, so that the store doesn't have to hash it again.
+    pub fn send_encoded<B: Backend, D: Digest>(
+        &self,
+        store: &Store<B>,
+        encoded: Vec<u8>,
+    ) -> FutureSmallHandle<B> {
+        let mut builder = store.builder();
+        FutureSmallHandle {
+            blocking: Box::new(
+                encode::small(&mut builder, self)
+                    .map(|()| builder.finish_encoded::<D>(encoded))
+                    .into_future()
+                    .flatten(),
+            ),
+            size: self.size(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -532,6 +686,10 @@ impl SmallBuilder {
     pub fn as_small(&self) -> &Small {
         &self.0
     }
+
+    pub fn into_small(self) -> Small {
+        self.0
+    }
 }
 
 pub struct FutureLarge<B: Backend> {
@@ -786,6 +944,7 @@ impl<'a, H: 'a> Iterator for LargeRangeIter<'a, H> {
 pub struct Large<H> {
     size: u64,
     depth: u8,
+    splitter: Option<String>,
     entries: BTreeMap<u64, (u64, ObjectRef<H>)>,
 }
 
@@ -813,10 +972,16 @@ impl<H> Large<H> {
         self.size
     }
 
+    /// A description of the chunker and parameters which produced this object's entries, if it
+    /// was recorded. Objects built with different splitters will not share entries.
+    pub fn splitter(&self) -> Option<&str> {
+        self.splitter.as_ref().map(String::as_str)
+    }
+
     pub fn range(&self, range: Range<u64>) -> LargeRangeIter<H> {
         let start = match self.entries.range(..range.start).rev().next() {
             // If there's an overlapping entry which comes before range.start, include it.
-            Some((end, _)) if end > &range.start => Bound::Included(end),
+            Some((start, &(end, _))) if end > range.start => Bound::Included(start),
             // Otherwise, it's just a standard inclusive bound on range.start.
             Some((_, _)) => Bound::Included(&range.start),
             None => Bound::Unbounded,
@@ -835,6 +1000,12 @@ impl<H> Large<H> {
 }
 
 impl<B: Backend> Large<Handle<B>> {
+    /// Stream the small objects at the bottom of this large object, in order, fetching nested
+    /// large objects only as they're reached.
+    pub fn leaves(self) -> impl Stream<Item = SmallRef<Handle<B>>, Error = Error> {
+        large_leaves(self)
+    }
+
     pub fn send(&self, store: &Store<B>) -> FutureLargeHandle<B> {
         let mut builder = store.builder();
         FutureLargeHandle {
@@ -850,6 +1021,33 @@ impl<B: Backend> Large<Handle<B>> {
     }
 }
 
+#[async_stream(item = SmallRef<Handle<B>>)]
+fn large_leaves<B: Backend>(large: Large<Handle<B>>) -> Result<(), Error> {
+    // Depth-first, keeping one iterator per level between here and the current leaf.
+    let mut stack = vec![large.into_iter()];
+
+    loop {
+        let next = match stack.last_mut() {
+            Some(iter) => iter.next(),
+            None => break,
+        };
+
+        match next {
+            Some((_, ObjectRef::Small(small_ref))) => stream_yield!(small_ref),
+            Some((_, ObjectRef::Large(large_ref))) => {
+                let child = await!(large_ref.fetch())?;
+                stack.push(child.into_iter());
+            }
+            Some(_) => bail!("Large object has an entry which is not data!"),
+            None => {
+                stack.pop();
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct LargeBuilder<H>(Large<H>);
 
@@ -858,10 +1056,17 @@ impl<H> LargeBuilder<H> {
         LargeBuilder(Large {
             size: 0,
             depth,
+            splitter: None,
             entries: BTreeMap::new(),
         })
     }
 
+    /// Record the chunker which produced this object's entries.
+    pub fn splitter(&mut self, tag: String) -> &mut Self {
+        self.0.splitter = Some(tag);
+        self
+    }
+
     pub fn as_large(&self) -> &Large<H> {
         &self.0
     }
@@ -1023,9 +1228,63 @@ impl<B: Backend> Future for FutureTreeId<B> {
     }
 }
 
+/// The mode of a data entry in a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileMode {
+    Regular,
+    Executable,
+
+    /// A symbolic link, whose data is the link's target, unresolved.
+    Symlink,
+}
+
+impl Default for FileMode {
+    fn default() -> Self {
+        FileMode::Regular
+    }
+}
+
+/// Everything recorded about an entry in a tree besides the object it names.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EntryAttrs<H> {
+    /// Always `Regular` for subtrees.
+    pub mode: FileMode,
+
+    /// A small object holding the entry's `Posix` metadata, if any was recorded. It is attached to
+    /// the tree rather than the entry's data, so it never affects the digest of the data.
+    pub posix: Option<ObjectRef<H>>,
+}
+
+impl<H> Default for EntryAttrs<H> {
+    fn default() -> Self {
+        Self {
+            mode: FileMode::default(),
+            posix: None,
+        }
+    }
+}
+
+impl<H> From<FileMode> for EntryAttrs<H> {
+    fn from(mode: FileMode) -> Self {
+        Self { mode, posix: None }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Tree<H> {
     entries: BTreeMap<String, ObjectRef<H>>,
+
+    // Modes of data entries, for those which are not `FileMode::Regular`. Trees written before
+    // modes were recorded simply have none.
+    modes: BTreeMap<String, FileMode>,
+
+    // POSIX metadata objects of data entries and subtrees, for those which have any. These are
+    // encoded as metadata under names derived from the entries' names.
+    posix: BTreeMap<String, ObjectRef<H>>,
+
+    // Named metadata objects attached to the tree. These are encoded as arguments rather than refs
+    // and are kept separate from the tree's entries.
+    metadata: BTreeMap<String, ObjectRef<H>>,
 }
 
 impl<H> Deref for Tree<H> {
@@ -1049,6 +1308,61 @@ impl<H> Tree<H> {
     pub fn diverge(self) -> TreeBuilder<H> {
         TreeBuilder(self)
     }
+
+    pub fn as_metadata(&self) -> &BTreeMap<String, ObjectRef<H>> {
+        &self.metadata
+    }
+
+    /// The mode of a named entry. Anything which isn't a data entry is always `Regular`.
+    pub fn mode(&self, name: &str) -> FileMode {
+        match self.entries.get(name) {
+            Some(&ObjectRef::Small(_)) | Some(&ObjectRef::Large(_)) => {
+                self.modes.get(name).cloned().unwrap_or_default()
+            }
+            _ => FileMode::Regular,
+        }
+    }
+
+    /// The attributes of a named entry. Subtrees may only have POSIX metadata, and anything else
+    /// which isn't a data entry has no attributes at all.
+    pub fn attrs(&self, name: &str) -> EntryAttrs<H>
+    where
+        H: Clone,
+    {
+        match self.entries.get(name) {
+            Some(&ObjectRef::Small(_)) | Some(&ObjectRef::Large(_)) => EntryAttrs {
+                mode: self.modes.get(name).cloned().unwrap_or_default(),
+                posix: self.posix.get(name).cloned(),
+            },
+            Some(&ObjectRef::Tree(_)) => EntryAttrs {
+                mode: FileMode::Regular,
+                posix: self.posix.get(name).cloned(),
+            },
+            _ => EntryAttrs::default(),
+        }
+    }
+
+    /// Consume the tree, yielding its entries along with their attributes.
+    pub fn into_iter_with_attrs(
+        self,
+    ) -> impl Iterator<Item = (String, ObjectRef<H>, EntryAttrs<H>)> {
+        let mut modes = self.modes;
+        let mut posix = self.posix;
+        self.entries.into_iter().map(move |(name, objref)| {
+            let attrs = match objref {
+                ObjectRef::Small(_) | ObjectRef::Large(_) => EntryAttrs {
+                    mode: modes.remove(&name).unwrap_or_default(),
+                    posix: posix.remove(&name),
+                },
+                ObjectRef::Tree(_) => EntryAttrs {
+                    mode: FileMode::Regular,
+                    posix: posix.remove(&name),
+                },
+                _ => EntryAttrs::default(),
+            };
+            (name, objref, attrs)
+        })
+    }
 }
 
 impl<B: Backend> Tree<Handle<B>> {
@@ -1090,13 +1404,78 @@ impl<H> TreeBuilder<H> {
     pub fn new() -> Self {
         TreeBuilder(Tree {
             entries: BTreeMap::new(),
+            modes: BTreeMap::new(),
+            posix: BTreeMap::new(),
+            metadata: BTreeMap::new(),
         })
     }
 
+    /// Insert an entry, replacing any entry of the same name along with its attributes.
+    pub fn insert(&mut self, name: String, objref: ObjectRef<H>) -> Option<ObjectRef<H>> {
+        self.0.modes.remove(&name);
+        self.0.posix.remove(&name);
+        self.0.entries.insert(name, objref)
+    }
+
+    /// Insert an entry along with its attributes, replacing any entry of the same name.
+    pub fn insert_with_attrs(
+        &mut self,
+        name: String,
+        objref: ObjectRef<H>,
+        attrs: EntryAttrs<H>,
+    ) -> Option<ObjectRef<H>> {
+        let previous = self.insert(name.clone(), objref);
+        self.set_attrs(&name, attrs);
+        previous
+    }
+
+    /// Remove an entry along with its attributes.
+    pub fn remove(&mut self, name: &str) -> Option<ObjectRef<H>> {
+        self.0.modes.remove(name);
+        self.0.posix.remove(name);
+        self.0.entries.remove(name)
+    }
+
+    /// Set all the attributes of a named entry at once. Modes are only recorded for data entries,
+    /// and POSIX metadata for data entries and subtrees.
+    pub fn set_attrs(&mut self, name: &str, attrs: EntryAttrs<H>) {
+        self.set_mode(name, attrs.mode);
+        match attrs.posix {
+            Some(posix) => {
+                self.0.posix.insert(name.to_owned(), posix);
+            }
+            None => {
+                self.0.posix.remove(name);
+            }
+        }
+    }
+
+    /// Set the mode of a named entry. Modes are only recorded for data entries, and are kept
+    /// separately from the entries themselves. Inserting or removing an entry clears its
+    /// attributes, but entries replaced through `DerefMut` keep theirs until they are set again.
+    pub fn set_mode(&mut self, name: &str, mode: FileMode) {
+        match mode {
+            FileMode::Regular => {
+                self.0.modes.remove(name);
+            }
+            _ => {
+                self.0.modes.insert(name.to_owned(), mode);
+            }
+        }
+    }
+
     pub fn as_tree(&self) -> &Tree<H> {
         &self.0
     }
 
+    pub fn as_metadata(&self) -> &BTreeMap<String, ObjectRef<H>> {
+        &self.0.metadata
+    }
+
+    pub fn as_metadata_mut(&mut self) -> &mut BTreeMap<String, ObjectRef<H>> {
+        &mut self.0.metadata
+    }
+
     pub fn into_tree(self) -> Tree<H> {
         self.0
     }
@@ -1249,6 +1628,27 @@ impl CommitAuthor {
     }
 }
 
+/// The identity which actually made a commit, and when; as opposed to its author, on whose behalf
+/// it may have been made.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitCommitter {
+    pub name: Option<String>,
+    pub mbox: Option<String>,
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+impl CommitCommitter {
+    /// A committer committing at the current local time.
+    pub fn now(name: Option<String>, mbox: Option<String>) -> Self {
+        let local = Local::now();
+        Self {
+            name,
+            mbox,
+            timestamp: local.with_timezone(local.offset()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Commit<H> {
     subtree: TreeRef<H>,
@@ -1256,7 +1656,11 @@ pub struct Commit<H> {
 
     timestamp: DateTime<FixedOffset>,
     author: CommitAuthor,
+    committer: Option<CommitCommitter>,
     message: Option<String>,
+    properties: Vec<(String, String)>,
+
+    metadata: BTreeMap<String, ObjectRef<H>>,
 }
 
 impl<H> Commit<H> {
@@ -1276,6 +1680,11 @@ impl<H> Commit<H> {
         &self.author
     }
 
+    /// The committer of this commit, if it was recorded separately from the author.
+    pub fn as_committer(&self) -> Option<&CommitCommitter> {
+        self.committer.as_ref()
+    }
+
     pub fn as_timestamp(&self) -> &DateTime<FixedOffset> {
         &self.timestamp
     }
@@ -1283,6 +1692,19 @@ impl<H> Commit<H> {
     pub fn as_message(&self) -> Option<&str> {
         self.message.as_ref().map(String::as_str)
     }
+
+    /// Custom key/value properties recorded on this commit, e.g. pipeline run IDs, source URLs
+    /// or dataset schema versions. Like trailers, they keep the order they were added in, and a
+    /// key may appear more than once.
+    pub fn as_properties(&self) -> &[(String, String)] {
+        &self.properties
+    }
+
+    /// Named metadata objects attached to this commit, e.g. attachments, schemas or provenance
+    /// records. These are hashed into the commit's canonical form as arguments rather than refs.
+    pub fn as_metadata(&self) -> &BTreeMap<String, ObjectRef<H>> {
+        &self.metadata
+    }
 }
 
 impl<B: Backend> Commit<Handle<B>> {
@@ -1303,7 +1725,10 @@ pub enum CommitBuilder<H> {
         parents: Vec<CommitRef<H>>,
         timestamp: DateTime<FixedOffset>,
         author: CommitAuthor,
+        committer: Option<CommitCommitter>,
         message: Option<String>,
+        properties: Vec<(String, String)>,
+        metadata: BTreeMap<String, ObjectRef<H>>,
     },
     Complete(Commit<H>),
 }
@@ -1323,7 +1748,10 @@ impl<H> Default for CommitBuilder<H> {
                 local.with_timezone(local.offset())
             },
             author: Default::default(),
+            committer: Default::default(),
             message: Default::default(),
+            properties: Default::default(),
+            metadata: Default::default(),
         }
     }
 }
@@ -1382,6 +1810,16 @@ impl<H> CommitBuilder<H> {
         self
     }
 
+    pub fn committer(&mut self, new_committer: CommitCommitter) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => commit.committer = Some(new_committer),
+            CommitBuilder::Incomplete {
+                ref mut committer, ..
+            } => *committer = Some(new_committer),
+        }
+        self
+    }
+
     pub fn subtree(&mut self, new_subtree: TreeRef<H>) -> &mut Self {
         let tmp = match mem::replace(self, CommitBuilder::default()) {
             CommitBuilder::Complete(commit) => Commit {
@@ -1391,14 +1829,20 @@ impl<H> CommitBuilder<H> {
             CommitBuilder::Incomplete {
                 parents,
                 author,
+                committer,
                 message,
                 timestamp,
+                properties,
+                metadata,
             } => Commit {
                 subtree: new_subtree,
                 parents,
                 timestamp,
                 author,
+                committer,
                 message,
+                properties,
+                metadata,
             },
         };
         *self = CommitBuilder::Complete(tmp);
@@ -1414,23 +1858,171 @@ impl<H> CommitBuilder<H> {
         }
         self
     }
+
+    pub fn property(&mut self, key: String, value: String) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => {
+                commit.properties.push((key, value));
+            }
+            CommitBuilder::Incomplete {
+                ref mut properties, ..
+            } => {
+                properties.push((key, value));
+            }
+        }
+        self
+    }
+
+    pub fn metadata(&mut self, name: String, objref: ObjectRef<H>) -> &mut Self {
+        match *self {
+            CommitBuilder::Complete(ref mut commit) => {
+                commit.metadata.insert(name, objref);
+            }
+            CommitBuilder::Incomplete {
+                ref mut metadata, ..
+            } => {
+                metadata.insert(name, objref);
+            }
+        }
+        self
+    }
 }
 
+/// Split a byte stream into chunks with the default splitter and send it to the store.
+///
+/// Repositories may be configured with other splitter parameters; prefer `share_chunked` with
+/// those wherever they are known, as objects split differently will not deduplicate.
 pub fn share<R: Read, B: Backend>(
     reader: R,
     store: Store<B>,
 ) -> impl Future<Item = ObjectRef<Handle<B>>, Error = Error> {
-    async_block! {
-        let mut splitter = Splitter::new(reader, Parameters::default());
+    share_chunked(Splitter::new(reader, Parameters::default()), store)
+}
 
-        let mut small_builder = SmallBuilder::new();
-        let mut chunks = FuturesOrdered::new();
-        while let Some(_) = splitter.find(&mut small_builder)? {
-            chunks.push(small_builder.as_small().send(&store));
-            small_builder.clear();
+/// The number of chunks a single share may read ahead of the oldest chunk still being hashed.
+const SHARE_READ_AHEAD: usize = 64;
+
+/// The number of threads in the process-wide pool hashing chunks for `share_chunked`.
+const SHARE_HASHER_THREADS: usize = 4;
+
+/// The number of chunks which may be in the process of being sent to the store at once.
+const SHARE_SENDS_IN_FLIGHT: usize = 32;
+
+/// A chunk, its canonical encoding and its digest.
+type HashedChunk = (Small, Vec<u8>, Sha3Digest);
+type HashJob = (Small, oneshot::Sender<Result<HashedChunk, Error>>);
+
+lazy_static! {
+    /// The queue of the process-wide pool of hasher threads, which is started the first time it's
+    /// needed and shared by every share in progress.
+    static ref HASHER_QUEUE: Mutex<mpsc::Sender<HashJob>> = Mutex::new(start_hashers());
+}
+
+fn start_hashers() -> mpsc::Sender<HashJob> {
+    let (work_tx, work_rx) = mpsc::channel::<HashJob>(SHARE_READ_AHEAD);
+    let work_rx = Arc::new(Mutex::new(work_rx.wait()));
+
+    for _ in 0..SHARE_HASHER_THREADS {
+        let work_rx = work_rx.clone();
+        thread::spawn(move || loop {
+            // Bind the result first so that the lock is released before hashing.
+            let next = work_rx.lock().unwrap().next();
+            match next {
+                Some(Ok((small, tx))) => {
+                    let hashed = small.canonical::<Sha3Digest>().map(|encoded| {
+                        let digest = Sha3Digest::digest(&encoded);
+                        (small, encoded, digest)
+                    });
+                    let _ = tx.send(hashed);
+                }
+                Some(Err(())) | None => return,
+            }
+        });
+    }
+
+    work_tx
+}
+
+/// Hand a chunk to the hasher pool. While the pool's queue is full, the returned future waits for
+/// room rather than blocking the thread polling it.
+fn hash_chunk(
+    queue: mpsc::Sender<HashJob>,
+    small: Small,
+) -> impl Future<Item = HashedChunk, Error = Error> {
+    let (tx, rx) = oneshot::channel();
+    queue
+        .send((small, tx))
+        .map_err(|_| format_err!("Chunk hasher pool shut down unexpectedly"))
+        .and_then(|_| {
+            rx.map_err(|_| format_err!("Chunk hasher exited unexpectedly"))
+                .and_then(|hashed| hashed)
+        })
+}
+
+/// The non-empty chunks found by a chunker. Empty chunks carry no data, and cannot be entries of
+/// a large object.
+struct Chunks<C: Chunker>(C);
+
+impl<C: Chunker> Iterator for Chunks<C> {
+    type Item = Result<Small, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut small_builder = SmallBuilder::new();
+            match self.0.find(&mut small_builder) {
+                Ok(Some(_)) if small_builder.is_empty() => continue,
+                Ok(Some(_)) => return Some(Ok(small_builder.into_small())),
+                Ok(None) => return None,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+/// Split a stream into chunks and hash them, yielding the hashed chunks in stream order.
+///
+/// Chunks are read on the polling thread, up to `SHARE_READ_AHEAD` ahead of the oldest chunk not
+/// yet hashed, while the shared hasher pool hashes them in parallel. Reading stays on the polling
+/// thread rather than getting a thread of its own because chunkers may borrow their readers, and
+/// so need be neither `Send` nor `'static`; splitting is cheap next to hashing in any case.
+fn hashed_chunks<C: Chunker>(chunker: C) -> impl Stream<Item = HashedChunk, Error = Error> {
+    let queue = HASHER_QUEUE.lock().unwrap().clone();
+    stream::iter_result(Chunks(chunker))
+        .map(move |small| hash_chunk(queue.clone(), small))
+        .buffered(SHARE_READ_AHEAD)
+}
+
+/// Send the chunks of a byte stream, as found by a given chunker, to the store.
+///
+/// Chunks are hashed in parallel by a pool of threads shared by every share in progress, and
+/// handed to the store along with their canonical encodings so that the store need not hash them
+/// again. Chunks always end up in the same order as they appear in the stream.
+pub fn share_chunked<C: Chunker, B: Backend>(
+    chunker: C,
+    store: Store<B>,
+) -> impl Future<Item = ObjectRef<Handle<B>>, Error = Error> {
+    async_block! {
+        let splitter = chunker.tag();
+
+        let send_store = store.clone();
+        let chunks = hashed_chunks(chunker)
+            .map(move |(small, encoded, digest)| {
+                small
+                    .send_encoded::<_, Sha3Digest>(&send_store, encoded)
+                    .map(move |small_ref| (small_ref, digest))
+            })
+            .buffered(SHARE_SENDS_IN_FLIGHT);
+
+        // Every chunk is fingerprinted by its digest, and every group of entries takes the
+        // fingerprint of its last entry, so group boundaries depend only on the data.
+        let mut level = Vec::new();
+
+        #[async]
+        for (small_ref, digest) in chunks {
+            level.push((ObjectRef::Small(small_ref), fingerprint(&digest)));
         }
 
-        match chunks.len() {
+        match level.len() {
             0 => {
                 // No chunks means the reader was empty.
                 let small_ref = await!(SmallBuilder::new().as_small().send(&store))?;
@@ -1438,32 +2030,107 @@ pub fn share<R: Read, B: Backend>(
             }
             1 => {
                 // If we have a single chunk, no need to put it into a "large" chunk.
-                let small_ref = await!(chunks.into_future()).map_err(|t| t.0)?.0.unwrap();
-                Ok(ObjectRef::Small(small_ref))
+                Ok(level.pop().unwrap().0)
             }
             _ => {
-                // TODO: "deep" hashsplitting. This is why the "depth" of the `LargeBuilder` is `1`.
-                let mut large_builder = LargeBuilder::new(1);
-
-                #[async]
-                for chunk in chunks {
-                    large_builder.push(ObjectRef::Small(chunk));
+                // Group the entries of each level into large objects one level deeper, until only
+                // a single entry is left. Boundaries at depth `d` are found by looking at a
+                // different window of fingerprint bits than at depth `d - 1`, so that entries
+                // ending a group at one level do not all end groups at the next.
+                let mut depth = 1u8;
+                while level.len() > 1 {
+                    let n_entries = level.len();
+                    let shift = LARGE_FANOUT_BITS * u32::from(depth - 1);
+                    let mut next_level = Vec::new();
+                    let mut large_builder = LargeBuilder::new(depth);
+                    large_builder.splitter(splitter.clone());
+
+                    for (i, (objref, fp)) in level.into_iter().enumerate() {
+                        large_builder.push(objref);
+
+                        let at_boundary = shift < 64 && (fp >> shift) & LARGE_FANOUT_MASK == 0;
+                        if at_boundary || large_builder.as_large().len() >= LARGE_MAX_ENTRIES
+                            || i + 1 == n_entries
+                        {
+                            let large_ref = await!(large_builder.as_large().send(&store))?;
+                            next_level.push((ObjectRef::Large(large_ref), fp));
+                            large_builder = LargeBuilder::new(depth);
+                            large_builder.splitter(splitter.clone());
+                        }
+                    }
+
+                    level = next_level;
+                    depth += 1;
                 }
-                let large_ref = await!(large_builder.as_large().send(&store))?;
 
-                Ok(ObjectRef::Large(large_ref))
+                Ok(level.pop().unwrap().0)
             }
         }
     }
 }
 
+/// The number of fingerprint bits examined at each level of a deep large object; on average, a
+/// large object will hold `2^LARGE_FANOUT_BITS` entries.
+const LARGE_FANOUT_BITS: u32 = 8;
+const LARGE_FANOUT_MASK: u64 = (1 << LARGE_FANOUT_BITS) - 1;
+
+/// An upper bound on the number of entries in a single large object, regardless of fingerprints.
+const LARGE_MAX_ENTRIES: usize = 4096;
+
+fn fingerprint<D: Digest>(digest: &D) -> u64 {
+    digest
+        .as_bytes()
+        .iter()
+        .take(8)
+        .fold(0, |acc, &byte| (acc << 8) | u64::from(byte))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use store::dummy::*;
 
+    use std::sync::atomic;
+
     use proptest::prelude::*;
 
+    /// Send `data` to a memory store as a large object of the given depth, with up to `chunk`
+    /// bytes in each small object and up to `fanout` entries in each nested large object.
+    pub fn send_large(
+        store: &Store<MemoryBackend>,
+        data: &[u8],
+        chunk: usize,
+        fanout: usize,
+        depth: u8,
+    ) -> Large<Handle<MemoryBackend>> {
+        let mut level = data.chunks(chunk)
+            .map(|bytes| {
+                let mut small_builder = SmallBuilder::new();
+                small_builder.write_all(bytes).unwrap();
+                ObjectRef::Small(small_builder.into_small().send(store).wait().unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        for child_depth in 1..depth {
+            level = level
+                .chunks(fanout)
+                .map(|entries| {
+                    let mut large_builder = LargeBuilder::new(child_depth);
+                    for objref in entries {
+                        large_builder.push(objref.clone());
+                    }
+                    ObjectRef::Large(large_builder.as_large().send(store).wait().unwrap())
+                })
+                .collect();
+        }
+
+        let mut large_builder = LargeBuilder::new(depth);
+        for objref in level {
+            large_builder.push(objref);
+        }
+        large_builder.into_large()
+    }
+
     prop_compose! {
         fn arb_small()
                 (data in any::<Vec<u8>>()) -> Small {
@@ -1480,8 +2147,12 @@ mod tests {
 
     prop_compose! {
         fn arb_large(store: Store<DummyBackend>)
-                (refs in prop::collection::vec(arb_small_ref(store), 1..1024)) -> Large<Handle<DummyBackend>> {
+                (refs in prop::collection::vec(arb_small_ref(store), 1..1024),
+                 splitter in prop::option::of("[a-z0-9= -]{0,64}")) -> Large<Handle<DummyBackend>> {
             let mut builder = LargeBuilder::new(1);
+            if let Some(tag) = splitter {
+                builder.splitter(tag);
+            }
             for small_ref in refs {
                 builder.push(ObjectRef::Small(small_ref));
             }
@@ -1489,6 +2160,18 @@ mod tests {
         }
     }
 
+    prop_compose! {
+        fn arb_deep_large(store: Store<DummyBackend>)
+                (children in prop::collection::vec(
+                    (1u64..1 << 32, dummy_handle(store)), 1..256)) -> Large<Handle<DummyBackend>> {
+            let mut builder = LargeBuilder::new(2);
+            for (size, inner) in children {
+                builder.push(ObjectRef::Large(LargeRef { inner, size, depth: 1 }));
+            }
+            builder.into_large()
+        }
+    }
+
     prop_compose! {
         fn arb_large_ref(store: Store<DummyBackend>)
                 (size in any::<u64>(), depth in 1u8..255u8, inner in dummy_handle(store)) -> LargeRef<Handle<DummyBackend>> {
@@ -1497,6 +2180,23 @@ mod tests {
         }
     }
 
+    fn arb_metadata(
+        store: Store<DummyBackend>,
+    ) -> BoxedStrategy<Vec<(String, ObjectRef<Handle<DummyBackend>>)>> {
+        prop::collection::vec(
+            (
+                ".*",
+                prop_oneof![
+                    1 => arb_small_ref(store.clone()).prop_map(ObjectRef::Small),
+                    1 => arb_large_ref(store.clone()).prop_map(ObjectRef::Large),
+                    1 => arb_tree_ref(store.clone()).prop_map(ObjectRef::Tree),
+                    1 => arb_commit_ref(store.clone()).prop_map(ObjectRef::Commit)
+                ],
+            ),
+            0..8,
+        ).boxed()
+    }
+
     prop_compose! {
         fn arb_tree(store: Store<DummyBackend>)
                 (entries in
@@ -1507,15 +2207,29 @@ mod tests {
                                1 => arb_small_ref(store.clone()).prop_map(ObjectRef::Small),
                                1 => arb_large_ref(store.clone()).prop_map(ObjectRef::Large),
                                1 => arb_tree_ref(store.clone()).prop_map(ObjectRef::Tree)
-                            ]
+                            ],
+                            prop_oneof![
+                                Just(FileMode::Regular),
+                                Just(FileMode::Executable),
+                                Just(FileMode::Symlink)
+                            ],
+                            prop::option::of(arb_small_ref(store.clone()).prop_map(ObjectRef::Small))
                         ),
                         0..1024,
-                    )
+                    ),
+                 metadata in arb_metadata(store.clone())
                 ) -> Tree<Handle<DummyBackend>> {
             let mut tree_builder = TreeBuilder::new();
-            for (name, handle) in entries {
-                tree_builder.insert(name, handle);
+            for (name, handle, mode, posix) in entries {
+                let is_data = match handle {
+                    ObjectRef::Small(_) | ObjectRef::Large(_) => true,
+                    _ => false,
+                };
+                // Only data entries have modes, so don't give anything else one.
+                let attrs = if is_data { EntryAttrs { mode, posix } } else { EntryAttrs::default() };
+                tree_builder.insert_with_attrs(name, handle, attrs);
             }
+            tree_builder.as_metadata_mut().extend(metadata);
             tree_builder.into_tree()
         }
     }
@@ -1536,25 +2250,39 @@ mod tests {
     }
 
     prop_compose! {
-        /// Worth noting: only testing printable characters here in the metadata because we don't
-        /// have an RDF N-triples parser/formatter which will correctly deal with
-        /// non-printable/non-ASCII characters. TODO: Find or write one.
         fn arb_commit(store: Store<DummyBackend>)
                 (subtree in arb_tree_ref(store.clone()),
                  parents in prop::collection::vec(arb_commit_ref(store.clone()), 0..4),
-                 name in prop::option::of("[ -~]*"),
-                 mbox in prop::option::of("[ -~]*"),
+                 name in prop::option::of(".*"),
+                 mbox in prop::option::of(".*"),
                  timestamp in arb_timestamp(),
-                 message in prop::option::of("[ -~]*")) -> Commit<Handle<DummyBackend>> {
+                 committer in prop::option::of(
+                     (prop::option::of(".*"), prop::option::of(".*"), arb_timestamp())
+                 ),
+                 message in prop::option::of("(?s).*"),
+                 properties in prop::collection::vec(("(?s).*", "(?s).*"), 0..16),
+                 metadata in arb_metadata(store.clone())) -> Commit<Handle<DummyBackend>> {
             let mut builder = CommitBuilder::new();
             builder.subtree(subtree).parents(parents);
             builder.timestamp(timestamp);
             builder.author(CommitAuthor { name, mbox });
 
+            if let Some((name, mbox, timestamp)) = committer {
+                builder.committer(CommitCommitter { name, mbox, timestamp });
+            }
+
             if let Some(msg) = message {
                 builder.message(msg);
             }
 
+            for (key, value) in properties {
+                builder.property(key, value);
+            }
+
+            for (name, objref) in metadata {
+                builder.metadata(name, objref);
+            }
+
             builder.into_commit().unwrap()
         }
     }
@@ -1591,6 +2319,56 @@ mod tests {
              assert_eq!(large, &battered_large);
          }
 
+         #[test]
+         fn roundtrip_deep_large((ref large, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_deep_large(store.clone()), Just(store.clone())))) {
+             let mut builder = store.builder();
+             super::encode::large(&mut builder, large).unwrap();
+             let battered_large =
+                 super::decode::large::<DummyBackend>(DummyContent::new(builder, store.clone()), large.size(), large.depth())
+                     .unwrap();
+             assert_eq!(large, &battered_large);
+         }
+
+         #[test]
+         fn hashed_chunks_preserve_order(ref data in prop::collection::vec(any::<u8>(), 0..1 << 16)) {
+             let parameters = Parameters {
+                 strides_per_window: 64,
+                 log2_modulus: 6,
+                 min_chunk: 16,
+                 max_chunk: 1024,
+                 ..Parameters::default()
+             };
+             let chunks = hashed_chunks(Splitter::new(io::Cursor::new(data.clone()), parameters))
+                 .collect()
+                 .wait()
+                 .unwrap();
+             let rejoined = chunks
+                 .iter()
+                 .flat_map(|&(ref small, _, _)| small.data.iter().cloned())
+                 .collect::<Vec<_>>();
+             assert_eq!(&rejoined, data);
+             for &(ref small, ref encoded, ref digest) in &chunks {
+                 assert_eq!(encoded, &small.canonical::<Sha3Digest>().unwrap());
+                 assert_eq!(digest, &small.digest::<Sha3Digest>().unwrap());
+             }
+         }
+
+         #[test]
+         fn large_range_covers_offset((ref large, offset) in
+                             Just(Store::default()).prop_flat_map(|store| arb_large(store))
+                                .prop_flat_map(|large| {
+                                    let size = large.size();
+                                    (Just(large), 0..size + 1)
+                                })) {
+             let covering = large.range(offset..offset + 1).next();
+             if offset < large.size() {
+                 let (range, _) = covering.unwrap();
+                 assert!(range.start <= offset && offset < range.end);
+             }
+         }
+
          #[test]
          fn roundtrip_tree((ref tree, ref store) in
                              Just(Store::default()).prop_flat_map(|store|
@@ -1603,6 +2381,82 @@ mod tests {
              assert_eq!(tree, &battered_tree);
          }
 
+         #[test]
+         fn roundtrip_untyped((ref object, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (prop_oneof![
+                                    1 => arb_small().prop_map(Object::Small),
+                                    1 => arb_large(store.clone()).prop_map(Object::Large),
+                                    1 => arb_tree(store.clone()).prop_map(Object::Tree),
+                                    1 => arb_commit(store.clone()).prop_map(Object::Commit)
+                                 ], Just(store.clone())))) {
+             let mut builder = store.builder();
+             match *object {
+                 Object::Small(ref small) => super::encode::small(&mut builder, small).unwrap(),
+                 Object::Large(ref large) => super::encode::large(&mut builder, large).unwrap(),
+                 Object::Tree(ref tree) => super::encode::tree(&mut builder, tree).unwrap(),
+                 Object::Commit(ref commit) => super::encode::commit(&mut builder, commit).unwrap(),
+             }
+             let battered_object =
+                 super::decode::object::<DummyBackend>(DummyContent::new(builder, store.clone()))
+                     .unwrap();
+             assert_eq!(object, &battered_object);
+         }
+
+         #[test]
+         fn header_kind_mismatch((ref tree, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_tree(store.clone()), Just(store.clone())))) {
+             let mut builder = store.builder();
+             super::encode::tree(&mut builder, tree).unwrap();
+             assert!(
+                 super::decode::commit::<DummyBackend>(DummyContent::new(builder, store.clone()))
+                     .is_err()
+             );
+         }
+
+         #[test]
+         fn small_objects_are_raw_data((ref small, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_small(), Just(store.clone())))) {
+             // Small objects carry no header, so that chunks keep the digests they had before
+             // type headers were introduced.
+             let mut builder = store.builder();
+             super::encode::small(&mut builder, small).unwrap();
+             let mut encoded = Vec::new();
+             DummyContent::new(builder, store.clone()).read_to_end(&mut encoded).unwrap();
+             assert_eq!(&encoded, &small.data);
+         }
+
+         #[test]
+         fn decode_small_resembling_header((ref small, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_small(), Just(store.clone())))) {
+             // Data which happens to begin with a valid header is still raw data.
+             let mut data = Vec::new();
+             super::encode::header(&mut data, &ObjectHeader::Tree).unwrap();
+             data.extend_from_slice(&small.data);
+             let mut builder = store.builder();
+             builder.write_all(&data).unwrap();
+             let battered_small =
+                 super::decode::small::<DummyBackend>(DummyContent::new(builder, store.clone()))
+                     .unwrap();
+             assert_eq!(&battered_small.data, &data);
+         }
+
+         #[test]
+         fn decode_legacy_tree((ref child, ref store) in
+                             Just(Store::default()).prop_flat_map(|store|
+                                (arb_tree_ref(store.clone()), Just(store.clone())))) {
+             let mut builder = store.builder();
+             builder.push(child.as_inner().clone());
+             builder.write_all(b"10:0 tree foo,\n").unwrap();
+             let battered_tree =
+                 super::decode::tree::<DummyBackend>(DummyContent::new(builder, store.clone()))
+                     .unwrap();
+             assert_eq!(battered_tree.get("foo"), Some(&ObjectRef::Tree(child.clone())));
+         }
+
          #[test]
          fn roundtrip_commit((ref commit, ref store) in
                              Just(Store::default()).prop_flat_map(|store|
@@ -1614,5 +2468,104 @@ mod tests {
                      .unwrap();
              assert_eq!(commit, &battered_commit);
          }
+
+         #[test]
+         fn write_to_rejoins_chunks_in_order(ref data in prop::collection::vec(any::<u8>(), 1..4096),
+                                             chunk in 1usize..64,
+                                             fanout in 2usize..8,
+                                             depth in 1u8..4) {
+             let store = Store::new(MemoryBackend::default());
+             let large = send_large(&store, data, chunk, fanout, depth);
+             let large_ref = large.send(&store).wait().unwrap();
+
+             let written = ObjectRef::Large(large_ref).write_to(Vec::new()).wait().unwrap();
+             assert_eq!(&written, data);
+
+             let leaves = large
+                 .leaves()
+                 .and_then(|small_ref| small_ref.fetch())
+                 .collect()
+                 .wait()
+                 .unwrap();
+             assert_eq!(leaves.len(), (data.len() + chunk - 1) / chunk);
+             let rejoined = leaves.iter().flat_map(|small| small.iter().cloned()).collect::<Vec<_>>();
+             assert_eq!(&rejoined, data);
+         }
+    }
+
+    #[test]
+    fn leaves_fetch_nested_large_objects_as_reached() {
+        let backend = MemoryBackend::default();
+        let loads = backend.loads();
+        let store = Store::new(backend);
+        let data = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let large = send_large(&store, &data, 10, 2, 3);
+
+        // Depth first: only the large objects leading down to the first leaf are fetched for it.
+        let (first, rest) = large.leaves().into_future().wait().map_err(|(err, _)| err).unwrap();
+        assert_eq!(first.unwrap().fetch().wait().unwrap()[..], data[..10]);
+        assert_eq!(loads.load(atomic::Ordering::SeqCst), 3);
+
+        let remaining = rest.collect().wait().unwrap();
+        assert_eq!(remaining.len(), 19);
+    }
+
+    #[test]
+    fn write_to_small_and_non_data() {
+        let store = Store::new(MemoryBackend::default());
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"hello").unwrap();
+        let small_ref = small_builder.into_small().send(&store).wait().unwrap();
+        let written = ObjectRef::Small(small_ref).write_to(Vec::new()).wait().unwrap();
+        assert_eq!(written, b"hello");
+
+        let tree_ref = TreeBuilder::new().as_tree().send(&store).wait().unwrap();
+        assert!(ObjectRef::Tree(tree_ref).write_to(Vec::new()).wait().is_err());
+    }
+
+    #[test]
+    fn replaced_and_removed_entries_leave_no_attributes_behind() {
+        use std::ops::DerefMut;
+
+        fn small(store: &Store<MemoryBackend>, bytes: &[u8]) -> ObjectRef<Handle<MemoryBackend>> {
+            let mut small_builder = SmallBuilder::new();
+            small_builder.write_all(bytes).unwrap();
+            ObjectRef::Small(small_builder.into_small().send(store).wait().unwrap())
+        }
+
+        let store = Store::new(MemoryBackend::default());
+        let data = small(&store, b"data");
+        let attrs = EntryAttrs {
+            mode: FileMode::Executable,
+            posix: Some(small(&store, b"posix")),
+        };
+        let digest = |tree_builder: &TreeBuilder<Handle<MemoryBackend>>| {
+            let tree_ref = tree_builder.as_tree().send(&store).wait().unwrap();
+            ObjectRef::Tree(tree_ref).digest::<Sha3Digest>().wait().unwrap()
+        };
+
+        let mut plain = TreeBuilder::new();
+        plain.insert("x".to_owned(), data.clone());
+
+        // Inserting over an entry replaces its attributes along with it.
+        let mut replaced = TreeBuilder::new();
+        replaced.insert_with_attrs("x".to_owned(), small(&store, b"old"), attrs.clone());
+        replaced.insert("x".to_owned(), data.clone());
+        assert_eq!(replaced.attrs("x"), EntryAttrs::default());
+        assert_eq!(digest(&replaced), digest(&plain));
+
+        // Entries removed behind the builder's back don't leave their metadata in the encoding.
+        let mut removed = plain.clone();
+        removed.insert_with_attrs("y".to_owned(), data, attrs);
+        removed.deref_mut().remove("y");
+        assert_eq!(digest(&removed), digest(&plain));
+    }
+
+    #[test]
+    fn splitter_tags_with_newlines_are_rejected() {
+        let store = Store::new(MemoryBackend::default());
+        let mut large_builder = LargeBuilder::new(1);
+        large_builder.splitter("bad\ntag".to_owned());
+        assert!(large_builder.as_large().send(&store).wait().is_err());
     }
 }
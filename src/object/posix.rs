@@ -0,0 +1,141 @@
+//! POSIX metadata of tree entries: permission bits, ownership, modification time and extended
+//! attributes.
+//!
+//! Each entry's metadata is kept in a small object of its own, attached to the tree containing the
+//! entry. The encoding is line-based text, with extended attribute names and values hex-encoded
+//! since they may be arbitrary bytes:
+//!
+//! ```text
+//! mode <octal permission bits>
+//! uid <uid>
+//! gid <gid>
+//! mtime <seconds> <nanoseconds>
+//! xattr <hex name> <hex value>
+//! ...
+//! ```
+
+use std::{collections::BTreeMap, io::Write, str};
+
+use failure::Error;
+use hex;
+
+use object::{Small, SmallBuilder};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Posix {
+    /// Permission bits, including the setuid, setgid and sticky bits but not the file type.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+
+    /// Modification time, as seconds and nanoseconds since the Unix epoch.
+    pub mtime: (i64, u32),
+
+    pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Posix {
+    pub fn to_small(&self) -> Small {
+        let mut small_builder = SmallBuilder::new();
+
+        // Writing into a `SmallBuilder` never fails.
+        write!(small_builder, "mode {:o}\n", self.mode & 0o7777).unwrap();
+        write!(small_builder, "uid {}\n", self.uid).unwrap();
+        write!(small_builder, "gid {}\n", self.gid).unwrap();
+        write!(small_builder, "mtime {} {}\n", self.mtime.0, self.mtime.1).unwrap();
+        for (name, value) in &self.xattrs {
+            write!(
+                small_builder,
+                "xattr {} {}\n",
+                hex::encode(name),
+                hex::encode(value)
+            ).unwrap();
+        }
+
+        small_builder.into_small()
+    }
+
+    /// Parse the contents of a small object written by `to_small`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let text = str::from_utf8(bytes).map_err(|_| format_err!("POSIX metadata is not UTF-8"))?;
+        let mut lines = text.lines();
+
+        let mode = u32::from_str_radix(single(field(&mut lines, "mode")?, "mode")?, 8)?;
+        let uid = single(field(&mut lines, "uid")?, "uid")?.parse()?;
+        let gid = single(field(&mut lines, "gid")?, "gid")?.parse()?;
+        let mtime = match field(&mut lines, "mtime")? {
+            ref words if words.len() == 2 => (words[0].parse()?, words[1].parse()?),
+            _ => bail!("Bad `mtime` in POSIX metadata"),
+        };
+
+        // Everything else is an extended attribute.
+        let mut xattrs = BTreeMap::new();
+        while let Some(line) = lines.next() {
+            match parse_line(line, "xattr")? {
+                ref words if words.len() == 2 => {
+                    xattrs.insert(hex::decode(words[0])?, hex::decode(words[1])?);
+                }
+                _ => bail!("Bad `xattr` in POSIX metadata"),
+            }
+        }
+
+        Ok(Self {
+            mode,
+            uid,
+            gid,
+            mtime,
+            xattrs,
+        })
+    }
+}
+
+/// Split a line into words, checking that the first is the expected key, and return the rest.
+fn parse_line<'a>(line: &'a str, key: &str) -> Result<Vec<&'a str>, Error> {
+    let mut words = line.split(' ');
+    ensure!(
+        words.next() == Some(key),
+        "Expected `{}` in POSIX metadata, found {:?}",
+        key,
+        line
+    );
+    Ok(words.collect())
+}
+
+fn field<'a>(lines: &mut str::Lines<'a>, key: &str) -> Result<Vec<&'a str>, Error> {
+    let line = lines
+        .next()
+        .ok_or_else(|| format_err!("POSIX metadata is missing `{}`", key))?;
+    parse_line(line, key)
+}
+
+fn single<'a>(words: Vec<&'a str>, key: &str) -> Result<&'a str, Error> {
+    ensure!(words.len() == 1, "Bad `{}` in POSIX metadata", key);
+    Ok(words[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    prop_compose! {
+        fn arb_posix()
+                (mode in 0u32..0o10000,
+                 uid in any::<u32>(),
+                 gid in any::<u32>(),
+                 mtime in (any::<i64>(), 0u32..1_000_000_000),
+                 xattrs in prop::collection::vec((any::<Vec<u8>>(), any::<Vec<u8>>()), 0..8))
+                -> Posix {
+            Posix { mode, uid, gid, mtime, xattrs: xattrs.into_iter().collect() }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_posix(ref posix in arb_posix()) {
+            let small = posix.to_small();
+            prop_assert_eq!(&Posix::from_bytes(&small).unwrap(), posix);
+        }
+    }
+}
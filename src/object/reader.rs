@@ -0,0 +1,256 @@
+use std::{cmp, collections::VecDeque, io::{self, Read, Seek, SeekFrom}, sync::Arc};
+
+use failure::Error;
+use futures::prelude::*;
+
+use object::{Large, ObjectRef, Small};
+use store::prelude::*;
+
+/// The number of fetched objects, small or large, kept around by a `LargeReader`.
+const LARGE_READER_CACHE_SIZE: usize = 16;
+
+enum Cached<B: Backend> {
+    Small(Arc<Small>),
+    Large(Arc<Large<Handle<B>>>),
+}
+
+impl<B: Backend> Clone for Cached<B> {
+    fn clone(&self) -> Self {
+        match *self {
+            Cached::Small(ref small) => Cached::Small(small.clone()),
+            Cached::Large(ref large) => Cached::Large(large.clone()),
+        }
+    }
+}
+
+/// A least-recently-used cache of fetched objects, keyed by their handles. It's small enough that
+/// a linear scan beats anything cleverer.
+struct Lru<B: Backend> {
+    entries: VecDeque<(Handle<B>, Cached<B>)>,
+}
+
+impl<B: Backend> Lru<B> {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::with_capacity(LARGE_READER_CACHE_SIZE),
+        }
+    }
+
+    fn get_or_fetch<F>(&mut self, handle: &Handle<B>, fetch: F) -> Result<Cached<B>, Error>
+    where
+        F: FnOnce() -> Result<Cached<B>, Error>,
+    {
+        if let Some(i) = self.entries.iter().position(|&(ref h, _)| h == handle) {
+            let entry = self.entries.remove(i).unwrap();
+            let cached = entry.1.clone();
+            self.entries.push_front(entry);
+            return Ok(cached);
+        }
+
+        let cached = fetch()?;
+        if self.entries.len() >= LARGE_READER_CACHE_SIZE {
+            self.entries.pop_back();
+        }
+        self.entries.push_front((handle.clone(), cached.clone()));
+        Ok(cached)
+    }
+}
+
+/// A seekable reader over the contents of a large object, of any depth.
+///
+/// Only the chunks covering the bytes actually read are fetched, along with the large objects on
+/// the path down to them; the most recently used of these are cached. Reads block on fetches from
+/// the store, so a `LargeReader` must not be used from within a future driven by the same
+/// executor as the store's.
+pub struct LargeReader<B: Backend> {
+    root: Arc<Large<Handle<B>>>,
+    position: u64,
+    cache: Lru<B>,
+}
+
+impl<B: Backend> LargeReader<B> {
+    pub fn new(large: Large<Handle<B>>) -> Self {
+        Self {
+            root: Arc::new(large),
+            position: 0,
+            cache: Lru::new(),
+        }
+    }
+
+    /// The total size of the large object's contents.
+    pub fn size(&self) -> u64 {
+        self.root.size()
+    }
+
+    /// Find the chunk covering a given offset, along with the offset at which it starts.
+    fn leaf(&mut self, position: u64) -> Result<(u64, Arc<Small>), Error> {
+        let mut large = self.root.clone();
+        let mut base = 0;
+
+        loop {
+            let offset = position - base;
+            let (range, objref) = large
+                .range(offset..offset + 1)
+                .next()
+                .map(|(range, objref)| (range, objref.clone()))
+                .ok_or_else(|| format_err!("No entry of large object covers offset {}", offset))?;
+
+            match objref {
+                ObjectRef::Small(small_ref) => {
+                    let cached = self.cache.get_or_fetch(small_ref.as_inner(), || {
+                        Ok(Cached::Small(Arc::new(small_ref.fetch().wait()?)))
+                    })?;
+
+                    match cached {
+                        Cached::Small(small) => return Ok((base + range.start, small)),
+                        Cached::Large(_) => unreachable!("handles identify a single object"),
+                    }
+                }
+                ObjectRef::Large(large_ref) => {
+                    let cached = self.cache.get_or_fetch(large_ref.as_inner(), || {
+                        Ok(Cached::Large(Arc::new(large_ref.fetch().wait()?)))
+                    })?;
+
+                    match cached {
+                        Cached::Large(child) => large = child,
+                        Cached::Small(_) => unreachable!("handles identify a single object"),
+                    }
+                    base += range.start;
+                }
+                _ => bail!("Large object has an entry which is not data!"),
+            }
+        }
+    }
+}
+
+impl<B: Backend> Read for LargeReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.root.size() {
+            return Ok(0);
+        }
+
+        let (start, small) = self.leaf(self.position)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.compat()))?;
+        let offset = (self.position - start) as usize;
+        let n = cmp::min(buf.len(), small.len() - offset);
+        buf[..n].copy_from_slice(&small[offset..offset + n]);
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<B: Backend> Seek for LargeReader<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(delta) => offset(self.root.size(), delta),
+            SeekFrom::Current(delta) => offset(self.position, delta),
+        };
+
+        match new_position {
+            // As with files, seeking past the end is fine; reads from there are just empty.
+            Some(n) => {
+                self.position = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+
+    use object::tests::send_large;
+    use store::dummy::MemoryBackend;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn reads_across_chunk_boundaries() {
+        let store = Store::new(MemoryBackend::default());
+        let data = data(1000);
+        let mut reader = LargeReader::new(send_large(&store, &data, 7, 5, 3));
+        assert_eq!(reader.size(), 1000);
+
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+
+        // Spans within one chunk, across several chunks and across nested large objects.
+        for &(start, len) in &[(0, 3), (5, 4), (6, 30), (33, 140), (174, 1), (990, 10)] {
+            let mut buf = vec![0; len];
+            reader.seek(SeekFrom::Start(start as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[start..start + len]);
+        }
+    }
+
+    #[test]
+    fn seeks_like_a_file() {
+        let store = Store::new(MemoryBackend::default());
+        let data = data(100);
+        let mut reader = LargeReader::new(send_large(&store, &data, 8, 4, 2));
+        let mut byte = [0];
+
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 90);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], data[90]);
+
+        assert_eq!(reader.seek(SeekFrom::Current(-41)).unwrap(), 50);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], data[50]);
+
+        // Seeking past the end is fine, but there's nothing to read there; seeking before the
+        // start is an error.
+        assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), 105);
+        assert_eq!(reader.read(&mut byte).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-106)).is_err());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_chunks() {
+        // A single level of 4-byte chunks, so that every chunk is one fetch.
+        let backend = MemoryBackend::default();
+        let loads = backend.loads();
+        let store = Store::new(backend);
+        let chunks = LARGE_READER_CACHE_SIZE + 4;
+        let mut reader = LargeReader::new(send_large(&store, &data(4 * chunks), 4, chunks, 1));
+        let mut buf = [0; 4];
+
+        let mut read_chunk = |reader: &mut LargeReader<MemoryBackend>, i: usize| {
+            reader.seek(SeekFrom::Start(4 * i as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            loads.load(Ordering::SeqCst)
+        };
+
+        // Fill the cache; reading a cached chunk fetches nothing.
+        for i in 0..LARGE_READER_CACHE_SIZE {
+            assert_eq!(read_chunk(&mut reader, i), i + 1);
+        }
+        assert_eq!(read_chunk(&mut reader, 0), LARGE_READER_CACHE_SIZE);
+
+        // Chunk 0 was just used, so chunk 1 is the one evicted to make room.
+        let n = LARGE_READER_CACHE_SIZE;
+        assert_eq!(read_chunk(&mut reader, n), n + 1);
+        assert_eq!(read_chunk(&mut reader, 0), n + 1);
+        assert_eq!(read_chunk(&mut reader, 1), n + 2);
+    }
+}
@@ -1,7 +1,25 @@
-use std::{io::{Read, Write}, ops::Range};
+use std::{cmp, usize, io::{Read, Write}, ops::{Deref, Range}};
 
 use failure::Error;
+use memchr;
 
+/// A content-defined chunker over a byte stream.
+pub trait Chunker {
+    /// Write all bytes up to the next chunk boundary into the sink, returning the range of the
+    /// chunk within the stream, or `None` once the stream is exhausted. Implementations may yield
+    /// empty chunks, which carry no data and may be skipped.
+    fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error>;
+
+    /// A one-line description of this chunker and its parameters. Two chunkers with the same tag
+    /// must always split the same stream at the same boundaries.
+    fn tag(&self) -> String;
+}
+
+/// Parameters for the rolling-sum splitter.
+///
+/// No chunk is split off before it is `min_chunk` bytes long, and every chunk is split off once
+/// it reaches `max_chunk` bytes, regardless of its contents; only the final chunk of a stream may
+/// be shorter than `min_chunk`. `max_chunk` must be a nonzero multiple of `stride`.
 #[derive(Debug, Clone, Copy)]
 pub struct Parameters {
     pub stride: usize,
@@ -9,6 +27,62 @@ pub struct Parameters {
 
     pub split_marker: u64,
     pub log2_modulus: u32,
+
+    pub min_chunk: usize,
+    pub max_chunk: usize,
+}
+
+impl Parameters {
+    /// Check that these parameters describe a splitter which can actually run. Parameters read
+    /// from a repository's configuration should be checked with this before they are used, since
+    /// the splitters themselves panic on invalid parameters.
+    pub fn validate(&self) -> Result<(), Error> {
+        ensure!(
+            self.stride > 0 && self.max_chunk > 0 && self.max_chunk % self.stride == 0,
+            "The maximum chunk size must be a nonzero multiple of the stride ({})",
+            self.stride
+        );
+        ensure!(
+            self.min_chunk <= self.max_chunk,
+            "The minimum chunk size may not exceed the maximum chunk size"
+        );
+        ensure!(
+            self.log2_modulus < 64,
+            "The log2 modulus must be less than 64"
+        );
+
+        Ok(())
+    }
+
+    fn assert_valid(&self) {
+        if let Err(error) = self.validate() {
+            panic!("Invalid splitter parameters: {}", error);
+        }
+    }
+
+    /// The parameters every repository split with before chunk sizes could be bounded: the
+    /// defaults, but with no minimum and no maximum to speak of. Splitting with these finds the
+    /// same chunks as before, so that data staged again still deduplicates against the old.
+    pub fn legacy() -> Self {
+        Self {
+            min_chunk: 0,
+            max_chunk: usize::MAX,
+            ..Self::default()
+        }
+    }
+
+    /// A one-line description of these parameters, as recorded with large objects.
+    pub fn tag(&self) -> String {
+        format!(
+            "rollsum stride={} window={} marker={} log2-modulus={} min={} max={}",
+            self.stride,
+            self.strides_per_window,
+            self.split_marker,
+            self.log2_modulus,
+            self.min_chunk,
+            self.max_chunk
+        )
+    }
 }
 
 impl Default for Parameters {
@@ -19,6 +93,9 @@ impl Default for Parameters {
 
             split_marker: 1,
             log2_modulus: 14,
+
+            min_chunk: 8192,
+            max_chunk: 1 << 22,
         }
     }
 }
@@ -35,6 +112,8 @@ struct State<R: Read> {
 
 impl<R: Read> State<R> {
     fn new(source: R, parameters: Parameters) -> Self {
+        parameters.assert_valid();
+
         Self {
             parameters,
             buffer: vec![0; parameters.stride * (parameters.strides_per_window + 1)]
@@ -117,7 +196,10 @@ impl<R: Read> State<R> {
             // Truncate the accumulator, treating it as modulo some power of two.
             acc &= (1 << p.log2_modulus) - 1;
 
-            if chunk_strides >= p.strides_per_window && acc == p.split_marker {
+            let chunk_bytes = chunk_strides * p.stride;
+            let at_marker = chunk_strides >= p.strides_per_window && acc == p.split_marker;
+
+            if chunk_bytes >= p.max_chunk || (chunk_bytes >= p.min_chunk && at_marker) {
                 let pre_chunk_bytes = self.total * p.stride;
                 let post_chunk_bytes = pre_chunk_bytes + chunk_strides * p.stride;
 
@@ -133,13 +215,17 @@ impl<R: Read> State<R> {
 
 /// A hashsplitter over a given bytestream.
 pub struct Splitter<R: Read> {
+    parameters: Parameters,
     inner: Option<State<R>>,
 }
 
 impl<R: Read> Splitter<R> {
     /// Create a new splitter with a given byte stream and parameters.
+    ///
+    /// Panics if the parameters are invalid; see `Parameters::validate`.
     pub fn new(reader: R, params: Parameters) -> Self {
         Self {
+            parameters: params,
             inner: Some(State::new(reader, params)),
         }
     }
@@ -157,10 +243,513 @@ impl<R: Read> Splitter<R> {
     }
 }
 
+impl<R: Read> Chunker for Splitter<R> {
+    fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        Splitter::find(self, sink)
+    }
+
+    fn tag(&self) -> String {
+        self.parameters.tag()
+    }
+}
+
+/// Why the rolling-sum splitter ended a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cut {
+    /// The rolling sum hit the split marker.
+    Marker,
+    /// The chunk reached the maximum chunk size.
+    Limit,
+    /// The data ran out.
+    End,
+}
+
+/// The rolling sum over the chunk at the start of some data, taken a stride at a time. This
+/// mirrors `State::find` step for step: the rolling sum is reset at the start of each chunk, and
+/// bytes only leave it once a full window of the chunk has been read.
+#[derive(Debug, Default)]
+struct Rollsum {
+    chunk_strides: usize,
+    acc: u64,
+}
+
+impl Rollsum {
+    /// The number of bytes of the chunk needed for the next step, unless the data ends sooner.
+    fn next_len(&self, p: &Parameters) -> usize {
+        (self.chunk_strides + 1) * p.stride
+    }
+
+    /// Take the next stride of the chunk at the start of `data` into the sum, returning the length
+    /// of the chunk and why it ends there if it ends with this stride. `data` must hold at least
+    /// `next_len` bytes, or else everything left of the stream.
+    fn step(&mut self, p: &Parameters, data: &[u8]) -> Option<(usize, Cut)> {
+        let start = self.chunk_strides * p.stride;
+        let new = &data[start..cmp::min(start + p.stride, data.len())];
+
+        for &b in new {
+            self.acc = self.acc.wrapping_add(b as u64);
+        }
+
+        if self.chunk_strides >= p.strides_per_window {
+            let old_start = (self.chunk_strides - p.strides_per_window) * p.stride;
+            for &b in &data[old_start..old_start + p.stride] {
+                self.acc = self.acc.wrapping_sub(b as u64);
+            }
+        }
+
+        if new.len() < p.stride {
+            return Some((start + new.len(), Cut::End));
+        }
+
+        self.chunk_strides += 1;
+        self.acc &= (1 << p.log2_modulus) - 1;
+
+        let chunk_bytes = self.chunk_strides * p.stride;
+        let at_marker = self.chunk_strides >= p.strides_per_window && self.acc == p.split_marker;
+
+        if chunk_bytes >= p.max_chunk {
+            Some((chunk_bytes, Cut::Limit))
+        } else if chunk_bytes >= p.min_chunk && at_marker {
+            Some((chunk_bytes, Cut::Marker))
+        } else {
+            None
+        }
+    }
+}
+
+/// Find the length of the chunk at the start of `data`, and why it ends there.
+fn rollsum_cut(p: &Parameters, data: &[u8]) -> (usize, Cut) {
+    let mut rollsum = Rollsum::default();
+
+    loop {
+        if let Some(cut) = rollsum.step(p, data) {
+            return cut;
+        }
+    }
+}
+
+/// A hashsplitter over a byte slice held entirely in memory, such as a memory-mapped file.
+///
+/// Boundaries are found directly in the slice rather than by copying it stride by stride through
+/// a ring buffer, and each chunk is written to the sink in a single copy. The chunks found are
+/// always exactly those a `Splitter` with the same parameters would find over the same bytes.
+pub struct SliceSplitter<T: Deref<Target = [u8]>> {
+    parameters: Parameters,
+    data: T,
+    offset: usize,
+    done: bool,
+}
+
+impl<T: Deref<Target = [u8]>> SliceSplitter<T> {
+    /// Create a new splitter over a given byte slice and parameters.
+    ///
+    /// Panics if the parameters are invalid; see `Parameters::validate`.
+    pub fn new(data: T, parameters: Parameters) -> Self {
+        parameters.assert_valid();
+
+        Self {
+            parameters,
+            data,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Find the next split marker in the byte slice.
+    pub fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let (len, cut) = rollsum_cut(&self.parameters, &self.data[self.offset..]);
+        sink.write_all(&self.data[self.offset..self.offset + len])?;
+
+        let chunk = self.offset..self.offset + len;
+        self.offset += len;
+        self.done = cut == Cut::End;
+
+        Ok(Some(chunk))
+    }
+}
+
+impl<T: Deref<Target = [u8]>> Chunker for SliceSplitter<T> {
+    fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        SliceSplitter::find(self, sink)
+    }
+
+    fn tag(&self) -> String {
+        self.parameters.tag()
+    }
+}
+
+/// How much more input to read at once when a chunker runs out of lookahead.
+const LOOKAHEAD_BLOCK: usize = 1 << 16;
+
+/// Input read ahead of the chunk boundary currently being sought. The bytes before `start` have
+/// already been emitted; their space is only reclaimed once it makes up half of the buffer, so
+/// that each byte is moved at most once no matter how small the chunks are.
+struct Lookahead<R: Read> {
+    source: R,
+    buffer: Vec<u8>,
+    start: usize,
+    eof: bool,
+}
+
+impl<R: Read> Lookahead<R> {
+    fn new(source: R) -> Self {
+        Self {
+            source,
+            buffer: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    /// Read until at least `len` bytes are buffered, or the stream runs out.
+    fn fill_to(&mut self, len: usize) -> Result<(), Error> {
+        while !self.eof && self.data().len() < len {
+            if self.start > 0 && self.start >= self.buffer.len() / 2 {
+                self.buffer.drain(..self.start);
+                self.start = 0;
+            }
+
+            let filled = self.buffer.len();
+            let wanted = cmp::max(len - (filled - self.start), LOOKAHEAD_BLOCK);
+            self.buffer.resize(filled + wanted, 0);
+            let n = self.source.read(&mut self.buffer[filled..])?;
+            self.buffer.truncate(filled + n);
+            self.eof = n == 0;
+        }
+
+        Ok(())
+    }
+
+    /// The bytes read but not yet consumed.
+    fn data(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.eof && self.data().is_empty()
+    }
+}
+
+/// A splitter for line- or record-oriented data, which only ever cuts just after a delimiter.
+///
+/// The rolling-sum condition of the regular splitter decides *when* a chunk may end; the chunk
+/// then runs on to the end of the next record, i.e. just past the next `delimiter` byte. This
+/// keeps whole records together, so inserting or changing one record changes only the chunk
+/// containing it. A chunk which would run past `max_chunk` bytes ends after the last record
+/// which fits instead, and a chunk without any delimiter at all is cut at `max_chunk` bytes.
+pub struct RecordSplitter<R: Read> {
+    parameters: Parameters,
+    delimiter: u8,
+
+    lookahead: Lookahead<R>,
+    done: bool,
+
+    // Total bytes emitted so far.
+    offset: usize,
+}
+
+impl<R: Read> RecordSplitter<R> {
+    /// Create a new record splitter with a given byte stream, parameters and record delimiter.
+    ///
+    /// Panics if the parameters are invalid; see `Parameters::validate`.
+    pub fn new(source: R, parameters: Parameters, delimiter: u8) -> Self {
+        parameters.assert_valid();
+
+        Self {
+            parameters,
+            delimiter,
+
+            lookahead: Lookahead::new(source),
+            done: false,
+
+            offset: 0,
+        }
+    }
+
+    /// The length of a chunk which may not run past `limit` bytes: everything up to the last
+    /// delimiter before the limit, if there is one.
+    fn last_record(&self, limit: usize) -> usize {
+        match memchr::memrchr(self.delimiter, &self.lookahead.data()[..limit]) {
+            Some(i) => i + 1,
+            None => limit,
+        }
+    }
+
+    /// Find the end of the record in which the split marker at `marker` lies.
+    fn next_record(&mut self, marker: usize) -> Result<usize, Error> {
+        let max_chunk = self.parameters.max_chunk;
+        let mut searched = marker;
+
+        loop {
+            let available = cmp::min(self.lookahead.data().len(), max_chunk);
+            let found = memchr::memchr(self.delimiter, &self.lookahead.data()[searched..available]);
+            if let Some(i) = found {
+                return Ok(searched + i + 1);
+            } else if available == max_chunk {
+                return Ok(self.last_record(max_chunk));
+            } else if self.lookahead.eof {
+                return Ok(available);
+            }
+
+            searched = available;
+            self.lookahead.fill_to(available + LOOKAHEAD_BLOCK)?;
+        }
+    }
+
+    /// Find the next record boundary in the byte stream after a split marker.
+    pub fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let p = self.parameters;
+        let mut rollsum = Rollsum::default();
+        let cut = loop {
+            self.lookahead.fill_to(rollsum.next_len(&p))?;
+            if let Some(cut) = rollsum.step(&p, self.lookahead.data()) {
+                break cut;
+            }
+        };
+
+        let len = match cut {
+            (marker, Cut::Marker) => self.next_record(marker)?,
+            (limit, Cut::Limit) => self.last_record(limit),
+            (len, Cut::End) => len,
+        };
+        sink.write_all(&self.lookahead.data()[..len])?;
+        self.lookahead.consume(len);
+        self.done = cut.1 == Cut::End || self.lookahead.is_exhausted();
+
+        let chunk = self.offset..self.offset + len;
+        self.offset += len;
+
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: Read> Chunker for RecordSplitter<R> {
+    fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        RecordSplitter::find(self, sink)
+    }
+
+    fn tag(&self) -> String {
+        format!("records delimiter={} {}", self.delimiter, self.parameters.tag())
+    }
+}
+
+/// Parameters for the Gear chunker.
+///
+/// Chunk boundaries are never placed before `min_size` or after `max_size` bytes. Between the
+/// minimum size and `avg_size`, a stricter mask is used to find boundaries; past it, a looser
+/// one. The further apart the two masks are (`normalization` bits either side of the average),
+/// the more tightly chunk sizes cluster around the average.
+#[derive(Debug, Clone, Copy)]
+pub struct GearParameters {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+
+    pub normalization: u32,
+}
+
+impl Default for GearParameters {
+    fn default() -> Self {
+        Self {
+            min_size: 2048,
+            avg_size: 8192,
+            max_size: 65536,
+
+            normalization: 2,
+        }
+    }
+}
+
+/// Generate the table of pseudorandom values which bytes are mapped to by the Gear hash, using
+/// SplitMix64 from a fixed seed so that chunk boundaries are stable across builds.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x6174_7461_6361_6765u64;
+
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *entry = z ^ (z >> 31);
+    }
+
+    table
+}
+
+/// A mask selecting the `bits` highest bits of a Gear hash, which depend on the most input.
+fn gear_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else if bits >= 64 {
+        !0
+    } else {
+        !0 << (64 - bits)
+    }
+}
+
+/// A FastCDC-style content-defined chunker, using a Gear rolling hash with normalized chunking.
+pub struct GearChunker<R: Read> {
+    parameters: GearParameters,
+    table: [u64; 256],
+    mask_small: u64,
+    mask_large: u64,
+
+    lookahead: Lookahead<R>,
+
+    // Total bytes emitted so far.
+    offset: usize,
+}
+
+impl<R: Read> GearChunker<R> {
+    /// Create a new chunker over a given byte stream.
+    pub fn new(source: R, parameters: GearParameters) -> Result<Self, Error> {
+        ensure!(
+            parameters.avg_size.is_power_of_two(),
+            "Gear chunker average size must be a power of two"
+        );
+        ensure!(
+            0 < parameters.min_size && parameters.min_size <= parameters.avg_size
+                && parameters.avg_size <= parameters.max_size,
+            "Gear chunker sizes must satisfy 0 < min <= avg <= max"
+        );
+
+        let bits = parameters.avg_size.trailing_zeros();
+        ensure!(
+            parameters.normalization <= bits,
+            "Gear chunker normalization may not exceed log2 of the average size"
+        );
+
+        Ok(Self {
+            parameters,
+            table: gear_table(),
+            mask_small: gear_mask(bits + parameters.normalization),
+            mask_large: gear_mask(bits - parameters.normalization),
+
+            lookahead: Lookahead::new(source),
+
+            offset: 0,
+        })
+    }
+
+    /// Find the length of the first chunk in `data`, which is at most `max_size` bytes long.
+    fn cut(&self, data: &[u8]) -> usize {
+        let p = self.parameters;
+
+        if data.len() <= p.min_size {
+            return data.len();
+        }
+
+        let normal = if data.len() < p.avg_size {
+            data.len()
+        } else {
+            p.avg_size
+        };
+
+        // The first `min_size` bytes can never hold a boundary, so they are skipped outright.
+        let mut hash = 0u64;
+        for (i, &b) in data[p.min_size..normal].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(self.table[b as usize]);
+            if hash & self.mask_small == 0 {
+                return p.min_size + i + 1;
+            }
+        }
+
+        for (i, &b) in data[normal..].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(self.table[b as usize]);
+            if hash & self.mask_large == 0 {
+                return normal + i + 1;
+            }
+        }
+
+        data.len()
+    }
+}
+
+impl<R: Read> Chunker for GearChunker<R> {
+    fn find<W: Write>(&mut self, sink: &mut W) -> Result<Option<Range<usize>>, Error> {
+        let max_size = self.parameters.max_size;
+        self.lookahead.fill_to(max_size)?;
+
+        if self.lookahead.data().is_empty() {
+            return Ok(None);
+        }
+
+        let len = {
+            let data = self.lookahead.data();
+            self.cut(&data[..cmp::min(data.len(), max_size)])
+        };
+        sink.write_all(&self.lookahead.data()[..len])?;
+        self.lookahead.consume(len);
+
+        let chunk = self.offset..self.offset + len;
+        self.offset += len;
+
+        Ok(Some(chunk))
+    }
+
+    fn tag(&self) -> String {
+        let p = &self.parameters;
+        format!(
+            "gear min={} avg={} max={} normalization={}",
+            p.min_size, p.avg_size, p.max_size, p.normalization
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::collections::HashSet;
+
+    /// Deterministic pseudorandom test data (xorshift64*.)
+    fn pseudorandom(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state >> 12;
+                state ^= state << 25;
+                state ^= state >> 27;
+                (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunks<C: Chunker>(mut chunker: C) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        while let Some(_) = chunker.find(&mut chunk).unwrap() {
+            if !chunk.is_empty() {
+                chunks.push(chunk.split_off(0));
+            }
+        }
+        chunks
+    }
+
+    /// The fraction of bytes of `modified` which lie in chunks already present in `original`.
+    fn dedup_ratio(original: &[Vec<u8>], modified: &[Vec<u8>]) -> f64 {
+        let known = original.iter().map(Vec::as_slice).collect::<HashSet<_>>();
+        let total = modified.iter().map(Vec::len).sum::<usize>();
+        let reused = modified
+            .iter()
+            .filter(|chunk| known.contains(chunk.as_slice()))
+            .map(Vec::len)
+            .sum::<usize>();
+        reused as f64 / total as f64
+    }
+
     #[test]
     fn split_1() {
         let data = vec![
@@ -176,6 +765,9 @@ mod tests {
 
                 log2_modulus: 8,
                 split_marker: 255,
+
+                min_chunk: 0,
+                max_chunk: 32,
             },
         );
 
@@ -207,6 +799,9 @@ mod tests {
 
                 log2_modulus: 8,
                 split_marker: 255,
+
+                min_chunk: 0,
+                max_chunk: 32,
             },
         );
 
@@ -220,4 +815,283 @@ mod tests {
 
         assert_eq!(data, split);
     }
+
+    #[test]
+    fn split_min_chunk() {
+        let data = vec![
+            0, 1, 2, 3, 255, 5, 6, 7, 255, 255, 255, 11, 12, 13, 14, 15, 16, 17, 18, 19, 255, 21,
+            22, 23, 24, 25, 26, 255, 28, 29, 30, 31,
+        ];
+        let mut data_slice = data.as_slice();
+        let mut splitter = Splitter::new(
+            &mut data_slice,
+            Parameters {
+                stride: 1,
+                strides_per_window: 1,
+
+                log2_modulus: 8,
+                split_marker: 255,
+
+                min_chunk: 6,
+                max_chunk: 32,
+            },
+        );
+
+        let mut split = Vec::new();
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(0..9));
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(9..21));
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(21..28));
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(28..32));
+        assert_eq!(splitter.find(&mut split).unwrap(), None);
+
+        assert_eq!(data, split);
+    }
+
+    #[test]
+    fn split_max_chunk() {
+        // A run of zeros never hits the split marker, so only the maximum bounds the chunks.
+        let data = vec![0; 10000];
+        let mut data_slice = data.as_slice();
+        let mut splitter = Splitter::new(
+            &mut data_slice,
+            Parameters {
+                stride: 4,
+                min_chunk: 256,
+                max_chunk: 1024,
+                ..Parameters::default()
+            },
+        );
+
+        let mut split = Vec::new();
+        for i in 0..9 {
+            assert_eq!(
+                splitter.find(&mut split).unwrap(),
+                Some(i * 1024..(i + 1) * 1024)
+            );
+        }
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(9216..10000));
+        assert_eq!(splitter.find(&mut split).unwrap(), None);
+
+        assert_eq!(data, split);
+    }
+
+    #[test]
+    fn split_legacy_unbounded() {
+        // Without bounds, a run of zeros never hits the split marker and is never split.
+        let data = vec![0; 100000];
+        let mut splitter = Splitter::new(data.as_slice(), Parameters::legacy());
+
+        let mut split = Vec::new();
+        assert_eq!(splitter.find(&mut split).unwrap(), Some(0..100000));
+        assert_eq!(splitter.find(&mut split).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        assert!(Parameters::default().validate().is_ok());
+        assert!(Parameters::legacy().validate().is_ok());
+
+        let inverted = Parameters {
+            min_chunk: 1 << 16,
+            max_chunk: 1 << 12,
+            ..Parameters::default()
+        };
+        assert!(inverted.validate().is_err());
+
+        let unaligned = Parameters {
+            stride: 4,
+            min_chunk: 0,
+            max_chunk: 4097,
+            ..Parameters::default()
+        };
+        assert!(unaligned.validate().is_err());
+
+        let zero_max = Parameters {
+            min_chunk: 0,
+            max_chunk: 0,
+            ..Parameters::default()
+        };
+        assert!(zero_max.validate().is_err());
+    }
+
+    #[test]
+    fn slice_splitter_matches_splitter() {
+        let data = pseudorandom(1 << 18, 3);
+        let parameter_sets = [
+            Parameters::default(),
+            Parameters {
+                stride: 1,
+                strides_per_window: 64,
+                log2_modulus: 6,
+                min_chunk: 128,
+                max_chunk: 4096,
+                ..Parameters::default()
+            },
+            Parameters {
+                stride: 3,
+                strides_per_window: 17,
+                log2_modulus: 5,
+                min_chunk: 0,
+                max_chunk: 3 * 512,
+                ..Parameters::default()
+            },
+        ];
+
+        // Cover lengths which do and do not end on a stride.
+        for &len in &[0, 1, 1000, 4096, 65537, data.len()] {
+            for &params in &parameter_sets {
+                let streamed = chunks(Splitter::new(&data[..len], params));
+                let sliced = chunks(SliceSplitter::new(&data[..len], params));
+                assert_eq!(streamed, sliced);
+            }
+        }
+    }
+
+    #[test]
+    fn records_cut_at_delimiters() {
+        // Lines of pseudorandom printable bytes of varying lengths.
+        let mut data = Vec::new();
+        for (i, chunk) in pseudorandom(1 << 18, 4).chunks(97).enumerate() {
+            data.extend(chunk.iter().take((i * 31) % 97).map(|b| b'a' + b % 26));
+            data.push(b'\n');
+        }
+
+        let params = Parameters {
+            strides_per_window: 64,
+            log2_modulus: 8,
+            min_chunk: 256,
+            max_chunk: 1 << 16,
+            ..Parameters::default()
+        };
+        let chunks = chunks(RecordSplitter::new(data.as_slice(), params, b'\n'));
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert_eq!(chunk.last(), Some(&b'\n'));
+            assert!(chunk.len() <= params.max_chunk);
+        }
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn records_fall_back_to_the_last_delimiter() {
+        let mut data = Vec::new();
+        for (i, chunk) in pseudorandom(1 << 16, 5).chunks(97).enumerate() {
+            data.extend(chunk.iter().take((i * 31) % 97).map(|b| b'a' + b % 26));
+            data.push(b'\n');
+        }
+
+        // The split marker is out of the rolling sum's range, so every chunk runs into the limit.
+        let params = Parameters {
+            log2_modulus: 8,
+            split_marker: 1 << 8,
+            min_chunk: 0,
+            max_chunk: 1000,
+            ..Parameters::default()
+        };
+        let chunks = chunks(RecordSplitter::new(data.as_slice(), params, b'\n'));
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert_eq!(chunk.last(), Some(&b'\n'));
+            assert!(chunk.len() <= params.max_chunk);
+        }
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn inserting_a_record_changes_one_chunk() {
+        let records = pseudorandom(1 << 18, 6)
+            .chunks(61)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut record = chunk
+                    .iter()
+                    .take((i * 17) % 61)
+                    .map(|b| b'a' + b % 26)
+                    .collect::<Vec<_>>();
+                record.push(b'\n');
+                record
+            })
+            .collect::<Vec<_>>();
+
+        let mut inserted = records.clone();
+        inserted.insert(records.len() / 2, b"a freshly inserted row\n".to_vec());
+
+        let params = Parameters {
+            strides_per_window: 64,
+            log2_modulus: 8,
+            min_chunk: 256,
+            max_chunk: 1 << 16,
+            ..Parameters::default()
+        };
+        let original = chunks(RecordSplitter::new(records.concat().as_slice(), params, b'\n'));
+        let modified = chunks(RecordSplitter::new(inserted.concat().as_slice(), params, b'\n'));
+
+        let original_set = original.iter().collect::<HashSet<_>>();
+        let modified_set = modified.iter().collect::<HashSet<_>>();
+        assert!(original.len() > 16);
+        assert_eq!(original_set.difference(&modified_set).count(), 1);
+        assert_eq!(modified_set.difference(&original_set).count(), 1);
+    }
+
+    #[test]
+    fn records_without_delimiters_respect_max_chunk() {
+        let data = vec![b'x'; 10000];
+        let params = Parameters {
+            stride: 4,
+            min_chunk: 256,
+            max_chunk: 1024,
+            ..Parameters::default()
+        };
+        let chunks = chunks(RecordSplitter::new(data.as_slice(), params, b'\n'));
+
+        assert_eq!(chunks.len(), 10);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= params.max_chunk));
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn gear_respects_bounds() {
+        let data = pseudorandom(1 << 20, 1);
+        let params = GearParameters::default();
+        let chunks = chunks(GearChunker::new(data.as_slice(), params).unwrap());
+
+        let (last, init) = chunks.split_last().unwrap();
+        assert!(last.len() <= params.max_size);
+        for chunk in init {
+            assert!(params.min_size <= chunk.len() && chunk.len() <= params.max_size);
+        }
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn gear_dedups_shifted_inserts() {
+        let original = pseudorandom(1 << 21, 2);
+
+        // Insert a few short runs of bytes at scattered offsets, shifting everything after them.
+        let mut modified = original.clone();
+        for &(offset, len) in &[(1 << 20, 7), (1 << 19, 3), (12345, 1)] {
+            let tail = modified.split_off(offset);
+            modified.extend(pseudorandom(len, offset as u64));
+            modified.extend(tail);
+        }
+
+        let gear_ratio = dedup_ratio(
+            &chunks(GearChunker::new(original.as_slice(), GearParameters::default()).unwrap()),
+            &chunks(GearChunker::new(modified.as_slice(), GearParameters::default()).unwrap()),
+        );
+        let splitter_ratio = dedup_ratio(
+            &chunks(Splitter::new(original.as_slice(), Parameters::default())),
+            &chunks(Splitter::new(modified.as_slice(), Parameters::default())),
+        );
+
+        assert!(gear_ratio > 0.9, "gear dedup ratio {}", gear_ratio);
+        assert!(
+            gear_ratio >= splitter_ratio,
+            "gear dedup ratio {} worse than splitter's {}",
+            gear_ratio,
+            splitter_ratio
+        );
+    }
 }
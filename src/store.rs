@@ -1,4 +1,4 @@
-use std::{fmt, iter, any::Any, borrow::Borrow, cmp::Ordering, collections::HashMap,
+use std::{fmt, iter, mem, any::Any, borrow::Borrow, cmp::Ordering, collections::HashMap,
           hash::{Hash, Hasher}, io::{self, Read, Write}, sync::Arc};
 
 use failure::Error;
@@ -27,14 +27,32 @@ const FSCK_CHANNEL_SIZE: usize = 16;
 
 /// Convenience module reexporting all important traits.
 pub mod prelude {
-    pub use super::{Backend, Builder, Content, FutureContent, FutureDigest, FutureFinish,
-                    FutureId, FutureLoadBranches, FutureResolveDigest, FutureResolveId,
-                    FutureSwapBranches, Handle, LocalId, OwnedLocalId, Store};
+    pub use super::{Backend, Builder, Content, ContentArguments, FutureContent, FutureDigest,
+                    FutureFinish, FutureId, FutureLoadBranches, FutureResolveDigest,
+                    FutureResolveId, FutureSwapBranches, Handle, LocalId, OwnedLocalId, Store};
 }
 
+/// A backend-local reference to an object.
+///
+/// Raw handles are reference counted by the backend which issued them. Every raw handle returned
+/// from a `Backend` method (or yielded from a `Backend::Content` iterator) carries a single
+/// reference, which is taken over by the `Handle` wrapping it and released when that `Handle` is
+/// dropped. This allows backends to forget about objects which are no longer referenced, keeping
+/// their memory usage proportional to the number of live handles.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct RawHandle(pub u64);
 
+/// A raw handle pushed into a builder as an argument of the object being built, rather than as a
+/// ref. See `canonical::encode` for the distinction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RawArgument(pub RawHandle);
+
+/// Trait for backend content types, yielding the arguments of a loaded object. Each raw handle
+/// yielded carries a reference, just as those yielded by the content's `Iterator` impl.
+pub trait ContentArguments {
+    fn next_argument(&mut self) -> Option<RawHandle>;
+}
+
 #[derive(Debug, Default)]
 struct Inner<B: Backend> {
     uuid: Uuid,
@@ -66,6 +84,7 @@ impl<B: Backend> Store<B> {
         Builder {
             store: self.clone(),
             builder: self.inner.backend.builder(),
+            handles: Vec::new(),
         }
     }
 
@@ -117,13 +136,16 @@ impl<B: Backend> Store<B> {
     ) -> FutureSwapBranches {
         let store = self.clone();
         let blocking = async_block! {
-            let old_stripped = old.into_iter()
-                .map(|(key, value)| (key, value.id))
+            // Strip the handles without consuming them, so that their references are held until
+            // the backend is done with the raw handles.
+            let old_stripped = old.iter()
+                .map(|(key, value)| (key.clone(), value.id))
                 .collect();
-            let new_stripped = new.into_iter()
-                .map(|(key, value)| (key, value.id))
+            let new_stripped = new.iter()
+                .map(|(key, value)| (key.clone(), value.id))
                 .collect();
             await!(store.inner.backend.swap_branches(old_stripped, new_stripped))?;
+            mem::drop((old, new));
             Ok(())
         };
         Box::new(blocking)
@@ -152,6 +174,30 @@ impl<B: Backend> Iterator for Content<B> {
     }
 }
 
+impl<B: Backend> Content<B> {
+    /// Iterate over the arguments of the loaded object. Refs are yielded by the `Iterator` impl of
+    /// `Content` itself.
+    pub fn arguments(&mut self) -> Arguments<B> {
+        Arguments { content: self }
+    }
+}
+
+pub struct Arguments<'a, B: Backend + 'a> {
+    content: &'a mut Content<B>,
+}
+
+impl<'a, B: Backend> Iterator for Arguments<'a, B> {
+    type Item = Handle<B>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let store = &self.content.store;
+        self.content.content.next_argument().map(|id| Handle {
+            store: store.clone(),
+            id,
+        })
+    }
+}
+
 pub struct Handle<B: Backend> {
     store: Store<B>,
     id: RawHandle,
@@ -168,6 +214,8 @@ impl<B: Backend> fmt::Debug for Handle<B> {
 
 impl<B: Backend> Clone for Handle<B> {
     fn clone(&self) -> Self {
+        self.store.inner.backend.retain(self.id);
+
         Self {
             store: self.store.clone(),
             id: self.id,
@@ -175,6 +223,12 @@ impl<B: Backend> Clone for Handle<B> {
     }
 }
 
+impl<B: Backend> Drop for Handle<B> {
+    fn drop(&mut self) {
+        self.store.inner.backend.release(self.id);
+    }
+}
+
 impl<B: Backend> PartialEq for Handle<B> {
     fn eq(&self, rhs: &Self) -> bool {
         self.id == rhs.id && self.store.inner.uuid == rhs.store.inner.uuid
@@ -207,31 +261,30 @@ impl<B: Backend> Hash for Handle<B> {
     }
 }
 
+// These clone the handle into the returned future rather than just copying out the raw handle,
+// so that the backend cannot release the raw handle before the future completes.
 impl<B: Backend> Handle<B> {
     pub fn load(&self) -> FutureContent<B> {
-        let store = self.store.clone();
-        let id = self.id;
+        let this = self.clone();
         let blocking = async_block! {
-            let content = await!(store.inner.backend.load(id))?;
-            Ok(Content { store, content })
+            let content = await!(this.store.inner.backend.load(this.id))?;
+            Ok(Content { store: this.store.clone(), content })
         };
         Box::new(blocking)
     }
 
     pub fn id(&self) -> FutureId<B> {
-        let store = self.store.clone();
-        let id = self.id;
+        let this = self.clone();
         let blocking = async_block! {
-            Ok(await!(store.inner.backend.id(id))?)
+            Ok(await!(this.store.inner.backend.id(this.id))?)
         };
         Box::new(blocking)
     }
 
     pub fn digest<D: Digest>(&self) -> FutureDigest<D> {
-        let store = self.store.clone();
-        let id = self.id;
+        let this = self.clone();
         let blocking = async_block! {
-            let any_digest = await!(store.inner.backend.digest(D::SIGNATURE, id))?;
+            let any_digest = await!(this.store.inner.backend.digest(D::SIGNATURE, this.id))?;
             let digest = any_digest.into_digest::<D>().unwrap();
             Ok(digest)
         };
@@ -242,6 +295,10 @@ impl<B: Backend> Handle<B> {
 pub struct Builder<B: Backend> {
     store: Store<B>,
     builder: B::Builder,
+
+    // Handles pushed into the builder are kept alive until the builder is finished, so that the
+    // raw handles held by the backend's builder stay valid.
+    handles: Vec<Handle<B>>,
 }
 
 impl<B: Backend> Write for Builder<B> {
@@ -260,9 +317,12 @@ impl<B: Backend> Extend<Handle<B>> for Builder<B> {
         I: IntoIterator<Item = Handle<B>>,
     {
         let store_id = self.store.inner.uuid;
+        let handles = &mut self.handles;
         self.builder.extend(iter.into_iter().map(|handle| {
             assert!(handle.store.inner.uuid == store_id);
-            handle.id
+            let id = handle.id;
+            handles.push(handle);
+            id
         }));
     }
 }
@@ -272,10 +332,46 @@ impl<B: Backend> Builder<B> {
         self.extend(iter::once(handle));
     }
 
+    pub fn push_argument(&mut self, handle: Handle<B>) {
+        self.extend_arguments(iter::once(handle));
+    }
+
+    pub fn extend_arguments<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = Handle<B>>,
+    {
+        let store_id = self.store.inner.uuid;
+        let handles = &mut self.handles;
+        self.builder.extend(iter.into_iter().map(|handle| {
+            assert!(handle.store.inner.uuid == store_id);
+            let id = handle.id;
+            handles.push(handle);
+            RawArgument(id)
+        }));
+    }
+
     pub fn finish(self) -> FutureFinish<B> {
+        self.finish_with(None)
+    }
+
+    /// Finish the builder, given the canonical encoding of the object it holds: exactly what
+    /// `canonical::encode::<_, D>` writes for the data, refs and arguments pushed into it. Backends
+    /// identifying objects by `D` digests use it instead of hashing the object again.
+    pub fn finish_encoded<D: Digest>(self, encoded: Vec<u8>) -> FutureFinish<B> {
+        self.finish_with(Some((D::SIGNATURE, encoded)))
+    }
+
+    fn finish_with(self, encoded: Option<(DigestSignature, Vec<u8>)>) -> FutureFinish<B> {
         let blocking = async_block! {
-            let store = self.store;
-            let id = await!(store.inner.backend.finish(self.builder))?;
+            let Builder { store, builder, handles } = self;
+            let future_id = match encoded {
+                Some((signature, encoded)) => {
+                    store.inner.backend.finish_encoded(builder, signature, encoded)
+                }
+                None => store.inner.backend.finish(builder),
+            };
+            let id = await!(future_id)?;
+            mem::drop(handles);
             Ok(Handle {
                 store,
                 id,
@@ -308,12 +404,32 @@ impl<D: Digest> ErasedDigest for D {
 pub trait Backend: Send + Sync + 'static {
     fn uuid(&self) -> [u8; 16];
 
-    type Builder: Write + Extend<RawHandle> + 'static;
+    /// Take an additional reference to a live raw handle.
+    fn retain(&self, id: RawHandle);
+
+    /// Release a reference to a raw handle. Once all references to a raw handle have been
+    /// released, the backend is free to forget it; the raw handle must not be used again.
+    fn release(&self, id: RawHandle);
+
+    type Builder: Write + Extend<RawHandle> + Extend<RawArgument> + 'static;
     type FutureFinish: Future<Item = RawHandle, Error = Error>;
     fn builder(&self) -> Self::Builder;
     fn finish(&self, Self::Builder) -> Self::FutureFinish;
 
-    type Content: Read + Iterator<Item = RawHandle> + 'static;
+    /// Finish a builder whose canonical encoding has already been calculated using digests with
+    /// the given signature. Hashing an object's data is the costly part of finishing it, so
+    /// backends which identify objects by digests with that signature should use the encoding
+    /// rather than calculate it again; the rest may ignore it.
+    fn finish_encoded(
+        &self,
+        builder: Self::Builder,
+        _signature: DigestSignature,
+        _encoded: Vec<u8>,
+    ) -> Self::FutureFinish {
+        self.finish(builder)
+    }
+
+    type Content: Read + Iterator<Item = RawHandle> + ContentArguments + 'static;
     type FutureContent: Future<Item = Self::Content, Error = Error>;
     fn load(&self, id: RawHandle) -> Self::FutureContent;
 
@@ -346,10 +462,11 @@ pub trait Backend: Send + Sync + 'static {
 trait AnyBuilder: 'static {
     fn as_write(&mut self) -> &mut Write;
     fn do_extend(&mut self, iterator: &mut Iterator<Item = RawHandle>);
+    fn do_extend_arguments(&mut self, iterator: &mut Iterator<Item = RawArgument>);
     fn into_any(self: Box<Self>) -> Box<Any>;
 }
 
-impl<T: Write + Extend<RawHandle> + 'static> AnyBuilder for T {
+impl<T: Write + Extend<RawHandle> + Extend<RawArgument> + 'static> AnyBuilder for T {
     fn as_write(&mut self) -> &mut Write {
         self
     }
@@ -358,6 +475,10 @@ impl<T: Write + Extend<RawHandle> + 'static> AnyBuilder for T {
         self.extend(iterator);
     }
 
+    fn do_extend_arguments(&mut self, iterator: &mut Iterator<Item = RawArgument>) {
+        self.extend(iterator);
+    }
+
     fn into_any(self: Box<Self>) -> Box<Any> {
         self
     }
@@ -382,12 +503,21 @@ impl Extend<RawHandle> for ErasedBuilder {
     where
         I: IntoIterator<Item = RawHandle>,
     {
-        self.do_extend(&mut iterable.into_iter());
+        self.boxed.do_extend(&mut iterable.into_iter());
+    }
+}
+
+impl Extend<RawArgument> for ErasedBuilder {
+    fn extend<I>(&mut self, iterable: I)
+    where
+        I: IntoIterator<Item = RawArgument>,
+    {
+        self.boxed.do_extend_arguments(&mut iterable.into_iter());
     }
 }
 
 impl ErasedBuilder {
-    fn new<T: Write + Extend<RawHandle> + 'static>(builder: T) -> Self {
+    fn new<T: Write + Extend<RawHandle> + Extend<RawArgument> + 'static>(builder: T) -> Self {
         Self {
             boxed: Box::new(builder),
         }
@@ -397,9 +527,10 @@ impl ErasedBuilder {
 trait AnyContent: 'static {
     fn as_read(&mut self) -> &mut Read;
     fn as_iterator(&mut self) -> &mut Iterator<Item = RawHandle>;
+    fn as_arguments(&mut self) -> &mut ContentArguments;
 }
 
-impl<T: Read + Iterator<Item = RawHandle> + 'static> AnyContent for T {
+impl<T: Read + Iterator<Item = RawHandle> + ContentArguments + 'static> AnyContent for T {
     fn as_read(&mut self) -> &mut Read {
         self
     }
@@ -407,6 +538,10 @@ impl<T: Read + Iterator<Item = RawHandle> + 'static> AnyContent for T {
     fn as_iterator(&mut self) -> &mut Iterator<Item = RawHandle> {
         self
     }
+
+    fn as_arguments(&mut self) -> &mut ContentArguments {
+        self
+    }
 }
 
 pub struct ErasedContent {
@@ -427,8 +562,14 @@ impl Iterator for ErasedContent {
     }
 }
 
+impl ContentArguments for ErasedContent {
+    fn next_argument(&mut self) -> Option<RawHandle> {
+        self.boxed.as_arguments().next_argument()
+    }
+}
+
 impl ErasedContent {
-    fn new<T: Read + Iterator<Item = RawHandle> + 'static>(content: T) -> Self {
+    fn new<T: Read + Iterator<Item = RawHandle> + ContentArguments + 'static>(content: T) -> Self {
         Self {
             boxed: Box::new(content),
         }
@@ -444,6 +585,14 @@ impl<B: Backend> Backend for BoxWrapped<B> {
         self.backend.uuid()
     }
 
+    fn retain(&self, id: RawHandle) {
+        self.backend.retain(id)
+    }
+
+    fn release(&self, id: RawHandle) {
+        self.backend.release(id)
+    }
+
     type Builder = ErasedBuilder;
     type FutureFinish = Box<Future<Item = RawHandle, Error = Error>>;
     fn builder(&self) -> Self::Builder {
@@ -453,6 +602,15 @@ impl<B: Backend> Backend for BoxWrapped<B> {
         let builder = *builder.boxed.into_any().downcast::<B::Builder>().unwrap();
         Box::new(self.backend.finish(builder))
     }
+    fn finish_encoded(
+        &self,
+        builder: ErasedBuilder,
+        signature: DigestSignature,
+        encoded: Vec<u8>,
+    ) -> Self::FutureFinish {
+        let builder = *builder.boxed.into_any().downcast::<B::Builder>().unwrap();
+        Box::new(self.backend.finish_encoded(builder, signature, encoded))
+    }
 
     type Content = ErasedContent;
     type FutureContent = Box<Future<Item = Self::Content, Error = Error>>;
@@ -539,6 +697,14 @@ impl Backend for ErasedBackend {
         self.boxed.uuid()
     }
 
+    fn retain(&self, id: RawHandle) {
+        self.boxed.retain(id)
+    }
+
+    fn release(&self, id: RawHandle) {
+        self.boxed.release(id)
+    }
+
     type Builder = ErasedBuilder;
     type FutureFinish = Box<Future<Item = RawHandle, Error = Error>>;
     fn builder(&self) -> Self::Builder {
@@ -547,6 +713,14 @@ impl Backend for ErasedBackend {
     fn finish(&self, builder: ErasedBuilder) -> Self::FutureFinish {
         self.boxed.finish(builder)
     }
+    fn finish_encoded(
+        &self,
+        builder: ErasedBuilder,
+        signature: DigestSignature,
+        encoded: Vec<u8>,
+    ) -> Self::FutureFinish {
+        self.boxed.finish_encoded(builder, signature, encoded)
+    }
 
     type Content = ErasedContent;
     type FutureContent = Box<Future<Item = Self::Content, Error = Error>>;
@@ -615,6 +789,13 @@ where
     io::copy(&mut content, &mut builder)?;
 
     // TODO: buffer?
+    let args = {
+        let arg_target = target.clone();
+        let future_args = stream::iter_ok(content.arguments().collect::<Vec<_>>())
+            .and_then(move |a| copy(a, arg_target.clone()))
+            .collect();
+        await!(future_args)?
+    };
     let refs = {
         let future_refs = stream::iter_ok(content)
             .and_then(move |r| copy(r, target.clone()))
@@ -622,6 +803,7 @@ where
         await!(future_refs)?
     };
     builder.extend(refs);
+    builder.extend_arguments(args);
 
     Ok(await!(builder.finish())?)
 }
@@ -659,6 +841,13 @@ where
         content.read_to_end(&mut buf)?;
         buf
     };
+    let arg_digests = {
+        let iter_tx = tx.clone();
+        let future_digests = stream::iter_ok(content.arguments().collect::<Vec<_>>())
+            .and_then(move |a| do_fsck(a, iter_tx.clone()))
+            .collect();
+        await!(future_digests)?
+    };
     let digests = {
         let iter_tx = tx.clone();
         let future_digests = stream::iter_ok(content)
@@ -668,7 +857,7 @@ where
     };
 
     let mut writer = D::writer();
-    canonical::encode(&mut writer, &content_buf, &digests)?;
+    canonical::encode(&mut writer, &content_buf, &digests, &arg_digests)?;
     let checked_digest = writer.finish();
 
     if store_digest != checked_digest {
@@ -710,14 +899,17 @@ where
 pub mod dummy {
     use super::*;
 
-    use std::io::Cursor;
+    use std::{io::Cursor, sync::{RwLock, atomic::{self, AtomicUsize}}};
 
+    use digest::Sha3Digest;
+    use futures::future;
     use proptest::prelude::*;
 
     #[derive(Debug, Default)]
     pub struct DummyBuilder {
         pub blob: Vec<u8>,
         pub refs: Vec<RawHandle>,
+        pub args: Vec<RawHandle>,
     }
 
     impl Write for DummyBuilder {
@@ -739,10 +931,20 @@ pub mod dummy {
         }
     }
 
+    impl Extend<RawArgument> for DummyBuilder {
+        fn extend<I>(&mut self, iterable: I)
+        where
+            I: IntoIterator<Item = RawArgument>,
+        {
+            self.args.extend(iterable.into_iter().map(|RawArgument(id)| id));
+        }
+    }
+
     #[derive(Debug)]
     pub struct DummyContent {
         blob: Cursor<Vec<u8>>,
         refs: ::std::vec::IntoIter<RawHandle>,
+        args: ::std::vec::IntoIter<RawHandle>,
     }
 
     impl Read for DummyContent {
@@ -759,6 +961,12 @@ pub mod dummy {
         }
     }
 
+    impl ContentArguments for DummyContent {
+        fn next_argument(&mut self) -> Option<RawHandle> {
+            self.args.next()
+        }
+    }
+
     impl DummyContent {
         pub fn new(
             builder: Builder<DummyBackend>,
@@ -769,6 +977,7 @@ pub mod dummy {
                 content: Self {
                     blob: Cursor::new(builder.builder.blob),
                     refs: builder.builder.refs.into_iter(),
+                    args: builder.builder.args.into_iter(),
                 },
             }
         }
@@ -830,6 +1039,10 @@ pub mod dummy {
             [0; 16]
         }
 
+        fn retain(&self, _: RawHandle) {}
+
+        fn release(&self, _: RawHandle) {}
+
         type Builder = DummyBuilder;
         type FutureFinish = Box<Future<Item = RawHandle, Error = Error>>;
         fn builder(&self) -> Self::Builder {
@@ -886,6 +1099,127 @@ pub mod dummy {
         }
     }
 
+    type StoredObject = (Vec<u8>, Vec<RawHandle>, Vec<RawHandle>);
+
+    /// A backend keeping objects in memory, for tests which need to load what they store.
+    /// Identical objects get identical handles, as with any content-addressed backend, but
+    /// handles are never released. Its SHA-3 digests are taken over handles rather than canonical
+    /// encodings, so they can be compared with each other but not with any other store's.
+    #[derive(Debug, Default)]
+    pub struct MemoryBackend {
+        objects: RwLock<Vec<StoredObject>>,
+        handles: RwLock<HashMap<StoredObject, RawHandle>>,
+        branches: RwLock<HashMap<String, RawHandle>>,
+        loads: Arc<AtomicUsize>,
+    }
+
+    impl MemoryBackend {
+        /// A counter of the objects loaded from this backend so far.
+        pub fn loads(&self) -> Arc<AtomicUsize> {
+            self.loads.clone()
+        }
+    }
+
+    fn id_bytes(id: RawHandle) -> Vec<u8> {
+        (0..8).map(|i| (id.0 >> (56 - 8 * i)) as u8).collect()
+    }
+
+    impl Backend for MemoryBackend {
+        fn uuid(&self) -> [u8; 16] {
+            [0; 16]
+        }
+
+        fn retain(&self, _: RawHandle) {}
+
+        fn release(&self, _: RawHandle) {}
+
+        type Builder = DummyBuilder;
+        type FutureFinish = Box<Future<Item = RawHandle, Error = Error>>;
+        fn builder(&self) -> Self::Builder {
+            DummyBuilder::default()
+        }
+        fn finish(&self, builder: Self::Builder) -> Self::FutureFinish {
+            let object = (builder.blob, builder.refs, builder.args);
+            let mut handles = self.handles.write().unwrap();
+            let id = match handles.get(&object) {
+                Some(&id) => id,
+                None => {
+                    let mut objects = self.objects.write().unwrap();
+                    let id = RawHandle(objects.len() as u64);
+                    objects.push(object.clone());
+                    handles.insert(object, id);
+                    id
+                }
+            };
+
+            Box::new(future::ok(id))
+        }
+
+        type Content = DummyContent;
+        type FutureContent = Box<Future<Item = Self::Content, Error = Error>>;
+        fn load(&self, id: RawHandle) -> Self::FutureContent {
+            self.loads.fetch_add(1, atomic::Ordering::SeqCst);
+            let (blob, refs, args) = self.objects.read().unwrap()[id.0 as usize].clone();
+
+            Box::new(future::ok(DummyContent {
+                blob: Cursor::new(blob),
+                refs: refs.into_iter(),
+                args: args.into_iter(),
+            }))
+        }
+
+        type Id = [u8];
+        type FutureId = Box<Future<Item = Vec<u8>, Error = Error>>;
+        fn id(&self, id: RawHandle) -> Self::FutureId {
+            Box::new(future::ok(id_bytes(id)))
+        }
+
+        type Digest = Sha3Digest;
+        type FutureDigest = Box<Future<Item = Self::Digest, Error = Error>>;
+        fn digest(&self, signature: DigestSignature, id: RawHandle) -> Self::FutureDigest {
+            if signature != Sha3Digest::SIGNATURE {
+                return Box::new(future::err(format_err!(
+                    "Memory backends only calculate SHA-3 digests"
+                )));
+            }
+
+            Box::new(future::ok(Sha3Digest::digest(&id_bytes(id))))
+        }
+
+        type FutureResolveId = Box<Future<Item = Option<RawHandle>, Error = Error>>;
+        fn resolve_id(&self, bytes: &[u8]) -> Self::FutureResolveId {
+            let id = RawHandle(bytes.iter().fold(0, |acc, &byte| (acc << 8) | u64::from(byte)));
+            let exists = bytes.len() == 8 && (id.0 as usize) < self.objects.read().unwrap().len();
+
+            Box::new(future::ok(if exists { Some(id) } else { None }))
+        }
+
+        type FutureResolveDigest = Box<Future<Item = Option<RawHandle>, Error = Error>>;
+        fn resolve_digest(&self, _: DigestSignature, _: &[u8]) -> Self::FutureResolveDigest {
+            Box::new(future::err(format_err!("Memory backends can't resolve digests")))
+        }
+
+        type FutureLoadBranches = Box<Future<Item = HashMap<String, RawHandle>, Error = Error>>;
+        fn load_branches(&self) -> Self::FutureLoadBranches {
+            Box::new(future::ok(self.branches.read().unwrap().clone()))
+        }
+
+        type FutureSwapBranches = Box<Future<Item = (), Error = Error>>;
+        fn swap_branches(
+            &self,
+            previous: HashMap<String, RawHandle>,
+            new: HashMap<String, RawHandle>,
+        ) -> Self::FutureSwapBranches {
+            let mut branches = self.branches.write().unwrap();
+            if *branches != previous {
+                return Box::new(future::err(format_err!("Branches changed concurrently!")));
+            }
+            *branches = new;
+
+            Box::new(future::ok(()))
+        }
+    }
+
     pub fn dummy_handle(store: Store<DummyBackend>) -> BoxedStrategy<Handle<DummyBackend>> {
         any::<u64>()
             .prop_map(move |n| Handle {
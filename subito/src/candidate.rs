@@ -1,15 +1,22 @@
-use std::{fmt, ffi::OsStr, fs::File, path::PathBuf};
+use std::{fmt, collections::BTreeSet, ffi::OsStr, fs::{self, File}, io::Write, os::unix::fs::PermissionsExt,
+          path::{Path, PathBuf}};
 
 use attaca::{batch::{Batch as ObjectBatch, Operation as ObjectOperation}, hierarchy::Hierarchy,
-             object::{self, CommitAuthor, CommitBuilder, CommitRef, ObjectRef, TreeBuilder},
-             path::ObjectPath, store::prelude::*};
+             object::{self, CommitAuthor, CommitBuilder, CommitCommitter, CommitRef, EntryAttrs,
+                      FileMode, ObjectRef, SmallBuilder, TreeBuilder},
+             path::ObjectPath, split::{RecordSplitter, SliceSplitter, Splitter}, store::prelude::*};
 use failure::{self, *};
 use futures::{stream, future::Either, prelude::*};
 use ignore::WalkBuilder;
+use memmap::Mmap;
 
 use {Repository, State};
 use cache::{Cache, Certainty, Status};
+use config::{Config, SplitStrategy};
+use link;
+use posix;
 use state::Head;
+use syntax::Property;
 
 /// Save the virtual workspace as a child commit of the previous commit.
 #[derive(Debug, StructOpt, Builder)]
@@ -23,6 +30,10 @@ pub struct CommitArgs {
     #[structopt(long = "author")]
     pub author: Option<String>,
 
+    /// Record a custom `key=value` property on the commit. May be given multiple times.
+    #[structopt(long = "meta", raw(number_of_values = "1"))]
+    pub meta: Vec<Property>,
+
     /// Instead of making a new commit, load the previous commit and update it.
     #[structopt(long = "amend")]
     pub amend: bool,
@@ -111,6 +122,37 @@ impl BatchOp {
     }
 }
 
+/// Everything from the configuration which affects how files are staged.
+#[derive(Debug, Clone)]
+struct StageOptions {
+    strategy: SplitStrategy,
+    keep_empty_directories: bool,
+    preserve_posix: bool,
+}
+
+impl StageOptions {
+    fn new(root: &Path, config: &Config) -> Result<Self, Error> {
+        Ok(Self {
+            strategy: SplitStrategy::new(root, config)?,
+            keep_empty_directories: config.keep_empty_directories,
+            preserve_posix: config.preserve_posix,
+        })
+    }
+}
+
+/// Read the mode to record for a local file. Any execute permission bit makes it executable.
+pub(crate) fn file_mode(path: &Path) -> Result<FileMode, Error> {
+    let permissions = fs::metadata(path)
+        .context("Error reading local file permissions")?
+        .permissions();
+
+    if permissions.mode() & 0o111 != 0 {
+        Ok(FileMode::Executable)
+    } else {
+        Ok(FileMode::Regular)
+    }
+}
+
 impl<B: Backend> Repository<B> {
     pub fn commit<'r>(&'r mut self, args: CommitArgs) -> CommitOut<'r> {
         let blocking = async_block! {
@@ -131,9 +173,31 @@ impl<B: Backend> Repository<B> {
             };
             let maybe_head = await!(maybe_head_ref.as_ref().map(CommitRef::fetch))?;
 
+            let maybe_merge_parent = match state.merge {
+                Some(ref merge) => {
+                    ensure!(!args.amend, "Cannot amend a commit while merging!");
+                    ensure!(
+                        merge.conflicts.is_empty(),
+                        "Cannot commit a merge with unresolved conflicts! \
+                         Stage these paths once they are fixed: {}",
+                        merge
+                            .conflicts
+                            .iter()
+                            .map(|conflict| conflict.path.to_path().display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                    Some(merge.parent.clone())
+                }
+                None => None,
+            };
+
+            // A merge commit is made even if the merge changed nothing, so that the merged
+            // history is recorded.
             if let Some(ref head_commit) = maybe_head {
                 ensure!(
-                    head_commit.as_subtree() != &candidate || args.force,
+                    head_commit.as_subtree() != &candidate || args.force
+                        || maybe_merge_parent.is_some(),
                     "Previous commit is identical to virtual workspace! \
                      No changes will be committed - use --force to override."
                 );
@@ -146,7 +210,7 @@ impl<B: Backend> Repository<B> {
                 }
             } else {
                 let mut builder = CommitBuilder::new();
-                builder.parents(maybe_head_ref.into_iter());
+                builder.parents(maybe_head_ref.into_iter().chain(maybe_merge_parent.clone()));
                 builder
             };
 
@@ -163,12 +227,22 @@ impl<B: Backend> Repository<B> {
                 });
             }
 
+            // The committer is always whoever is configured for this repository. The author is
+            // only ever what was given with `--author`, so it stays unset without one.
+            let identity = self.get_config()?.committer;
+            commit_builder.committer(CommitCommitter::now(identity.name, identity.mbox));
+
+            for Property { key, value } in args.meta {
+                commit_builder.property(key, value);
+            }
+
             let commit_ref = await!(commit_builder.into_commit()?.send(&self.store))?;
 
             match state.head {
                 Head::Empty | Head::Detached(_) => {
                     self.set_state(&State {
                         head: Head::Detached(commit_ref),
+                        merge: None,
                         ..state
                     })?;
                 }
@@ -176,6 +250,11 @@ impl<B: Backend> Repository<B> {
                     let mut new_branches = branches.clone();
                     new_branches.insert(branch.into_string(), commit_ref.into_inner());
                     await!(self.store.swap_branches(branches, new_branches))?;
+
+                    if maybe_merge_parent.is_some() {
+                        let state = self.get_state()?;
+                        self.set_state(&State { merge: None, ..state })?;
+                    }
                 }
             }
 
@@ -210,6 +289,7 @@ impl<B: Backend> Repository<B> {
     fn do_process_file(
         store: Store<B>,
         cache: Cache<B>,
+        strategy: SplitStrategy,
         absolute_path: PathBuf,
         object_path: ObjectPath,
     ) -> Result<ObjectRef<Handle<B>>, Error> {
@@ -235,9 +315,31 @@ impl<B: Backend> Repository<B> {
         match status {
             // TODO: Respect cache and reuse hash.
             Status::Extant(_, snapshot) | Status::New(snapshot) => {
-                let mut file = File::open(&absolute_path).context("Error opening local file")?;
-                let objref =
-                    await!(object::share(file, store)).context("Error hashing/sending local file")?;
+                let file = File::open(&absolute_path).context("Error opening local file")?;
+                let metadata = file.metadata().context("Error reading local file metadata")?;
+
+                // Split regular files in place through a memory map, unless they're to be split on
+                // records. Empty files can't be mapped, and anything which isn't a regular file
+                // might not have a fixed length.
+                let splitter = strategy.parameters();
+                let future_objref: Box<Future<Item = ObjectRef<Handle<B>>, Error = Error>> =
+                    match strategy.delimiter(&absolute_path) {
+                        Some(delimiter) => {
+                            let chunker = RecordSplitter::new(file, splitter, delimiter);
+                            Box::new(object::share_chunked(chunker, store))
+                        }
+                        None if metadata.is_file() && metadata.len() > 0 => {
+                            let mmap =
+                                unsafe { Mmap::map(&file) }.context("Error mapping local file")?;
+                            let chunker = SliceSplitter::new(mmap, splitter);
+                            Box::new(object::share_chunked(chunker, store))
+                        }
+                        None => {
+                            let chunker = Splitter::new(file, splitter);
+                            Box::new(object::share_chunked(chunker, store))
+                        }
+                    };
+                let objref = await!(future_objref).context("Error hashing/sending local file")?;
                 let id = await!(objref.id()).context("Error fetching object digest")?;
                 cache
                     .resolve(snapshot, id)
@@ -249,28 +351,89 @@ impl<B: Backend> Repository<B> {
         }
     }
 
+    /// Process a single file or symlink. Symlinks are never followed; their targets are stored
+    /// as they are.
+    #[async]
+    fn do_process_entry(
+        store: Store<B>,
+        cache: Cache<B>,
+        options: StageOptions,
+        absolute_path: PathBuf,
+        object_path: ObjectPath,
+        file_type: fs::FileType,
+    ) -> Result<(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>), Error> {
+        // Read metadata before contents, so that it's never newer than what gets staged.
+        let posix_opt = if options.preserve_posix {
+            Some(posix::read(&absolute_path)?)
+        } else {
+            None
+        };
+
+        let (objref, mode) = if file_type.is_symlink() {
+            let mut small_builder = SmallBuilder::new();
+            small_builder.write_all(&link::read(&absolute_path)?)?;
+            let small_ref = await!(small_builder.into_small().send(&store))
+                .context("Error sending symbolic link target")?;
+            (ObjectRef::Small(small_ref), FileMode::Symlink)
+        } else {
+            let mode = file_mode(&absolute_path)?;
+            let objref = await!(Self::do_process_file(
+                store.clone(),
+                cache,
+                options.strategy,
+                absolute_path,
+                object_path
+            ))?;
+            (objref, mode)
+        };
+
+        let posix = match posix_opt {
+            Some(posix) => {
+                let small_ref = await!(posix.to_small().send(&store))
+                    .context("Error sending POSIX metadata")?;
+                Some(ObjectRef::Small(small_ref))
+            }
+            None => None,
+        };
+
+        Ok((objref, EntryAttrs { mode, posix }))
+    }
+
     #[async]
     fn do_process(
         store: Store<B>,
         cache: Cache<B>,
+        options: StageOptions,
         absolute_path: PathBuf,
         object_path: ObjectPath,
-    ) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
-        if !absolute_path.exists() {
-            return Ok(None);
-        }
+    ) -> Result<Option<(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>)>, Error> {
+        // Dangling symlinks don't "exist", but they are still staged.
+        let file_type = match absolute_path.symlink_metadata() {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => return Ok(None),
+        };
 
-        let file_type = absolute_path.symlink_metadata()?.file_type();
         if file_type.is_symlink() || file_type.is_file() {
-            let objref = await!(Self::do_process_file(
+            let entry = await!(Self::do_process_entry(
                 store,
                 cache,
+                options,
                 absolute_path,
-                object_path
+                object_path,
+                file_type
             ))?;
-            Ok(Some(objref))
+            Ok(Some(entry))
         } else {
-            let mut object_batch = ObjectBatch::<B>::new();
+            let keep_empty = options.keep_empty_directories;
+            let mut object_batch = ObjectBatch::<B>::new().keep_empty(keep_empty);
+            // Directories seen during the walk, and those which turned out to have something in
+            // them. Only used to find empty directories.
+            let mut directories = BTreeSet::new();
+            let mut occupied = BTreeSet::new();
+            // The attributes of the directory itself and of every directory beneath it, only
+            // recorded when POSIX metadata is.
+            let mut root_attrs = EntryAttrs::default();
+            let mut directory_attrs = Vec::new();
             // TODO #33
             let walk = WalkBuilder::new(&absolute_path).build();
 
@@ -278,25 +441,81 @@ impl<B: Backend> Repository<B> {
                 let direntry = direntry_res?;
                 let file_type = direntry.file_type().unwrap();
 
+                if direntry.depth() > 0 {
+                    occupied.extend(direntry.path().parent().map(Path::to_owned));
+                }
+
                 if file_type.is_dir() {
+                    if keep_empty && direntry.depth() > 0 {
+                        directories.insert(direntry.path().to_owned());
+                    }
+
+                    if options.preserve_posix {
+                        let posix = posix::read(direntry.path())?;
+                        let small_ref = await!(posix.to_small().send(&store))
+                            .context("Error sending POSIX metadata")?;
+                        let attrs = EntryAttrs {
+                            mode: FileMode::Regular,
+                            posix: Some(ObjectRef::Small(small_ref)),
+                        };
+
+                        if direntry.depth() > 0 {
+                            let object_path = ObjectPath::from_path(
+                                direntry.path().strip_prefix(&absolute_path)?,
+                            )?;
+                            directory_attrs.push((object_path, attrs));
+                        } else {
+                            root_attrs = attrs;
+                        }
+                    }
                     continue;
                 }
 
                 let object_path =
                     ObjectPath::from_path(direntry.path().strip_prefix(&absolute_path)?)?;
                 // TODO: Concurrency here? Or more efficient not to?
-                let object_ref = await!(Self::do_process_file(
+                let (object_ref, attrs) = await!(Self::do_process_entry(
                     store.clone(),
                     cache.clone(),
+                    options.clone(),
                     direntry.path().to_owned(),
                     object_path.clone(),
+                    file_type,
                 ))?;
+                object_batch = await!(
+                    object_batch.add(ObjectOperation::Add(object_path, object_ref, attrs))
+                )?;
+            }
+
+            if keep_empty {
+                let empty_dirs = directories
+                    .difference(&occupied)
+                    .cloned()
+                    .collect::<Vec<PathBuf>>();
+                if !empty_dirs.is_empty() {
+                    let empty_ref = await!(TreeBuilder::new().as_tree().send(&store))?;
+                    for empty_dir in empty_dirs {
+                        let object_path =
+                            ObjectPath::from_path(empty_dir.strip_prefix(&absolute_path)?)?;
+                        object_batch = await!(object_batch.add(ObjectOperation::Add(
+                            object_path,
+                            ObjectRef::Tree(empty_ref.clone()),
+                            EntryAttrs::default(),
+                        )))?;
+                    }
+                }
+            }
+
+            // Directories which end up empty are still dropped unless they're being kept, attributes
+            // or not.
+            for (object_path, attrs) in directory_attrs {
                 object_batch =
-                    await!(object_batch.add(ObjectOperation::Add(object_path, object_ref)))?;
+                    await!(object_batch.add(ObjectOperation::SetAttrs(object_path, attrs)))?;
             }
 
             let built = await!(object_batch.run(store.clone(), TreeBuilder::new()))?;
-            Ok(Some(ObjectRef::Tree(await!(built.as_tree().send(&store))?)))
+            let tree_ref = await!(built.as_tree().send(&store))?;
+            Ok(Some((ObjectRef::Tree(tree_ref), root_attrs)))
         }
     }
 
@@ -304,13 +523,18 @@ impl<B: Backend> Repository<B> {
         &'r self,
         absolute_path: PathBuf,
         object_path: ObjectPath,
-    ) -> impl Future<Item = Option<ObjectRef<Handle<B>>>, Error = Error> {
-        Self::do_process(
-            self.store.clone(),
-            self.cache.clone(),
-            absolute_path,
-            object_path,
-        )
+    ) -> impl Future<Item = Option<(ObjectRef<Handle<B>>, EntryAttrs<Handle<B>>)>, Error = Error>
+    {
+        let store = self.store.clone();
+        let cache = self.cache.clone();
+
+        // Files are always split with the repository's configured parameters and record
+        // patterns, so that they deduplicate against everything else staged in it.
+        let options_res = self.get_config()
+            .and_then(|config| StageOptions::new(&self.path, &config));
+        options_res.into_future().and_then(move |options| {
+            Self::do_process(store, cache, options, absolute_path, object_path)
+        })
     }
 
     fn do_process_operation<'r>(
@@ -342,7 +566,7 @@ impl<B: Backend> Repository<B> {
             let future = match op {
                 OpKind::Unstage => Either::A(
                     hierarchy
-                        .get(object_path.clone())
+                        .get_with_attrs(object_path.clone())
                         .map_err(|e| e.context("Error processing file from previous commit")),
                 ),
                 OpKind::Stage => Either::B(
@@ -356,7 +580,7 @@ impl<B: Backend> Repository<B> {
         let future = async_block! {
             let (object_path, objref_opt) = await!(future_res?)?;
             let operation = match objref_opt {
-                Some(objref) => ObjectOperation::Add(object_path, objref),
+                Some((objref, attrs)) => ObjectOperation::Add(object_path, objref, attrs),
                 None => ObjectOperation::Delete(object_path),
             };
             Ok(operation)
@@ -381,6 +605,7 @@ impl<B: Backend> Repository<B> {
     {
         async_block! {
             let state = self.get_state()?;
+            let keep_empty = self.get_config()?.keep_empty_directories;
             let branches = await!(self.store.load_branches())?;
             let maybe_head_ref = match state.head {
                 Head::Empty => None,
@@ -401,10 +626,16 @@ impl<B: Backend> Repository<B> {
                     .into_iter()
                     .map(|batch_op| self.process_operation(hierarchy.clone(), batch_op)),
             );
-            let batch: ObjectBatch<B> = await!(
-                queue.fold(ObjectBatch::new(), |batch, op| batch.add(op))
-            ).context("Error while batching stage operations")?;
+            let (batch, staged_paths): (ObjectBatch<B>, Vec<ObjectPath>) = await!(queue.fold(
+                (ObjectBatch::new().keep_empty(keep_empty), Vec::new()),
+                |(batch, mut staged_paths), op| {
+                    staged_paths.push(op.as_object_path().clone());
+                    batch.add(op).map(move |batch| (batch, staged_paths))
+                }
+            )).context("Error while batching stage operations")?;
             await!(self.stage_objects(batch)).context("Error while staging objects")?;
+            self.mark_resolved(&staged_paths)
+                .context("Error while marking merge conflicts resolved")?;
 
             Ok(())
         }
@@ -439,3 +670,157 @@ impl<B: Backend> Repository<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix;
+
+    use attaca::object::Tree;
+    use attaca_leveldb::LevelDbBackend;
+
+    use init;
+    use plumbing;
+
+    fn set_keep_empty(repository: &Repository<LevelDbBackend>, keep_empty_directories: bool) {
+        let config = repository.get_config().unwrap();
+        repository
+            .set_config(&Config {
+                keep_empty_directories,
+                ..config
+            })
+            .unwrap();
+    }
+
+    /// `a/empty/`, `a/link` (a dangling symlink) and `b/`. Regular files are left out, since
+    /// symlinks and directories are all that empty directories are about.
+    fn make_layout(root: &Path) {
+        fs::create_dir_all(root.join("a/empty")).unwrap();
+        fs::create_dir(root.join("b")).unwrap();
+        unix::fs::symlink("nowhere", root.join("a/link")).unwrap();
+    }
+
+    fn fetch_tree(objref: &ObjectRef<Handle<LevelDbBackend>>) -> Tree<Handle<LevelDbBackend>> {
+        match *objref {
+            ObjectRef::Tree(ref tree_ref) => tree_ref.fetch().wait().unwrap(),
+            ref other => panic!("Expected a tree, got {:?}", other),
+        }
+    }
+
+    fn names(tree: &Tree<Handle<LevelDbBackend>>) -> Vec<&str> {
+        tree.keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn processing_drops_empty_directories_by_default() {
+        let repository = init::scratch("process-drop");
+        make_layout(&repository.path);
+
+        let (objref, _) = repository
+            .process((*repository.path).clone(), ObjectPath::new())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let root = fetch_tree(&objref);
+        assert_eq!(names(&root), vec!["a"]);
+        assert_eq!(names(&fetch_tree(&root["a"])), vec!["link"]);
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+
+    #[test]
+    fn processing_keeps_empty_directories_if_configured() {
+        let repository = init::scratch("process-keep");
+        set_keep_empty(&repository, true);
+        make_layout(&repository.path);
+
+        let (objref, _) = repository
+            .process((*repository.path).clone(), ObjectPath::new())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let root = fetch_tree(&objref);
+        assert_eq!(names(&root), vec!["a", "b"]);
+        assert!(fetch_tree(&root["b"]).is_empty());
+
+        let a = fetch_tree(&root["a"]);
+        assert_eq!(names(&a), vec!["empty", "link"]);
+        assert!(fetch_tree(&a["empty"]).is_empty());
+        assert_eq!(a.mode("link"), FileMode::Symlink);
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+
+    #[test]
+    fn staging_keeps_directories_emptied_by_removals_if_configured() {
+        let mut repository = init::scratch("stage-keep");
+        set_keep_empty(&repository, true);
+        make_layout(&repository.path);
+
+        let a_path = repository.path.join("a");
+        repository.stage_batch(vec![BatchOp::stage(a_path)]).wait().unwrap();
+        let candidate = repository.get_state().unwrap().candidate.unwrap();
+        let a = fetch_tree(&candidate.fetch().wait().unwrap()["a"]);
+        assert_eq!(names(&a), vec!["empty", "link"]);
+
+        // Removing the link leaves `a` holding nothing but the empty directory; removing that
+        // too leaves `a` itself empty, but still there.
+        fs::remove_file(repository.path.join("a/link")).unwrap();
+        fs::remove_dir(repository.path.join("a/empty")).unwrap();
+        let link_path = repository.path.join("a/link");
+        let empty_path = repository.path.join("a/empty");
+        repository
+            .stage_batch(vec![BatchOp::stage(link_path), BatchOp::stage(empty_path)])
+            .wait()
+            .unwrap();
+        let candidate = repository.get_state().unwrap().candidate.unwrap();
+        let root = candidate.fetch().wait().unwrap();
+        assert_eq!(names(&root), vec!["a"]);
+        assert!(fetch_tree(&root["a"]).is_empty());
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+
+    #[test]
+    fn directory_posix_metadata_survives_staging_and_checkout() {
+        let mut repository = init::scratch("posix-dir");
+        let config = repository.get_config().unwrap();
+        repository
+            .set_config(&Config {
+                preserve_posix: true,
+                ..config
+            })
+            .unwrap();
+
+        let dir_path = repository.path.join("dir");
+        fs::create_dir(&dir_path).unwrap();
+        unix::fs::symlink("nowhere", dir_path.join("link")).unwrap();
+        let mut dir_posix = posix::read(&dir_path).unwrap();
+        dir_posix.mtime = (1_000_000_000, 0);
+        posix::apply(&dir_path, &dir_posix).unwrap();
+
+        let (objref, _) = repository
+            .process((*repository.path).clone(), ObjectPath::new())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let root = fetch_tree(&objref);
+        let posix_ref = root.attrs("dir").posix.expect("directory POSIX metadata is recorded");
+        let bytes = posix_ref.write_to(Vec::new()).wait().unwrap();
+        assert_eq!(object::Posix::from_bytes(&bytes).unwrap().mtime, (1_000_000_000, 0));
+
+        // Checking the directory out again writes its link into it, and only then restores its
+        // modification time.
+        fs::remove_dir_all(&dir_path).unwrap();
+        let tree_ref = match objref {
+            ObjectRef::Tree(tree_ref) => tree_ref,
+            other => panic!("Expected a tree, got {:?}", other),
+        };
+        plumbing::checkout::tree(&mut repository, tree_ref).wait().unwrap();
+        assert!(dir_path.join("link").symlink_metadata().is_ok());
+        assert_eq!(posix::read(&dir_path).unwrap().mtime, (1_000_000_000, 0));
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+}
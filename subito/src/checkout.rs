@@ -41,6 +41,8 @@ impl<B: Backend> Repository<B> {
             if args.paths.is_empty() {
                 // If there are no paths specified, we checkout an entire branch and update the
                 // HEAD (unless the HEAD is being checked out. Because that's silly.)
+                self.ensure_not_merging()?;
+
                 match args.refr {
                     Ref::Head => await!(plumbing::checkout::head(self))?,
                     Ref::Branch(BranchRef::Local(name)) => {
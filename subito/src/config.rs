@@ -1,16 +1,77 @@
-use std::{collections::HashMap, io::{BufRead, Write}};
+use std::{fmt, collections::HashMap, io::{BufRead, Write}, path::Path, sync::Arc};
 
-use attaca::store::prelude::*;
+use attaca::{split::Parameters, store::prelude::*};
 use capnp::{message, serialize_packed};
 use failure::*;
+use futures::prelude::*;
+use ignore::{Match, overrides::{Override, OverrideBuilder}};
 use leveldb::{kv::KV, options::{ReadOptions, WriteOptions}};
 use url::Url;
 
 use Repository;
 use db::Key;
+use syntax::RecordPattern;
 
 use config_capnp::*;
 
+/// View or change repository configuration.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "config")]
+pub struct ConfigArgs {
+    /// Set the name recorded as the committer of new commits.
+    #[structopt(long = "committer-name")]
+    pub committer_name: Option<String>,
+
+    /// Set the mailbox recorded as the committer of new commits.
+    #[structopt(long = "committer-mbox")]
+    pub committer_mbox: Option<String>,
+
+    /// Set the minimum size in bytes of chunks split from newly staged files.
+    #[structopt(long = "split-min-chunk")]
+    pub split_min_chunk: Option<usize>,
+
+    /// Set the maximum size in bytes of chunks split from newly staged files.
+    #[structopt(long = "split-max-chunk")]
+    pub split_max_chunk: Option<usize>,
+
+    /// Set the base-two logarithm of the modulus of the splitter's rolling sum, which controls
+    /// the average chunk size.
+    #[structopt(long = "split-log2-modulus")]
+    pub split_log2_modulus: Option<u32>,
+
+    /// Split files matching a glob on record boundaries, given as `GLOB[=DELIMITER]`. The
+    /// delimiter defaults to a newline; write any `=` in the glob as `\=`. May be given multiple
+    /// times.
+    #[structopt(long = "split-records", raw(number_of_values = "1"))]
+    pub split_records: Vec<RecordPattern>,
+
+    /// Stop splitting any files on record boundaries. Applied before `--split-records`.
+    #[structopt(long = "clear-split-records")]
+    pub clear_split_records: bool,
+
+    /// Set whether empty directories are staged and checked out (`true` or `false`).
+    #[structopt(long = "keep-empty-directories")]
+    pub keep_empty_directories: Option<bool>,
+
+    /// Set whether full permission bits, ownership, modification times and extended attributes
+    /// are recorded when staging and restored on checkout (`true` or `false`).
+    #[structopt(long = "preserve-posix")]
+    pub preserve_posix: Option<bool>,
+}
+
+#[must_use = "ConfigOut contains futures which must be driven to completion!"]
+pub struct ConfigOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for ConfigOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConfigOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum StoreKind {
     LevelDb,
@@ -23,10 +84,30 @@ pub struct StoreConfig {
     pub kind: StoreKind,
 }
 
+/// The identity recorded as the committer of new commits.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityConfig {
+    pub name: Option<String>,
+    pub mbox: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub store: StoreConfig,
     pub remotes: HashMap<String, StoreConfig>,
+    pub committer: IdentityConfig,
+    /// The hashsplitter parameters used to stage files. Changing these means newly staged files
+    /// will no longer share chunks with previously staged ones.
+    pub splitter: Parameters,
+    /// Paths which are split on record boundaries rather than anywhere the splitter finds. The
+    /// first matching pattern wins.
+    pub record_patterns: Vec<RecordPattern>,
+    /// Whether empty directories are recorded as empty trees when staged, and recreated on
+    /// checkout. Off unless asked for, since it changes the trees which get built.
+    pub keep_empty_directories: bool,
+    /// Whether POSIX metadata is recorded alongside staged files and restored on checkout. File
+    /// contents hash the same either way; only the trees holding them differ.
+    pub preserve_posix: bool,
 }
 
 // TODO codegen match statements/sets for this through the all_backends! macro.
@@ -65,7 +146,68 @@ impl Config {
                 .collect::<Result<HashMap<_, _>, Error>>()?
         };
 
-        Ok(Config { store, remotes })
+        let committer = if config_reader.has_committer() {
+            let identity_reader = config_reader.get_committer()?;
+            let name = if identity_reader.has_name() {
+                Some(String::from(identity_reader.get_name()?))
+            } else {
+                None
+            };
+            let mbox = if identity_reader.has_mbox() {
+                Some(String::from(identity_reader.get_mbox()?))
+            } else {
+                None
+            };
+            IdentityConfig { name, mbox }
+        } else {
+            IdentityConfig::default()
+        };
+
+        let splitter = if config_reader.has_splitter() {
+            let splitter_reader = config_reader.get_splitter()?;
+            Parameters {
+                stride: splitter_reader.get_stride() as usize,
+                strides_per_window: splitter_reader.get_strides_per_window() as usize,
+                split_marker: splitter_reader.get_split_marker(),
+                log2_modulus: splitter_reader.get_log2_modulus(),
+                min_chunk: splitter_reader.get_min_chunk() as usize,
+                max_chunk: splitter_reader.get_max_chunk() as usize,
+            }
+        } else {
+            // Repositories from before the splitter was configurable split without any bounds on
+            // chunk sizes, and must go on doing so to keep deduplicating against their history.
+            Parameters::legacy()
+        };
+        splitter.validate()?;
+
+        let record_patterns = if config_reader.has_record_patterns() {
+            config_reader
+                .get_record_patterns()?
+                .iter()
+                .map(|record_pattern_reader| {
+                    Ok(RecordPattern {
+                        pattern: String::from(record_pattern_reader.get_pattern()?),
+                        delimiter: record_pattern_reader.get_delimiter(),
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        } else {
+            Vec::new()
+        };
+
+        // Absent from older configurations, in which case it reads as `false`.
+        let keep_empty_directories = config_reader.get_keep_empty_directories();
+        let preserve_posix = config_reader.get_preserve_posix();
+
+        Ok(Config {
+            store,
+            remotes,
+            committer,
+            splitter,
+            record_patterns,
+            keep_empty_directories,
+            preserve_posix,
+        })
     }
 
     pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
@@ -98,6 +240,36 @@ impl Config {
                     }
                 }
             }
+            {
+                let mut identity_builder = config_builder.borrow().init_committer();
+                if let Some(ref name) = self.committer.name {
+                    identity_builder.set_name(name);
+                }
+                if let Some(ref mbox) = self.committer.mbox {
+                    identity_builder.set_mbox(mbox);
+                }
+            }
+            {
+                let mut splitter_builder = config_builder.borrow().init_splitter();
+                splitter_builder.set_stride(self.splitter.stride as u64);
+                splitter_builder.set_strides_per_window(self.splitter.strides_per_window as u64);
+                splitter_builder.set_split_marker(self.splitter.split_marker);
+                splitter_builder.set_log2_modulus(self.splitter.log2_modulus);
+                splitter_builder.set_min_chunk(self.splitter.min_chunk as u64);
+                splitter_builder.set_max_chunk(self.splitter.max_chunk as u64);
+            }
+            {
+                let mut record_patterns_builder = config_builder
+                    .borrow()
+                    .init_record_patterns(self.record_patterns.len() as u32);
+                for (i, record_pattern) in self.record_patterns.iter().enumerate() {
+                    let mut record_pattern_builder = record_patterns_builder.borrow().get(i as u32);
+                    record_pattern_builder.set_pattern(&record_pattern.pattern);
+                    record_pattern_builder.set_delimiter(record_pattern.delimiter);
+                }
+            }
+            config_builder.set_keep_empty_directories(self.keep_empty_directories);
+            config_builder.set_preserve_posix(self.preserve_posix);
         }
 
         serialize_packed::write_message(writer, &message)?;
@@ -106,7 +278,117 @@ impl Config {
     }
 }
 
+/// Everything needed to choose a splitter for a file being staged.
+#[derive(Debug, Clone)]
+pub struct SplitStrategy {
+    parameters: Parameters,
+    records: Arc<Vec<(Override, u8)>>,
+}
+
+impl SplitStrategy {
+    pub fn new(root: &Path, config: &Config) -> Result<Self, Error> {
+        let records = config
+            .record_patterns
+            .iter()
+            .map(|record_pattern| {
+                let mut builder = OverrideBuilder::new(root);
+                builder.add(&record_pattern.pattern)?;
+                Ok((builder.build()?, record_pattern.delimiter))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            parameters: config.splitter,
+            records: Arc::new(records),
+        })
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        self.parameters
+    }
+
+    /// The record delimiter to split a file on, if it should be split on records at all.
+    pub fn delimiter(&self, absolute_path: &Path) -> Option<u8> {
+        self.records
+            .iter()
+            .find(|&&(ref matcher, _)| match matcher.matched(absolute_path, false) {
+                Match::Whitelist(_) => true,
+                _ => false,
+            })
+            .map(|&(_, delimiter)| delimiter)
+    }
+}
+
 impl<B: Backend> Repository<B> {
+    pub fn config<'r>(&'r mut self, args: ConfigArgs) -> ConfigOut<'r> {
+        let blocking = async_block! {
+            let mut config = self.get_config()?;
+
+            let unchanged = args.committer_name.is_none() && args.committer_mbox.is_none()
+                && args.split_min_chunk.is_none() && args.split_max_chunk.is_none()
+                && args.split_log2_modulus.is_none() && args.split_records.is_empty()
+                && !args.clear_split_records && args.keep_empty_directories.is_none()
+                && args.preserve_posix.is_none();
+
+            if unchanged {
+                // TODO log this somehow instead of just printlning it.
+                if let Some(ref name) = config.committer.name {
+                    println!("committer.name = {}", name);
+                }
+                if let Some(ref mbox) = config.committer.mbox {
+                    println!("committer.mbox = {}", mbox);
+                }
+                println!("split.min-chunk = {}", config.splitter.min_chunk);
+                println!("split.max-chunk = {}", config.splitter.max_chunk);
+                println!("split.log2-modulus = {}", config.splitter.log2_modulus);
+                for record_pattern in &config.record_patterns {
+                    println!("split.records = {}", record_pattern);
+                }
+                println!("keep-empty-directories = {}", config.keep_empty_directories);
+                println!("preserve-posix = {}", config.preserve_posix);
+                return Ok(());
+            }
+
+            if let Some(name) = args.committer_name {
+                config.committer.name = Some(name);
+            }
+            if let Some(mbox) = args.committer_mbox {
+                config.committer.mbox = Some(mbox);
+            }
+            if let Some(min_chunk) = args.split_min_chunk {
+                config.splitter.min_chunk = min_chunk;
+            }
+            if let Some(max_chunk) = args.split_max_chunk {
+                config.splitter.max_chunk = max_chunk;
+            }
+            if let Some(log2_modulus) = args.split_log2_modulus {
+                config.splitter.log2_modulus = log2_modulus;
+            }
+            if args.clear_split_records {
+                config.record_patterns.clear();
+            }
+            config.record_patterns.extend(args.split_records);
+            if let Some(keep_empty_directories) = args.keep_empty_directories {
+                config.keep_empty_directories = keep_empty_directories;
+            }
+            if let Some(preserve_posix) = args.preserve_posix {
+                config.preserve_posix = preserve_posix;
+            }
+
+            config.splitter.validate()?;
+            // Catch bad globs now rather than on the next stage.
+            SplitStrategy::new(&self.path, &config)?;
+
+            self.set_config(&config)?;
+
+            Ok(())
+        };
+
+        ConfigOut {
+            blocking: Box::new(blocking),
+        }
+    }
+
     pub fn get_config(&self) -> Result<Config, Error> {
         let raw_config = self.db
             .read()
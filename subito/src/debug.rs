@@ -0,0 +1,158 @@
+use std::{fmt, collections::BTreeMap, fs::File, path::{Path, PathBuf}};
+
+use attaca::{digest::Sha3Digest, object::SmallBuilder,
+             split::{Chunker, RecordSplitter, Splitter}, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use ignore::WalkBuilder;
+
+use Repository;
+use config::SplitStrategy;
+
+/// Inspect the repository and prospective data. Debug commands never modify anything.
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "debug")]
+pub enum DebugArgs {
+    #[structopt(name = "split-stats")]
+    SplitStats(SplitStatsArgs),
+}
+
+/// Split files with the repository's splitter and report how well they would deduplicate, both
+/// against each other and against what is already in the store.
+#[derive(Debug, Clone, StructOpt, Builder)]
+#[structopt(name = "split-stats")]
+pub struct SplitStatsArgs {
+    /// Files or directories to analyze. Directories are walked, respecting ignores.
+    #[structopt(name = "PATH", parse(from_os_str))]
+    pub paths: Vec<PathBuf>,
+}
+
+#[must_use = "DebugOut contains futures which must be driven to completion!"]
+pub struct DebugOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for DebugOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DebugOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// Statistics over the chunks of a set of files.
+#[derive(Debug, Clone, Default)]
+pub struct SplitStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub chunks: usize,
+
+    /// Chunk counts, keyed by the base-two logarithm of the chunk size rounded down.
+    pub histogram: BTreeMap<u32, usize>,
+
+    /// The digests and sizes of all distinct chunks seen.
+    pub unique: BTreeMap<Sha3Digest, u64>,
+}
+
+impl SplitStats {
+    fn add_chunks<C: Chunker>(&mut self, mut chunker: C) -> Result<(), Error> {
+        self.files += 1;
+
+        let mut small_builder = SmallBuilder::new();
+        while let Some(_) = chunker.find(&mut small_builder)? {
+            // Empty chunks are never stored, so don't count them either.
+            if !small_builder.is_empty() {
+                let size = small_builder.len() as u64;
+                let digest = small_builder.as_small().digest::<Sha3Digest>()?;
+
+                self.bytes += size;
+                self.chunks += 1;
+                *self.histogram.entry(63 - size.leading_zeros()).or_insert(0) += 1;
+                self.unique.insert(digest, size);
+
+                small_builder.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_file(&mut self, strategy: &SplitStrategy, path: &Path) -> Result<(), Error> {
+        let file = File::open(path).with_context(|_| format!("Error opening {}", path.display()))?;
+
+        match strategy.delimiter(path) {
+            Some(delimiter) => {
+                self.add_chunks(RecordSplitter::new(file, strategy.parameters(), delimiter))
+            }
+            None => self.add_chunks(Splitter::new(file, strategy.parameters())),
+        }
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn debug<'r>(&'r self, args: DebugArgs) -> DebugOut<'r> {
+        match args {
+            DebugArgs::SplitStats(split_stats_args) => DebugOut {
+                blocking: Box::new(self.split_stats(split_stats_args)),
+            },
+        }
+    }
+
+    pub fn split_stats<'r>(
+        &'r self,
+        args: SplitStatsArgs,
+    ) -> impl Future<Item = (), Error = Error> + 'r {
+        async_block! {
+            let config = self.get_config()?;
+            let strategy = SplitStrategy::new(&self.path, &config)?;
+            let mut stats = SplitStats::default();
+
+            for path in args.paths {
+                let absolute_path = self.path.join(path);
+                if absolute_path.is_dir() {
+                    for direntry_res in WalkBuilder::new(&absolute_path).build() {
+                        let direntry = direntry_res?;
+                        if direntry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                            stats.add_file(&strategy, direntry.path())?;
+                        }
+                    }
+                } else {
+                    stats.add_file(&strategy, &absolute_path)?;
+                }
+            }
+
+            // Resolving digests only reads from the store; nothing is sent or cached.
+            let unique = stats.unique.iter().map(|(&d, &s)| (d, s)).collect::<Vec<_>>();
+            let mut stored = 0;
+            let mut stored_bytes = 0;
+            for (digest, size) in unique {
+                if await!(self.store.resolve_digest(digest))?.is_some() {
+                    stored += 1;
+                    stored_bytes += size;
+                }
+            }
+
+            let unique_bytes = stats.unique.values().sum::<u64>();
+
+            // TODO log this somehow instead of just printlning it.
+            println!("{} files, {} bytes", stats.files, stats.bytes);
+            println!(
+                "{} chunks, {} unique ({} bytes)",
+                stats.chunks,
+                stats.unique.len(),
+                unique_bytes
+            );
+            println!(
+                "{} unique chunks ({} bytes) already in the store",
+                stored, stored_bytes
+            );
+            println!();
+            println!("chunk size histogram:");
+            for (&log2, &count) in &stats.histogram {
+                println!("  [2^{}, 2^{}) bytes: {}", log2, log2 + 1, count);
+            }
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+
+use attaca::{hierarchy::Hierarchy, object::{self, LargeChange, ObjectRef}, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+
+use Repository;
+use plumbing;
+use status::Change;
+
+/// Show the changes between the previous commit and the virtual workspace.
+#[derive(Default, Debug, StructOpt, Builder)]
+#[structopt(name = "diff")]
+pub struct DiffArgs {
+    /// Show only how many bytes were added to and removed from each changed path.
+    #[structopt(long = "stat")]
+    pub stat: bool,
+}
+
+#[must_use = "DiffOut contains futures which must be driven to completion!"]
+pub struct DiffOut<'r> {
+    pub blocking: Box<Future<Item = (), Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for DiffOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiffOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+/// The total size of the data in an object, whether a single file or a whole subtree.
+#[async(boxed)]
+fn data_size<B: Backend>(objref: ObjectRef<Handle<B>>) -> Result<u64, Error> {
+    match objref {
+        ObjectRef::Small(small_ref) => Ok(small_ref.size()),
+        ObjectRef::Large(large_ref) => Ok(large_ref.size()),
+        ObjectRef::Tree(tree_ref) => {
+            let mut size = 0;
+            for (_, child) in await!(tree_ref.fetch())? {
+                size += await!(data_size(child))?;
+            }
+            Ok(size)
+        }
+        ObjectRef::Commit(_) => bail!("Trees never contain commits!"),
+    }
+}
+
+/// Count the bytes added and removed between two versions of a path. Two large objects are
+/// compared chunk by chunk; anything else counts as wholly removed and wholly added.
+#[async]
+fn diff_stat<B: Backend>(
+    old: Option<ObjectRef<Handle<B>>>,
+    new: Option<ObjectRef<Handle<B>>>,
+) -> Result<(u64, u64), Error> {
+    match (old, new) {
+        (Some(ObjectRef::Large(old_ref)), Some(ObjectRef::Large(new_ref))) => {
+            let mut added = 0;
+            let mut removed = 0;
+
+            #[async]
+            for change in object::diff_large(old_ref, new_ref) {
+                let len = change.len();
+                match change {
+                    LargeChange::Added { .. } => added += len,
+                    LargeChange::Removed { .. } => removed += len,
+                    LargeChange::Reused { .. } => {}
+                }
+            }
+
+            Ok((added, removed))
+        }
+        // Only the attributes of the path changed, not its contents.
+        (Some(ref old), Some(ref new)) if old == new => Ok((0, 0)),
+        (old, new) => {
+            let added = match new {
+                Some(objref) => await!(data_size(objref))?,
+                None => 0,
+            };
+            let removed = match old {
+                Some(objref) => await!(data_size(objref))?,
+                None => 0,
+            };
+
+            Ok((added, removed))
+        }
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn diff<'r>(&'r self, args: DiffArgs) -> DiffOut<'r> {
+        let blocking = async_block! {
+            ensure!(args.stat, "Only `--stat` output is supported for now.");
+
+            let state = self.get_state()?;
+            let old_hierarchy = match await!(plumbing::resolve_head_opt(self))? {
+                Some(commit_ref) => {
+                    Hierarchy::from(await!(commit_ref.fetch())?.as_subtree().clone())
+                }
+                None => Hierarchy::new(),
+            };
+            let new_hierarchy = match state.candidate.clone() {
+                Some(tree_ref) => Hierarchy::from(tree_ref),
+                None => Hierarchy::new(),
+            };
+
+            let mut changes = await!(Self::staged_changes(self.store.clone(), state).collect())?;
+            changes.sort_by(|a, b| a.path().cmp(b.path()));
+
+            let mut total_added = 0;
+            let mut total_removed = 0;
+            let files = changes.len();
+            for change in changes {
+                let path = change.path().clone();
+
+                // A path which changed between a file and a directory is reported as removed and
+                // added separately, so only look at the side each change is about.
+                let (old, new) = match change {
+                    Change::Added(_) => (None, await!(new_hierarchy.get(path.clone()))?),
+                    Change::Removed(_) => (await!(old_hierarchy.get(path.clone()))?, None),
+                    Change::Modified(_) => await!(
+                        old_hierarchy.get(path.clone()).join(new_hierarchy.get(path.clone()))
+                    )?,
+                };
+                let (added, removed) = await!(diff_stat(old, new))?;
+
+                // TODO log this somehow instead of just printlning it.
+                println!(" {} | +{} -{} bytes", path.to_path().display(), added, removed);
+                total_added += added;
+                total_removed += removed;
+            }
+
+            println!(
+                " {} paths changed, {} bytes added, {} bytes removed",
+                files, total_added, total_removed
+            );
+
+            Ok(())
+        };
+
+        DiffOut {
+            blocking: Box::new(blocking),
+        }
+    }
+}
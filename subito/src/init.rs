@@ -170,6 +170,11 @@ impl<B: Backend> Repository<B> {
         let config = Config {
             store: store_config,
             remotes: Default::default(),
+            committer: Default::default(),
+            splitter: Default::default(),
+            record_patterns: Vec::new(),
+            keep_empty_directories: false,
+            preserve_posix: false,
         };
         let mut buf = Vec::new();
         config.encode(&mut buf)?;
@@ -181,3 +186,19 @@ impl<B: Backend> Repository<B> {
         Ok(repository)
     }
 }
+
+/// Initialize a repository backed by LevelDB in a fresh scratch directory, for tests. Remove the
+/// directory once done with it.
+#[cfg(test)]
+pub(crate) fn scratch(name: &str) -> Repository<LevelDbBackend> {
+    use std::{env, time::{SystemTime, UNIX_EPOCH}};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    let path = env::temp_dir().join(format!("subito-{}-{}", name, nanos));
+    fs::create_dir_all(&path).unwrap();
+
+    Repository::init_with(path, |path| leveldb(path, InitLevelDb::default())).unwrap()
+}
@@ -121,15 +121,20 @@ backends! {
 
 mod cache;
 mod db;
+mod link;
+mod posix;
 mod state;
 
 pub mod branch;
 pub mod candidate;
 pub mod checkout;
 pub mod config;
+pub mod debug;
+pub mod diff;
 pub mod fetch;
 pub mod fsck;
 pub mod log;
+pub mod merge;
 pub mod plumbing;
 pub mod pull;
 pub mod push;
@@ -154,16 +159,21 @@ use leveldb::{database::Database, kv::KV, options::{Options, ReadOptions, WriteO
 
 use cache::Cache;
 use db::Key;
+use plumbing::ancestry::{CommitGraph, FetchParents};
 use state::State;
 
 pub use branch::BranchArgs;
 pub use candidate::{CommitArgs, StageArgs};
 pub use checkout::CheckoutArgs;
 pub use clone::{clone, CloneArgs};
+pub use config::ConfigArgs;
+pub use debug::DebugArgs;
+pub use diff::DiffArgs;
 pub use fetch::FetchArgs;
 pub use fsck::FsckArgs;
 pub use init::InitArgs;
 pub use log::LogArgs;
+pub use merge::{MergeArgs, MergeOutcome};
 pub use pull::PullArgs;
 pub use push::PushArgs;
 pub use remote::RemoteArgs;
@@ -176,6 +186,7 @@ pub struct Repository<B: Backend> {
     db: Arc<RwLock<Database<Key>>>,
 
     cache: Cache<B>,
+    graph: CommitGraph<FetchParents<B>>,
     path: Arc<PathBuf>,
 }
 
@@ -224,6 +235,7 @@ impl<B: Backend> Repository<B> {
             db,
 
             cache,
+            graph: CommitGraph::new(FetchParents::default()),
             path: Arc::new(path),
         }
     }
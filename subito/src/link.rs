@@ -0,0 +1,218 @@
+//! Reading and recreating symbolic links in the workspace.
+//!
+//! Links are stored as data objects holding their target, byte for byte. Targets are never
+//! resolved, so relative links stay relative and dangling links stay dangling.
+
+use std::{ffi::OsStr, fs, os::unix::{self, ffi::OsStrExt}, path::Path};
+
+use failure::*;
+
+/// Read the target of a symbolic link, as raw bytes.
+pub fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let target = fs::read_link(path)
+        .with_context(|_| format!("Error reading symbolic link {}", path.display()))?;
+    Ok(target.as_os_str().as_bytes().to_owned())
+}
+
+/// Create a symbolic link with the given target, replacing whatever is already at `path`.
+pub fn create(path: &Path, target: &[u8]) -> Result<(), Error> {
+    if let Ok(metadata) = path.symlink_metadata() {
+        if metadata.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+
+    unix::fs::symlink(OsStr::from_bytes(target), path)
+        .with_context(|_| format!("Error creating symbolic link {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs::File, io::{Read, Write}, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+    use attaca::{object::{EntryAttrs, FileMode, ObjectRef, SmallBuilder, TreeBuilder},
+                 path::ObjectPath, store::prelude::*};
+    use attaca_leveldb::LevelDbBackend;
+    use futures::prelude::*;
+
+    use Repository;
+    use init;
+    use plumbing::checkout::{self, CheckoutOptions};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let dir = env::temp_dir().join(format!("subito-link-{}-{}", name, nanos));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dangling_link_roundtrips() {
+        let dir = scratch_dir("dangling");
+        let original = dir.join("original");
+        unix::fs::symlink("does/not/exist", &original).unwrap();
+        assert!(!original.exists());
+
+        let target = read(&original).unwrap();
+        assert_eq!(target, b"does/not/exist");
+
+        let recreated = dir.join("recreated");
+        create(&recreated, &target).unwrap();
+        assert!(recreated.symlink_metadata().unwrap().file_type().is_symlink());
+        assert!(!recreated.exists());
+        assert_eq!(fs::read_link(&recreated).unwrap(), Path::new("does/not/exist"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_link_stays_relative() {
+        let dir = scratch_dir("relative");
+        fs::create_dir(dir.join("sub")).unwrap();
+        File::create(dir.join("sub/file"))
+            .unwrap()
+            .write_all(b"contents")
+            .unwrap();
+
+        let original = dir.join("original");
+        unix::fs::symlink("sub/file", &original).unwrap();
+
+        let target = read(&original).unwrap();
+        assert_eq!(target, b"sub/file");
+
+        // Recreate the link over an existing regular file; it should resolve relative to its own
+        // directory, just like the original.
+        let recreated = dir.join("recreated");
+        File::create(&recreated).unwrap();
+        create(&recreated, &target).unwrap();
+        assert_eq!(fs::read_link(&recreated).unwrap(), Path::new("sub/file"));
+        let mut contents = Vec::new();
+        File::open(&recreated)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"contents");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn send_data(
+        repository: &Repository<LevelDbBackend>,
+        data: &[u8],
+    ) -> ObjectRef<Handle<LevelDbBackend>> {
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(data).unwrap();
+        ObjectRef::Small(small_builder.as_small().send(&repository.store).wait().unwrap())
+    }
+
+    fn with_mode(mode: FileMode) -> EntryAttrs<Handle<LevelDbBackend>> {
+        EntryAttrs {
+            mode,
+            ..EntryAttrs::default()
+        }
+    }
+
+    fn check_out(
+        repository: &mut Repository<LevelDbBackend>,
+        objref: ObjectRef<Handle<LevelDbBackend>>,
+        attrs: EntryAttrs<Handle<LevelDbBackend>>,
+        path: &ObjectPath,
+    ) {
+        let options = CheckoutOptions::new(repository).unwrap();
+        checkout::checkout_path_from_object(repository, options, objref, attrs, path.clone())
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn dangling_link_is_staged_as_a_link() {
+        let repository = init::scratch("link-stage");
+        let link_path = repository.path.join("dangling");
+        unix::fs::symlink("does/not/exist", &link_path).unwrap();
+
+        let (objref, attrs) = repository
+            .process(link_path, ObjectPath::new().push_back("dangling".to_owned()))
+            .wait()
+            .unwrap()
+            .expect("dangling links should still be staged");
+        assert_eq!(attrs.mode, FileMode::Symlink);
+        assert_eq!(objref.write_to(Vec::new()).wait().unwrap(), b"does/not/exist");
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+
+    #[test]
+    fn checkout_replaces_a_file_with_a_link_and_back() {
+        let mut repository = init::scratch("link-file");
+        let path = ObjectPath::new().push_back("link-swapped-file".to_owned());
+        let absolute_path = path.with_base(&*repository.path);
+        File::create(&absolute_path)
+            .unwrap()
+            .write_all(b"old contents")
+            .unwrap();
+
+        let link_ref = send_data(&repository, b"nowhere");
+        check_out(&mut repository, link_ref, with_mode(FileMode::Symlink), &path);
+        assert!(absolute_path.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&absolute_path).unwrap(), Path::new("nowhere"));
+
+        // The link dangles, so writing through it would create `nowhere` instead.
+        let file_ref = send_data(&repository, b"new contents");
+        check_out(&mut repository, file_ref, with_mode(FileMode::Regular), &path);
+        assert!(absolute_path.symlink_metadata().unwrap().file_type().is_file());
+        assert!(!repository.path.join("nowhere").exists());
+        let mut contents = Vec::new();
+        File::open(&absolute_path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"new contents");
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+
+    #[test]
+    fn checkout_replaces_a_directory_with_a_link_and_back() {
+        let mut repository = init::scratch("link-dir");
+        let path = ObjectPath::new().push_back("link-swapped-dir".to_owned());
+        let absolute_path = path.with_base(&*repository.path);
+        fs::create_dir_all(absolute_path.join("sub")).unwrap();
+        unix::fs::symlink("elsewhere", absolute_path.join("sub/inner")).unwrap();
+
+        let link_ref = send_data(&repository, b"nowhere");
+        check_out(&mut repository, link_ref.clone(), with_mode(FileMode::Symlink), &path);
+        assert!(absolute_path.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&absolute_path).unwrap(), Path::new("nowhere"));
+
+        let mut tree_builder = TreeBuilder::new();
+        tree_builder.insert("inner".to_owned(), link_ref);
+        tree_builder.set_mode("inner", FileMode::Symlink);
+        let tree_ref = tree_builder
+            .as_tree()
+            .send(&repository.store)
+            .wait()
+            .unwrap();
+        check_out(
+            &mut repository,
+            ObjectRef::Tree(tree_ref),
+            EntryAttrs::default(),
+            &path,
+        );
+        assert!(absolute_path.symlink_metadata().unwrap().is_dir());
+        assert!(!repository.path.join("nowhere").exists());
+        let inner = absolute_path.join("inner");
+        assert!(inner.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&inner).unwrap(), Path::new("nowhere"));
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+}
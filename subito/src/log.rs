@@ -84,10 +84,18 @@ impl<B: Backend> Repository<B> {
                 builder.author(commit.as_author().clone());
                 builder.timestamp(commit.as_timestamp().clone());
 
+                if let Some(committer) = commit.as_committer() {
+                    builder.committer(committer.clone());
+                }
+
                 if let Some(message) = commit.as_message() {
                     builder.message(message.to_owned());
                 }
 
+                for &(ref key, ref value) in commit.as_properties() {
+                    builder.property(key.clone(), value.clone());
+                }
+
                 stream_yield!((digest, builder.into_commit().unwrap()));
             }
 
@@ -22,8 +22,9 @@ use clap::App;
 use failure::Error;
 use futures::prelude::*;
 use structopt::StructOpt;
-use subito::{BranchArgs, CheckoutArgs, CloneArgs, CommitArgs, FetchArgs, FsckArgs, Head, InitArgs,
-             LogArgs, PullArgs, PushArgs, RemoteArgs, ShowArgs, StageArgs, StatusArgs};
+use subito::{BranchArgs, CheckoutArgs, CloneArgs, CommitArgs, ConfigArgs, DebugArgs, DiffArgs,
+             FetchArgs, FsckArgs, Head, InitArgs, LogArgs, MergeArgs, PullArgs, PushArgs, RemoteArgs,
+             ShowArgs, StageArgs, StatusArgs};
 
 fn main() {
     match run() {
@@ -44,9 +45,13 @@ fn run() -> Result<(), Error> {
         .subcommand(CheckoutArgs::clap())
         .subcommand(CloneArgs::clap())
         .subcommand(CommitArgs::clap())
+        .subcommand(ConfigArgs::clap())
+        .subcommand(DebugArgs::clap())
+        .subcommand(DiffArgs::clap())
         .subcommand(FetchArgs::clap())
         .subcommand(FsckArgs::clap())
         .subcommand(LogArgs::clap())
+        .subcommand(MergeArgs::clap())
         .subcommand(InitArgs::clap())
         .subcommand(PullArgs::clap())
         .subcommand(PushArgs::clap())
@@ -68,6 +73,18 @@ fn run() -> Result<(), Error> {
             subito::clone(CloneArgs::from_clap(sub_m)).blocking.wait()?;
             Ok(())
         }
+        ("config", Some(sub_m)) => {
+            let args = ConfigArgs::from_clap(sub_m);
+            search!(repository, repository.config(args).blocking.wait())?
+        }
+        ("debug", Some(sub_m)) => {
+            let args = DebugArgs::from_clap(sub_m);
+            search!(repository, repository.debug(args).blocking.wait())?
+        }
+        ("diff", Some(sub_m)) => {
+            let args = DiffArgs::from_clap(sub_m);
+            search!(repository, repository.diff(args).blocking.wait())?
+        }
         ("fetch", Some(sub_m)) => {
             let args = FetchArgs::from_clap(sub_m);
             search!(repository, repository.fetch(args).blocking.wait())?
@@ -109,6 +126,18 @@ fn run() -> Result<(), Error> {
                         (&None, &None) => {}
                     }
                     writeln!(&mut buf, "date {}", commit.as_timestamp())?;
+                    if let Some(committer) = commit.as_committer() {
+                        match (&committer.name, &committer.mbox) {
+                            (&Some(ref n), &Some(ref m)) => writeln!(&mut buf, "committer {} <{}>", n, m)?,
+                            (&Some(ref n), &None) => writeln!(&mut buf, "committer {}", n)?,
+                            (&None, &Some(ref m)) => writeln!(&mut buf, "committer <{}>", m)?,
+                            (&None, &None) => {}
+                        }
+                        writeln!(&mut buf, "commit date {}", committer.timestamp)?;
+                    }
+                    for &(ref key, ref value) in commit.as_properties() {
+                        writeln!(&mut buf, "meta {}={}", key, value)?;
+                    }
                     if let Some(message) = commit.as_message() {
                         writeln!(&mut buf, "\t{}", message)?;
                     }
@@ -120,6 +149,14 @@ fn run() -> Result<(), Error> {
 
             Ok(())
         })?,
+        ("merge", Some(sub_m)) => {
+            let args = MergeArgs::from_clap(sub_m);
+            search!(repository, {
+                let outcome = repository.merge(args).blocking.wait()?;
+                println!("{}", outcome);
+                Ok(())
+            })?
+        }
         ("init", Some(sub_m)) => init!(InitArgs::from_clap(sub_m), _repository, Ok(()))?,
         ("push", Some(sub_m)) => {
             let args = PushArgs::from_clap(sub_m);
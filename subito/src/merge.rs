@@ -0,0 +1,370 @@
+use std::{fmt, collections::BTreeMap};
+
+use attaca::{merge::{self as tree_merge, ConflictKind}, object::{FileMode, ObjectRef, TreeRef},
+             path::ObjectPath, store::prelude::*};
+use failure::*;
+use futures::prelude::*;
+use ignore::WalkBuilder;
+
+use {Repository, State};
+use cache::{Certainty, Status};
+use candidate::{self, CommitArgs};
+use link;
+use plumbing;
+use plumbing::branch::Exists;
+use state::{Head, MergeConflict, MergeState};
+use syntax::Ref;
+
+/// Merge another branch into HEAD, committing the result unless there are conflicts.
+#[derive(Debug, StructOpt, Builder)]
+#[structopt(name = "merge")]
+pub struct MergeArgs {
+    /// The ref to merge into HEAD.
+    #[structopt(name = "REF", required_unless = "abort")]
+    pub refr: Option<Ref>,
+
+    /// Use this commit message for the merge instead of the default.
+    #[structopt(short = "m", long = "m")]
+    pub message: Option<String>,
+
+    /// Give up on the merge in progress, checking out HEAD in place of its partial results.
+    #[structopt(long = "abort", conflicts_with = "REF")]
+    pub abort: bool,
+}
+
+/// How a merge turned out, for the caller to report.
+#[derive(Debug, Clone)]
+pub enum MergeOutcome {
+    /// Their commit is already part of HEAD's history.
+    UpToDate,
+
+    /// HEAD was part of their history, and has been moved along to their commit.
+    FastForward,
+
+    /// The merge was clean, and has been committed.
+    Committed,
+
+    /// Paths which couldn't be merged, with our side of each checked out. The merge is committed
+    /// once they have been fixed up and staged.
+    Conflicted(Vec<(ConflictKind, ObjectPath)>),
+
+    /// The merge in progress was abandoned.
+    Aborted,
+}
+
+impl fmt::Display for MergeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MergeOutcome::UpToDate => write!(f, "Already up to date."),
+            MergeOutcome::FastForward => write!(f, "Fast-forward."),
+            MergeOutcome::Committed => write!(f, "Merge committed."),
+            MergeOutcome::Conflicted(ref conflicts) => {
+                for &(kind, ref path) in conflicts {
+                    writeln!(f, "Conflict ({}): {}", describe(kind), path.to_path().display())?;
+                }
+                write!(
+                    f,
+                    "Our side of each conflicted path has been checked out. Fix them up, stage \
+                     them and then commit to finish the merge, or run `merge --abort` to give up."
+                )
+            }
+            MergeOutcome::Aborted => write!(f, "Merge aborted."),
+        }
+    }
+}
+
+#[must_use = "MergeOut contains futures which must be driven to completion!"]
+pub struct MergeOut<'r> {
+    pub blocking: Box<Future<Item = MergeOutcome, Error = Error> + 'r>,
+}
+
+impl<'r> fmt::Debug for MergeOut<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MergeOut")
+            .field("blocking", &"OPAQUE")
+            .finish()
+    }
+}
+
+fn describe(kind: ConflictKind) -> &'static str {
+    match kind {
+        ConflictKind::AddAdd => "add/add",
+        ConflictKind::ModifyModify => "content",
+        ConflictKind::ModifyDelete => "modify/delete",
+        ConflictKind::TypeChange => "directory/file",
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    pub fn merge<'r>(&'r mut self, args: MergeArgs) -> MergeOut<'r> {
+        let blocking = async_block! {
+            if args.abort {
+                return await!(self.abort_merge());
+            }
+
+            let refr = args.refr.ok_or_else(|| format_err!("No ref to merge given!"))?;
+            let state = self.get_state()?;
+            ensure!(
+                state.merge.is_none(),
+                "A merge is already in progress! Stage its conflicted paths and commit it, or \
+                 abort it with `merge --abort`."
+            );
+
+            let our_ref = await!(plumbing::resolve_head(self))?;
+            let our_tree = await!(our_ref.fetch())?.as_subtree().clone();
+            ensure!(
+                state.candidate.as_ref().map_or(true, |candidate| candidate == &our_tree),
+                "The virtual workspace has uncommitted changes! Commit them before merging."
+            );
+
+            let their_ref = await!(plumbing::resolve(self, refr.clone()))?;
+            let graph = plumbing::ancestry::graph(self);
+            if await!(graph.is_ancestor(their_ref.clone(), our_ref.clone()))? {
+                return Ok(MergeOutcome::UpToDate);
+            }
+
+            // Checking out the result overwrites the local workspace, so refuse to unless
+            // everything in it is already safe in HEAD.
+            ensure!(
+                await!(self.workspace_matches(our_tree.clone()))?,
+                "The local workspace has changes which aren't in HEAD! Stage and commit them, or \
+                 check out HEAD to discard them, before merging."
+            );
+
+            let their_tree = await!(their_ref.fetch())?.as_subtree().clone();
+
+            // If HEAD is an ancestor of theirs, there is nothing to merge; just move HEAD along.
+            if await!(graph.is_ancestor(our_ref.clone(), their_ref.clone()))? {
+                await!(plumbing::checkout::tree(self, their_tree))?;
+                match state.head {
+                    Head::Branch(branch) => await!(plumbing::branch::create(
+                        self,
+                        Exists::Overwrite,
+                        branch,
+                        their_ref
+                    ))?,
+                    Head::Empty | Head::Detached(_) => {
+                        await!(plumbing::set_head(self, Head::Detached(their_ref)))?
+                    }
+                }
+                return Ok(MergeOutcome::FastForward);
+            }
+
+            let maybe_base_ref = await!(graph.merge_base(our_ref.clone(), their_ref.clone()))?;
+            let base_tree = match maybe_base_ref {
+                Some(base_ref) => Some(await!(base_ref.fetch())?.as_subtree().clone()),
+                None => None,
+            };
+
+            let keep_empty = self.get_config()?.keep_empty_directories;
+            let merge = await!(tree_merge::merge_trees(
+                self.store.clone(),
+                base_tree,
+                our_tree,
+                their_tree,
+                keep_empty
+            ))?;
+            let merged_tree = await!(merge.tree.as_tree().send(&self.store))?;
+            await!(plumbing::checkout::tree(self, merged_tree))?;
+
+            let conflicts = merge
+                .conflicts
+                .iter()
+                .map(|conflict| MergeConflict {
+                    path: conflict.path.clone(),
+                    ours: conflict.ours.as_ref().map(|entry| entry.objref.clone()),
+                    theirs: conflict.theirs.as_ref().map(|entry| entry.objref.clone()),
+                })
+                .collect();
+            let state = self.get_state()?;
+            self.set_state(&State {
+                merge: Some(MergeState {
+                    parent: their_ref,
+                    conflicts,
+                }),
+                ..state
+            })?;
+
+            if merge.is_clean() {
+                let message = args.message
+                    .unwrap_or_else(|| format!("Merge {}", refr));
+                await!(
+                    self.commit(CommitArgs {
+                        message: Some(message),
+                        author: None,
+                        meta: Vec::new(),
+                        amend: false,
+                        force: false,
+                    }).blocking
+                )?;
+
+                Ok(MergeOutcome::Committed)
+            } else {
+                let conflicts = merge
+                    .conflicts
+                    .into_iter()
+                    .map(|conflict| (conflict.kind, conflict.path))
+                    .collect();
+
+                Ok(MergeOutcome::Conflicted(conflicts))
+            }
+        };
+
+        MergeOut {
+            blocking: Box::new(blocking),
+        }
+    }
+
+    /// Check whether the local workspace holds exactly the files in a tree, without hashing or
+    /// sending anything. Like `status`, this only goes by what is already known: a file counts as
+    /// unchanged when the cache is certain it still holds the same object as the tree, and
+    /// anything the cache isn't sure of counts as changed.
+    fn workspace_matches<'r>(
+        &'r self,
+        tree_ref: TreeRef<Handle<B>>,
+    ) -> impl Future<Item = bool, Error = Error> + 'r {
+        async_block! {
+            let mut expected = BTreeMap::new();
+            let mut queue = vec![(ObjectPath::new(), tree_ref)];
+            while let Some((path, tree_ref)) = queue.pop() {
+                for (name, objref, attrs) in await!(tree_ref.fetch())?.into_iter_with_attrs() {
+                    let child_path = path.push_back(name);
+                    match objref {
+                        ObjectRef::Tree(subtree_ref) => queue.push((child_path, subtree_ref)),
+                        objref => {
+                            expected.insert(child_path, (objref, attrs.mode));
+                        }
+                    }
+                }
+            }
+
+            // Walk the workspace just as staging does, so that the same files are seen.
+            // TODO #33
+            for direntry_res in WalkBuilder::new(&*self.path).build() {
+                let direntry = direntry_res?;
+                let file_type = direntry.file_type().unwrap();
+                if file_type.is_dir() {
+                    continue;
+                }
+
+                let object_path =
+                    ObjectPath::from_path(direntry.path().strip_prefix(&*self.path)?)?;
+                let (objref, mode) = match expected.remove(&object_path) {
+                    Some(expected) => expected,
+                    None => return Ok(false),
+                };
+
+                if file_type.is_symlink() {
+                    let target = await!(objref.write_to(Vec::new()))?;
+                    if mode != FileMode::Symlink || target != link::read(direntry.path())? {
+                        return Ok(false);
+                    }
+                    continue;
+                }
+
+                if mode == FileMode::Symlink || candidate::file_mode(direntry.path())? != mode {
+                    return Ok(false);
+                }
+
+                let cached_ref = match self.cache.status(&object_path)? {
+                    Status::Extant(Certainty::Positive, snapshot) => await!(
+                        snapshot
+                            .as_object_ref()
+                            .map(|id| id.resolve_id(&self.store))
+                    )?,
+                    _ => None,
+                };
+                if cached_ref.and_then(|x| x) != Some(objref) {
+                    return Ok(false);
+                }
+            }
+
+            // Anything left over has been removed from the workspace.
+            Ok(expected.is_empty())
+        }
+    }
+
+    /// Forget the merge in progress, checking out HEAD over whatever it left in the virtual and
+    /// local workspaces.
+    fn abort_merge<'r>(&'r mut self) -> impl Future<Item = MergeOutcome, Error = Error> + 'r {
+        async_block! {
+            ensure!(self.get_state()?.merge.is_some(), "No merge in progress to abort!");
+
+            await!(plumbing::checkout::head(self))?;
+            let state = self.get_state()?;
+            self.set_state(&State {
+                merge: None,
+                ..state
+            })?;
+
+            Ok(MergeOutcome::Aborted)
+        }
+    }
+
+    /// Refuse to replace the whole workspace while a merge is in progress, as its partial
+    /// results would be lost and the merge recorded against the wrong HEAD.
+    pub(crate) fn ensure_not_merging(&self) -> Result<(), Error> {
+        ensure!(
+            self.get_state()?.merge.is_none(),
+            "A merge is in progress! Commit it, or abort it with `merge --abort`, first."
+        );
+
+        Ok(())
+    }
+
+    /// Consider any conflicts of a merge in progress at or below the given paths resolved.
+    pub(crate) fn mark_resolved(&self, staged_paths: &[ObjectPath]) -> Result<(), Error> {
+        let mut state = self.get_state()?;
+
+        match state.merge {
+            Some(ref mut merge) => merge.conflicts.retain(|conflict| {
+                let conflict_path = conflict.path.to_path();
+                !staged_paths
+                    .iter()
+                    .any(|staged_path| conflict_path.starts_with(staged_path.to_path()))
+            }),
+            None => return Ok(()),
+        }
+
+        self.set_state(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, os::unix};
+
+    use init;
+
+    #[test]
+    fn workspace_matches_without_staging() {
+        let repository = init::scratch("merge-workspace");
+        unix::fs::symlink("target", repository.path.join("link")).unwrap();
+
+        let tree_ref = match repository
+            .process((*repository.path).clone(), ObjectPath::new())
+            .wait()
+            .unwrap()
+        {
+            Some((ObjectRef::Tree(tree_ref), _)) => tree_ref,
+            other => panic!("Expected a tree, got {:?}", other),
+        };
+        assert!(repository.workspace_matches(tree_ref.clone()).wait().unwrap());
+
+        // Untracked files would be clobbered by a checkout, too.
+        fs::File::create(repository.path.join("untracked")).unwrap();
+        assert!(!repository.workspace_matches(tree_ref.clone()).wait().unwrap());
+        fs::remove_file(repository.path.join("untracked")).unwrap();
+
+        fs::remove_file(repository.path.join("link")).unwrap();
+        unix::fs::symlink("elsewhere", repository.path.join("link")).unwrap();
+        assert!(!repository.workspace_matches(tree_ref.clone()).wait().unwrap());
+
+        fs::remove_file(repository.path.join("link")).unwrap();
+        assert!(!repository.workspace_matches(tree_ref).wait().unwrap());
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+//! Ancestry queries over the commit graph.
+//!
+//! Fetching a commit to learn its parents is the expensive part of walking history, so a
+//! `CommitGraph` remembers the parents of every commit it has seen, along with the answers to
+//! ancestry questions already asked. Clones of a graph share what they remember.
+
+use std::{collections::{HashMap, HashSet}, hash::Hash, marker::PhantomData, sync::{Arc, RwLock}};
+
+use attaca::store::prelude::*;
+use failure::*;
+use futures::{future, prelude::*};
+
+use super::*;
+use Repository;
+
+/// Somewhere to look up the parents of commits.
+pub trait ParentSource: 'static {
+    type Commit: Clone + Ord + Hash + 'static;
+    type Future: Future<Item = Vec<Self::Commit>, Error = Error> + 'static;
+
+    fn parents(&self, commit: &Self::Commit) -> Self::Future;
+}
+
+/// Look up parents by fetching commits from the store.
+#[derive(Debug)]
+pub struct FetchParents<B: Backend>(PhantomData<B>);
+
+impl<B: Backend> ParentSource for FetchParents<B> {
+    type Commit = CommitRef<Handle<B>>;
+    type Future = Box<Future<Item = Vec<CommitRef<Handle<B>>>, Error = Error>>;
+
+    fn parents(&self, commit: &Self::Commit) -> Self::Future {
+        Box::new(commit.fetch().map(|commit| commit.as_parents().to_owned()))
+    }
+}
+
+impl<B: Backend> Default for FetchParents<B> {
+    fn default() -> Self {
+        FetchParents(PhantomData)
+    }
+}
+
+pub struct CommitGraph<S: ParentSource> {
+    source: Arc<S>,
+    parents: Arc<RwLock<HashMap<S::Commit, Vec<S::Commit>>>>,
+    ancestry: Arc<RwLock<HashMap<(S::Commit, S::Commit), bool>>>,
+}
+
+impl<S: ParentSource> Clone for CommitGraph<S> {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone(),
+            parents: self.parents.clone(),
+            ancestry: self.ancestry.clone(),
+        }
+    }
+}
+
+impl<S: ParentSource> CommitGraph<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source: Arc::new(source),
+            parents: Arc::new(RwLock::new(HashMap::new())),
+            ancestry: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The parents of a commit, looked up at most once.
+    pub fn parents(
+        &self,
+        commit: S::Commit,
+    ) -> Box<Future<Item = Vec<S::Commit>, Error = Error>> {
+        let cached = self.parents.read().unwrap().get(&commit).cloned();
+        match cached {
+            Some(parents) => Box::new(future::ok(parents)),
+            None => {
+                let memo = self.parents.clone();
+                Box::new(self.source.parents(&commit).map(move |parents| {
+                    memo.write().unwrap().insert(commit, parents.clone());
+                    parents
+                }))
+            }
+        }
+    }
+
+    /// Every ancestor of a commit, including the commit itself.
+    pub fn ancestors(
+        &self,
+        commit: S::Commit,
+    ) -> impl Future<Item = HashSet<S::Commit>, Error = Error> {
+        let this = self.clone();
+        async_block! {
+            let mut ancestors = HashSet::new();
+            let mut stack = vec![commit];
+            while let Some(commit) = stack.pop() {
+                if ancestors.insert(commit.clone()) {
+                    stack.extend(await!(this.parents(commit))?);
+                }
+            }
+
+            Ok(ancestors)
+        }
+    }
+
+    /// Whether `ancestor` can be reached from `descendant` by following parents. Every commit is
+    /// its own ancestor.
+    pub fn is_ancestor(
+        &self,
+        ancestor: S::Commit,
+        descendant: S::Commit,
+    ) -> impl Future<Item = bool, Error = Error> {
+        let this = self.clone();
+        async_block! {
+            let key = (ancestor, descendant);
+            let cached = this.ancestry.read().unwrap().get(&key).cloned();
+            if let Some(answer) = cached {
+                return Ok(answer);
+            }
+
+            let mut visited = HashSet::new();
+            let mut stack = vec![key.1.clone()];
+            let mut answer = false;
+            while let Some(commit) = stack.pop() {
+                if commit == key.0 {
+                    answer = true;
+                    break;
+                }
+
+                if visited.insert(commit.clone()) {
+                    stack.extend(await!(this.parents(commit))?);
+                }
+            }
+
+            this.ancestry.write().unwrap().insert(key, answer);
+            Ok(answer)
+        }
+    }
+
+    /// The best common ancestors of two commits: those common ancestors which aren't ancestors of
+    /// any other common ancestor. There may be several, as after criss-cross merges, or none at
+    /// all, if the histories are unrelated. They are returned in order.
+    pub fn merge_bases(
+        &self,
+        a: S::Commit,
+        b: S::Commit,
+    ) -> impl Future<Item = Vec<S::Commit>, Error = Error> {
+        let this = self.clone();
+        async_block! {
+            let (a_ancestors, b_ancestors) = await!(this.ancestors(a).join(this.ancestors(b)))?;
+            let common = a_ancestors
+                .intersection(&b_ancestors)
+                .cloned()
+                .collect::<HashSet<_>>();
+
+            // Every ancestor of a common ancestor is itself common, so the common ancestors which
+            // aren't best are exactly the parents of common ancestors. Their parents have all been
+            // looked up by now.
+            let mut superseded = HashSet::new();
+            for commit in common.iter().cloned().collect::<Vec<_>>() {
+                superseded.extend(await!(this.parents(commit))?);
+            }
+
+            let mut bases = common
+                .into_iter()
+                .filter(|commit| !superseded.contains(commit))
+                .collect::<Vec<_>>();
+            bases.sort();
+
+            Ok(bases)
+        }
+    }
+
+    /// A single best common ancestor of two commits, if they have any. Where there are several to
+    /// choose from, the first in order is picked.
+    pub fn merge_base(
+        &self,
+        a: S::Commit,
+        b: S::Commit,
+    ) -> impl Future<Item = Option<S::Commit>, Error = Error> {
+        self.merge_bases(a, b)
+            .map(|bases| bases.into_iter().next())
+    }
+
+    /// How many commits `a` has which `b` doesn't, and how many `b` has which `a` doesn't.
+    pub fn ahead_behind(
+        &self,
+        a: S::Commit,
+        b: S::Commit,
+    ) -> impl Future<Item = (usize, usize), Error = Error> {
+        self.ancestors(a)
+            .join(self.ancestors(b))
+            .map(|(a_ancestors, b_ancestors)| {
+                (
+                    a_ancestors.difference(&b_ancestors).count(),
+                    b_ancestors.difference(&a_ancestors).count(),
+                )
+            })
+    }
+}
+
+/// The repository's commit graph. Commits never change once written, so everything the graph
+/// learns stays true for the life of the repository, and every caller shares it.
+pub fn graph<B: Backend>(this: &Repository<B>) -> CommitGraph<FetchParents<B>> {
+    this.graph.clone()
+}
+
+/// Find a best common ancestor of `ours` and `theirs`; `None` means the two histories are
+/// unrelated.
+pub fn merge_base<B: Backend>(
+    this: &Repository<B>,
+    ours: CommitRef<Handle<B>>,
+    theirs: CommitRef<Handle<B>>,
+) -> FutureOptionCommitRef<B> {
+    Box::new(graph(this).merge_base(ours, theirs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::FutureResult;
+
+    /// A hand-built commit graph, counting how many times parents are looked up.
+    struct Dag {
+        parents: HashMap<u32, Vec<u32>>,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl Dag {
+        fn new(edges: Vec<(u32, Vec<u32>)>) -> (Self, Arc<AtomicUsize>) {
+            let lookups = Arc::new(AtomicUsize::new(0));
+            let dag = Dag {
+                parents: edges.into_iter().collect(),
+                lookups: lookups.clone(),
+            };
+
+            (dag, lookups)
+        }
+    }
+
+    impl ParentSource for Dag {
+        type Commit = u32;
+        type Future = FutureResult<Vec<u32>, Error>;
+
+        fn parents(&self, commit: &u32) -> Self::Future {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            future::result(
+                self.parents
+                    .get(commit)
+                    .cloned()
+                    .ok_or_else(|| format_err!("No such commit {}", commit)),
+            )
+        }
+    }
+
+    // 1 - 2 - 3 - 4
+    //      \
+    //       5 - 6
+    fn forked() -> (CommitGraph<Dag>, Arc<AtomicUsize>) {
+        let (dag, lookups) = Dag::new(vec![
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![3]),
+            (5, vec![2]),
+            (6, vec![5]),
+        ]);
+
+        (CommitGraph::new(dag), lookups)
+    }
+
+    #[test]
+    fn ancestry_follows_parents() {
+        let (graph, _) = forked();
+
+        assert!(graph.is_ancestor(1, 4).wait().unwrap());
+        assert!(graph.is_ancestor(2, 6).wait().unwrap());
+        assert!(graph.is_ancestor(4, 4).wait().unwrap());
+        assert!(!graph.is_ancestor(4, 1).wait().unwrap());
+        assert!(!graph.is_ancestor(3, 6).wait().unwrap());
+    }
+
+    #[test]
+    fn merge_base_of_a_fork() {
+        let (graph, _) = forked();
+
+        assert_eq!(graph.merge_bases(4, 6).wait().unwrap(), vec![2]);
+        assert_eq!(graph.merge_base(6, 4).wait().unwrap(), Some(2));
+
+        // Fast-forwards: one commit is the merge base of itself and any descendant.
+        assert_eq!(graph.merge_base(2, 4).wait().unwrap(), Some(2));
+        assert_eq!(graph.merge_base(4, 4).wait().unwrap(), Some(4));
+    }
+
+    #[test]
+    fn ahead_and_behind() {
+        let (graph, _) = forked();
+
+        assert_eq!(graph.ahead_behind(4, 6).wait().unwrap(), (2, 2));
+        assert_eq!(graph.ahead_behind(6, 1).wait().unwrap(), (3, 0));
+        assert_eq!(graph.ahead_behind(3, 3).wait().unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn criss_cross_merges_have_two_bases() {
+        //   1 - 2 - 4 - 6
+        //    \    X
+        //     3 - 5 - 7
+        let (dag, _) = Dag::new(vec![
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![1]),
+            (4, vec![2, 3]),
+            (5, vec![3, 2]),
+            (6, vec![4]),
+            (7, vec![5]),
+        ]);
+        let graph = CommitGraph::new(dag);
+
+        assert_eq!(graph.merge_bases(6, 7).wait().unwrap(), vec![2, 3]);
+        assert_eq!(graph.merge_base(6, 7).wait().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn unrelated_histories_have_no_base() {
+        let (dag, _) = Dag::new(vec![(1, vec![]), (2, vec![1]), (3, vec![]), (4, vec![3])]);
+        let graph = CommitGraph::new(dag);
+
+        assert_eq!(graph.merge_bases(2, 4).wait().unwrap(), Vec::<u32>::new());
+        assert!(!graph.is_ancestor(1, 4).wait().unwrap());
+        assert_eq!(graph.ahead_behind(2, 4).wait().unwrap(), (2, 2));
+    }
+
+    #[test]
+    fn parents_are_looked_up_once() {
+        let (graph, lookups) = forked();
+
+        graph.merge_bases(4, 6).wait().unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 6);
+
+        // Everything needed has been seen already, including by a clone of the graph.
+        graph.clone().ahead_behind(4, 6).wait().unwrap();
+        graph.is_ancestor(1, 6).wait().unwrap();
+        graph.is_ancestor(1, 6).wait().unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 6);
+    }
+}
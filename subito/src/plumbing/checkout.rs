@@ -1,76 +1,159 @@
-use std::{usize, collections::BTreeSet, fs::{self, File, OpenOptions}};
+use std::{usize, fs::{self, File, OpenOptions}, ops::Range, os::unix::fs::PermissionsExt,
+          path::{Path, PathBuf}};
 
-use attaca::{hierarchy::Hierarchy, object::{Large, Object, ObjectRef, TreeRef}, path::ObjectPath,
-             store::prelude::*};
+use attaca::{hierarchy::Hierarchy,
+             object::{EntryAttrs, FileMode, Large, Object, ObjectRef, Posix, SmallRef, TreeRef},
+             path::ObjectPath, store::prelude::*};
 use failure::*;
-use futures::{stream, prelude::*};
+use futures::{future, stream, prelude::*};
 use memmap::MmapMut;
 use ignore::WalkBuilder;
 
 use super::*;
 use Repository;
 use cache::{Certainty, Status};
+use link;
+use posix;
 
 const LARGE_CHILD_LOOKAHEAD_BUFFER_SIZE: usize = 32;
 
-#[async]
-fn checkout_data_from_large_with_previous<B: Backend>(
-    new_large: Large<Handle<B>>,
-    old_large: Large<Handle<B>>,
-    mut mmap: MmapMut,
-) -> Result<(), Error> {
-    assert!(new_large.size() <= usize::MAX as u64);
-    assert!(mmap.len() == new_large.size() as usize);
-    assert!(new_large.depth() == 1 && old_large.depth() == 1);
+/// Everything from the configuration which affects how objects are checked out, read once per
+/// checkout rather than once per path.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckoutOptions {
+    keep_empty_directories: bool,
+    preserve_posix: bool,
+}
 
-    let new_entries = new_large
-        .into_iter()
-        .map(|(range, objref)| (range.start, range.end, objref))
-        .collect::<BTreeSet<_>>();
-    let old_entries = old_large
-        .into_iter()
-        .map(|(range, objref)| (range.start, range.end, objref))
-        .collect::<BTreeSet<_>>();
-    let entries = new_entries
-        .difference(&old_entries)
-        .cloned()
-        .map(|(start, end, objref)| {
-            assert!(start <= usize::MAX as u64 && end <= usize::MAX as u64);
-            let range = start as usize..end as usize;
-            (range, objref)
+impl CheckoutOptions {
+    pub fn new<B: Backend>(this: &Repository<B>) -> Result<Self, Error> {
+        let config = this.get_config()?;
+        Ok(Self {
+            keep_empty_directories: config.keep_empty_directories,
+            preserve_posix: config.preserve_posix,
         })
-        .collect::<Vec<_>>();
+    }
+}
 
-    let futures = entries.into_iter().map(|(range, objref)| match objref {
-        ObjectRef::Small(small_ref) => small_ref.fetch().map(|small| (range, small)),
-        _ => unreachable!("depth == 1, all refs are to small objects"),
-    });
-    let buffered = stream::iter_ok(futures).buffered(LARGE_CHILD_LOOKAHEAD_BUFFER_SIZE);
+/// Set or clear the execute bits of a checked out file. Executable files get an execute bit
+/// wherever they have a read bit, as with `chmod +x` under the usual umasks.
+fn set_file_mode(path: &Path, mode: FileMode) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    let bits = permissions.mode();
+    let new_bits = match mode {
+        FileMode::Regular => bits & !0o111,
+        FileMode::Executable => bits | ((bits & 0o444) >> 2),
+        // Symlinks have no permissions of their own to speak of.
+        FileMode::Symlink => bits,
+    };
 
-    #[async]
-    for (range, small) in buffered {
-        mmap[range].copy_from_slice(&small);
+    if new_bits != bits {
+        permissions.set_mode(new_bits);
+        fs::set_permissions(path, permissions)?;
     }
-    mmap.flush()?;
 
     Ok(())
 }
 
+/// Restore the POSIX metadata of a checked out path, if it has any and the repository is
+/// configured to.
+fn restore_posix<B: Backend>(
+    options: CheckoutOptions,
+    posix_ref: Option<ObjectRef<Handle<B>>>,
+    absolute_path: PathBuf,
+) -> FutureUnit<'static> {
+    match posix_ref {
+        Some(posix_ref) if options.preserve_posix => {
+            Box::new(posix_ref.write_to(Vec::new()).and_then(move |bytes| {
+                Posix::from_bytes(&bytes).and_then(|posix| posix::apply(&absolute_path, &posix))
+            }))
+        }
+        _ => Box::new(future::ok(())),
+    }
+}
+
+/// Remember that a checked out file holds the given object, so that it's known to be unchanged
+/// until it's touched again. The cache is only a shortcut, so a file which can't be recorded is
+/// simply hashed again the next time it's staged.
+fn record_checkout<B: Backend>(
+    this: &Repository<B>,
+    data_ref: ObjectRef<Handle<B>>,
+    path: ObjectPath,
+) -> FutureUnit<'static> {
+    let cache = this.cache.clone();
+    Box::new(data_ref.id().map(move |id| match cache.status(&path) {
+        Ok(Status::Extant(_, snapshot)) | Ok(Status::New(snapshot)) => {
+            let _ = cache.resolve(snapshot, id);
+        }
+        _ => {}
+    }))
+}
+
+/// Walk a (possibly deep) large object down to its small leaves, skipping any subtree which is
+/// identical to the entry covering the same byte range in the previous version of the file.
+/// Returned ranges are absolute offsets into the file.
+#[async]
+fn changed_leaves<B: Backend>(
+    new_large: Large<Handle<B>>,
+    old_large: Option<Large<Handle<B>>>,
+) -> Result<Vec<(Range<usize>, SmallRef<Handle<B>>)>, Error> {
+    let mut leaves = Vec::new();
+    let mut queue = vec![(0u64, new_large, old_large)];
+
+    while let Some((offset, new_large, old_large)) = queue.pop() {
+        let old_entries = old_large
+            .into_iter()
+            .flat_map(|large| large.into_iter())
+            .map(|(range, objref)| ((range.start, range.end), objref))
+            .collect::<HashMap<_, _>>();
+
+        for (range, objref) in new_large {
+            let old_entry = old_entries.get(&(range.start, range.end)).cloned();
+            if old_entry.as_ref() == Some(&objref) {
+                continue;
+            }
+
+            let start = offset + range.start;
+            match objref {
+                ObjectRef::Small(small_ref) => {
+                    let end = offset + range.end;
+                    assert!(start <= usize::MAX as u64 && end <= usize::MAX as u64);
+                    leaves.push((start as usize..end as usize, small_ref));
+                }
+                ObjectRef::Large(large_ref) => {
+                    // Only diff against the previous entry if it has the same shape.
+                    let future_old = match old_entry {
+                        Some(ObjectRef::Large(old_ref)) => if old_ref.depth() == large_ref.depth() {
+                            Some(old_ref.fetch())
+                        } else {
+                            None
+                        },
+                        _ => None,
+                    };
+                    let (new_child, old_child) = await!(large_ref.fetch().join(future_old))?;
+                    queue.push((start, new_child, old_child));
+                }
+                _ => unreachable!("large objects only contain small and large objects"),
+            }
+        }
+    }
+
+    Ok(leaves)
+}
+
 #[async]
-fn checkout_data_from_large_without_previous<B: Backend>(
+fn checkout_data_from_large<B: Backend>(
     new_large: Large<Handle<B>>,
+    old_large: Option<Large<Handle<B>>>,
     mut mmap: MmapMut,
 ) -> Result<(), Error> {
-    let entries = new_large.into_iter().map(|(range, objref)| {
-        assert!(range.start <= usize::MAX as u64 && range.end <= usize::MAX as u64);
-        let range = range.start as usize..range.end as usize;
-        (range, objref)
-    });
-
-    let futures = entries.into_iter().map(|(range, objref)| match objref {
-        ObjectRef::Small(small_ref) => small_ref.fetch().map(|small| (range, small)),
-        _ => unreachable!("depth == 1, all refs are to small objects"),
-    });
+    assert!(new_large.size() <= usize::MAX as u64);
+    assert!(mmap.len() == new_large.size() as usize);
+
+    let leaves = await!(changed_leaves(new_large, old_large))?;
+    let futures = leaves
+        .into_iter()
+        .map(|(range, small_ref)| small_ref.fetch().map(|small| (range, small)));
     let buffered = stream::iter_ok(futures).buffered(LARGE_CHILD_LOOKAHEAD_BUFFER_SIZE);
 
     #[async]
@@ -117,11 +200,8 @@ pub fn checkout_file_from_data<B: Backend>(
                 mmap.copy_from_slice(&small);
                 Ok(())
             }
-            (Object::Large(new_large), Some(old_large)) => await!(
-                checkout_data_from_large_with_previous(new_large, old_large, mmap)
-            ),
-            (Object::Large(new_large), None) => await!(
-                checkout_data_from_large_without_previous(new_large, mmap)
+            (Object::Large(new_large), old_large) => await!(
+                checkout_data_from_large(new_large, old_large, mmap)
             ),
             _ => unreachable!(),
         }
@@ -130,13 +210,29 @@ pub fn checkout_file_from_data<B: Backend>(
     Box::new(blocking)
 }
 
+/// Recreate a symbolic link from a data object holding its target.
+pub fn checkout_path_from_link<B: Backend>(
+    this: &mut Repository<B>,
+    data_ref: ObjectRef<Handle<B>>,
+    path: ObjectPath,
+) -> FutureUnit {
+    let absolute_path = path.with_base(&*this.path);
+    let blocking = data_ref
+        .write_to(Vec::new())
+        .and_then(move |target| link::create(&absolute_path, &target));
+
+    Box::new(blocking)
+}
+
 /// Panics if `data` is not a `Small` or `Large` ref.
 pub fn checkout_path_from_data<B: Backend>(
     this: &mut Repository<B>,
     data_ref: ObjectRef<Handle<B>>,
+    mode: FileMode,
     path: ObjectPath,
 ) -> FutureUnit {
     let blocking = async_block! {
+        let absolute_path = path.with_base(&*this.path);
         let maybe_previous_ref = match this.cache.status(&path)? {
             Status::Extant(Certainty::Positive, snapshot) => {
                 let maybe_pre_ref = await!(
@@ -148,6 +244,7 @@ pub fn checkout_path_from_data<B: Backend>(
                 match maybe_pre_ref.and_then(|x| x) {
                     Some(pre_ref) => {
                         if pre_ref == data_ref {
+                            set_file_mode(&absolute_path, mode)?;
                             return Ok(());
                         }
 
@@ -164,62 +261,74 @@ pub fn checkout_path_from_data<B: Backend>(
             _ => None,
         };
 
-        let absolute_path = path.with_base(&*this.path);
-        let file = if absolute_path.exists() {
-            let metadata = absolute_path.symlink_metadata()?;
-            let file_type = metadata.file_type();
-
-            if file_type.is_symlink() || file_type.is_file() {
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(&absolute_path)?
-            } else if file_type.is_dir() {
-                fs::remove_dir_all(&absolute_path)?;
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create_new(true)
-                    .open(&absolute_path)?
-            } else {
-                bail!("Unknown file type for {}", absolute_path.display());
+        // Check with `symlink_metadata` so that dangling symlinks are caught, too.
+        let file = match absolute_path.symlink_metadata() {
+            Ok(metadata) => {
+                let file_type = metadata.file_type();
+
+                if file_type.is_file() {
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(&absolute_path)?
+                } else if file_type.is_symlink() || file_type.is_dir() {
+                    // Never write through a symlink; replace it with the file itself.
+                    if file_type.is_symlink() {
+                        fs::remove_file(&absolute_path)?;
+                    } else {
+                        fs::remove_dir_all(&absolute_path)?;
+                    }
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create_new(true)
+                        .open(&absolute_path)?
+                } else {
+                    bail!("Unknown file type for {}", absolute_path.display());
+                }
             }
-        } else {
-            OpenOptions::new()
+            Err(_) => OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create_new(true)
-                .open(&absolute_path)?
+                .open(&absolute_path)?,
         };
 
-        await!(checkout_file_from_data(this, data_ref, maybe_previous_ref, file))
+        await!(checkout_file_from_data(this, data_ref, maybe_previous_ref, file))?;
+        set_file_mode(&absolute_path, mode)?;
+
+        Ok(())
     };
 
     Box::new(blocking)
 }
 
+/// Check out a directory and everything in it. Its own POSIX metadata, if any, is restored last,
+/// so that writing its contents doesn't disturb its modification time.
 pub fn checkout_path_from_tree<B: Backend>(
     this: &mut Repository<B>,
+    options: CheckoutOptions,
     tree_ref: TreeRef<Handle<B>>,
+    attrs: EntryAttrs<Handle<B>>,
     path: ObjectPath,
 ) -> FutureUnit {
     let blocking = async_block! {
         let tree = await!(tree_ref.fetch())?;
 
-        if tree.is_empty() {
+        // Empty trees are only checked out as directories when asked for; otherwise they're left
+        // alone, as they were before empty directories could be kept.
+        if tree.is_empty() && !options.keep_empty_directories {
             return Ok(());
         }
 
         let absolute_path = path.with_base(&*this.path);
-        if absolute_path.exists() {
-            let metadata = absolute_path.symlink_metadata()?;
-
-            if !metadata.is_dir() {
+        match absolute_path.symlink_metadata() {
+            Ok(ref metadata) if metadata.is_dir() => {}
+            Ok(_) => {
                 fs::remove_file(&absolute_path)?;
                 fs::create_dir(&absolute_path)?;
             }
-        } else {
-            fs::create_dir(&absolute_path)?;
+            Err(_) => fs::create_dir(&absolute_path)?,
         }
 
         // Use a WalkBuilder in order to respect ignores. This happens to also nicely ignore
@@ -244,21 +353,25 @@ pub fn checkout_path_from_tree<B: Backend>(
                 ))
             })
             .collect::<Result<HashMap<_, _>, Error>>()?;
-        entries.extend(tree.into_iter().map(|(name, objref)| (name, Some(objref))));
+        entries.extend(
+            tree.into_iter_with_attrs()
+                .map(|(name, objref, attrs)| (name, Some((objref, attrs)))),
+        );
 
         for (name, maybe_objref) in entries {
             match maybe_objref {
-                Some(objref) => {
+                Some((objref, attrs)) => {
                     await!(checkout_path_from_object(
                         this,
+                        options,
                         objref,
+                        attrs,
                         path.push_back(name),
                     ))?;
                 }
                 None => {
                     let child_path = absolute_path.join(&name);
-                    if child_path.exists() {
-                        let metadata = child_path.symlink_metadata()?;
+                    if let Ok(metadata) = child_path.symlink_metadata() {
                         let file_type = metadata.file_type();
 
                         if file_type.is_file() || file_type.is_symlink() {
@@ -273,6 +386,41 @@ pub fn checkout_path_from_tree<B: Backend>(
             }
         }
 
+        await!(restore_posix(options, attrs.posix, absolute_path))?;
+
+        Ok(())
+    };
+
+    Box::new(blocking)
+}
+
+/// Check out a file or symlink, then restore its POSIX metadata if it has any and the
+/// repository is configured to.
+pub fn checkout_path_from_entry<B: Backend>(
+    this: &mut Repository<B>,
+    options: CheckoutOptions,
+    data_ref: ObjectRef<Handle<B>>,
+    attrs: EntryAttrs<Handle<B>>,
+    path: ObjectPath,
+) -> FutureUnit {
+    let blocking = async_block! {
+        let absolute_path = path.with_base(&*this.path);
+
+        if attrs.mode == FileMode::Symlink {
+            await!(checkout_path_from_link(this, data_ref, path))?;
+            await!(restore_posix(options, attrs.posix, absolute_path))?;
+        } else {
+            await!(checkout_path_from_data(
+                this,
+                data_ref.clone(),
+                attrs.mode,
+                path.clone()
+            ))?;
+            await!(restore_posix(options, attrs.posix, absolute_path))?;
+            // Recorded last, as restoring metadata touches the file.
+            await!(record_checkout(this, data_ref, path))?;
+        }
+
         Ok(())
     };
 
@@ -282,14 +430,16 @@ pub fn checkout_path_from_tree<B: Backend>(
 /// This function will panic if given an `ObjectRef::Commit`.
 pub fn checkout_path_from_object<B: Backend>(
     this: &mut Repository<B>,
+    options: CheckoutOptions,
     object_ref: ObjectRef<Handle<B>>,
+    attrs: EntryAttrs<Handle<B>>,
     path: ObjectPath,
 ) -> FutureUnit {
     match object_ref {
         ObjectRef::Small(_) | ObjectRef::Large(_) => {
-            checkout_path_from_data(this, object_ref, path)
+            checkout_path_from_entry(this, options, object_ref, attrs, path)
         }
-        ObjectRef::Tree(tree_ref) => checkout_path_from_tree(this, tree_ref, path),
+        ObjectRef::Tree(tree_ref) => checkout_path_from_tree(this, options, tree_ref, attrs, path),
         ObjectRef::Commit(_) => unreachable!(),
     }
 }
@@ -305,14 +455,17 @@ where
     I: IntoIterator<Item = ObjectPath> + 'r,
 {
     let blocking = async_block! {
+        let options = CheckoutOptions::new(this)?;
         let subtree = Hierarchy::from(tree);
 
         for object_path in paths {
-            let maybe_object_ref = await!(subtree.get(object_path.clone()))?;
+            let maybe_object_ref = await!(subtree.get_with_attrs(object_path.clone()))?;
             match maybe_object_ref {
-                Some(object_ref) => await!(checkout_path_from_object(
+                Some((object_ref, attrs)) => await!(checkout_path_from_object(
                     this,
+                    options,
                     object_ref,
+                    attrs,
                     &base_path + object_path,
                 ))?,
                 None => bail!("No such object in the previous commit!"),
@@ -327,7 +480,14 @@ where
 
 pub fn tree<B: Backend>(this: &mut Repository<B>, tree_ref: TreeRef<Handle<B>>) -> FutureUnit {
     let blocking = async_block! {
-        await!(checkout_path_from_tree(this, tree_ref.clone(), ObjectPath::new()))?;
+        let options = CheckoutOptions::new(this)?;
+        await!(checkout_path_from_tree(
+            this,
+            options,
+            tree_ref.clone(),
+            EntryAttrs::default(),
+            ObjectPath::new()
+        ))?;
         await!(set_candidate(this, Some(tree_ref.clone())))?;
 
         Ok(())
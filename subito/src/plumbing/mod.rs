@@ -1,3 +1,4 @@
+pub mod ancestry;
 pub mod branch;
 pub mod checkout;
 pub mod fetch;
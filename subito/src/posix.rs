@@ -0,0 +1,191 @@
+//! Capturing and restoring the POSIX metadata of workspace files.
+//!
+//! Symlinks are never followed: their own ownership, modification time and extended attributes
+//! are what get recorded and restored. Their permission bits are meaningless and left alone.
+//!
+//! Extended attributes are only handled on Linux; on other platforms they are neither recorded
+//! nor restored.
+
+use std::{io, collections::BTreeMap, ffi::CString, fs::{self, Permissions},
+          os::unix::{ffi::OsStrExt, fs::{MetadataExt, PermissionsExt}}, path::Path};
+#[cfg(target_os = "linux")]
+use std::ptr;
+
+use attaca::object::Posix;
+use failure::*;
+use nix::libc;
+#[cfg(target_os = "linux")]
+use nix::libc::{c_char, c_void, ssize_t};
+
+/// Read the POSIX metadata of a file or symlink.
+pub fn read(path: &Path) -> Result<Posix, Error> {
+    let metadata = path.symlink_metadata()
+        .with_context(|_| format!("Error reading metadata of {}", path.display()))?;
+    let xattrs = read_xattrs(path)
+        .with_context(|_| format!("Error reading extended attributes of {}", path.display()))?;
+
+    Ok(Posix {
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: (metadata.mtime(), metadata.mtime_nsec() as u32),
+        xattrs,
+    })
+}
+
+/// Restore the POSIX metadata of a freshly checked out file or symlink.
+///
+/// Only privileged users may give files away or set extended attributes in the privileged
+/// namespaces, so failing at either for lack of permission is not an error; the file is left
+/// owned by whoever checked it out, without those attributes.
+pub fn apply(path: &Path, posix: &Posix) -> Result<(), Error> {
+    let c_path = c_path(path)?;
+    let is_symlink = path.symlink_metadata()?.file_type().is_symlink();
+
+    set_xattrs(&c_path, path, &posix.xattrs)?;
+
+    if unsafe { libc::lchown(c_path.as_ptr(), posix.uid, posix.gid) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EPERM) {
+            Err(err).with_context(|_| format!("Error changing owner of {}", path.display()))?;
+        }
+    }
+
+    // Permissions go after ownership, since changing the owner clears setuid and setgid bits.
+    if !is_symlink {
+        fs::set_permissions(path, Permissions::from_mode(posix.mode))?;
+    }
+
+    // And the modification time goes last of all, since setting anything else may bump it.
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: posix.mtime.0 as libc::time_t,
+            tv_nsec: posix.mtime.1 as libc::c_long,
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+            .with_context(|_| format!("Error setting modification time of {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Extended attribute namespaces which only privileged users may write.
+#[cfg(target_os = "linux")]
+const PRIVILEGED_XATTR_PREFIXES: &[&[u8]] = &[b"security.", b"trusted.", b"system.posix_acl_"];
+
+#[cfg(target_os = "linux")]
+fn set_xattrs(
+    c_path: &CString,
+    path: &Path,
+    xattrs: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), Error> {
+    for (name, value) in xattrs {
+        let c_name = CString::new(name.clone())?;
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            let privileged = PRIVILEGED_XATTR_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix));
+            if !(privileged && err.raw_os_error() == Some(libc::EPERM)) {
+                Err(err).with_context(|_| {
+                    format!("Error setting extended attributes of {}", path.display())
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattrs(
+    _c_path: &CString,
+    _path: &Path,
+    _xattrs: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &Path) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Error> {
+    Ok(BTreeMap::new())
+}
+
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Error> {
+    let c_path = c_path(path)?;
+    let names = match read_buffer(|buf, len| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut c_char, len)
+    }) {
+        Ok(names) => names,
+        // Filesystems without extended attributes just don't have any.
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = read_buffer(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf as *mut c_void, len)
+        })?;
+        xattrs.insert(name.to_owned(), value);
+    }
+
+    Ok(xattrs)
+}
+
+/// Call an xattr function which fills a buffer, first asking for the size it needs. The size may
+/// change between the two calls, in which case we just try again.
+#[cfg(target_os = "linux")]
+fn read_buffer<F>(mut fill: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut u8, usize) -> ssize_t,
+{
+    loop {
+        let size = fill(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0; size as usize];
+        let read = fill(buf.as_mut_ptr(), buf.len());
+        if read < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        buf.truncate(read as usize);
+        return Ok(buf);
+    }
+}
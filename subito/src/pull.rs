@@ -20,6 +20,8 @@ impl<B: Backend> Repository<B> {
         use plumbing::branch::Exists;
 
         let blocking = async_block! {
+            self.ensure_not_merging()?;
+
             let (branch, remote_ref) = {
                 let state = self.get_state()?;
                 let branch = match state.head {
@@ -1,8 +1,9 @@
 use std::fmt;
 
-use attaca::{object::ObjectRef, store::prelude::*};
+use attaca::{digest::{prelude::*, Sha3Digest}, object::{Object, ObjectRef}, store::prelude::*};
 use failure::Error;
 use futures::prelude::*;
+use hex;
 
 use Repository;
 use plumbing;
@@ -14,6 +15,11 @@ use syntax::Ref;
 pub struct ShowArgs {
     #[structopt(name = "REF", default_value = "HEAD")]
     refr: Ref,
+
+    /// Show the object with the given hex digest instead of a ref. Objects of any kind may be
+    /// shown this way; anything written without a type header is shown as small data.
+    #[structopt(long = "digest")]
+    digest: Option<String>,
 }
 
 #[must_use = "ShowOut contains futures which must be driven to completion!"]
@@ -29,9 +35,24 @@ impl<'r> fmt::Debug for ShowOut<'r> {
     }
 }
 
+fn describe<H>(objref: &ObjectRef<H>) -> String {
+    match *objref {
+        ObjectRef::Small(ref small_ref) => format!("Small {}", small_ref.size()),
+        ObjectRef::Large(ref large_ref) => {
+            format!("Large {} {}", large_ref.depth(), large_ref.size())
+        }
+        ObjectRef::Tree(_) => "Tree".to_owned(),
+        ObjectRef::Commit(_) => "Commit".to_owned(),
+    }
+}
+
 impl<B: Backend> Repository<B> {
     pub fn show<'r>(&'r self, args: ShowArgs) -> ShowOut<'r> {
         let blocking = async_block! {
+            if let Some(digest_hex) = args.digest {
+                return await!(self.show_digest(digest_hex));
+            }
+
             let resolved_ref = await!(plumbing::resolve(self, args.refr))?;
             let subtree = await!(await!(resolved_ref.fetch())?.as_subtree().fetch())?;
 
@@ -51,4 +72,62 @@ impl<B: Backend> Repository<B> {
             blocking: Box::new(blocking),
         }
     }
+
+    fn show_digest<'r>(&'r self, digest_hex: String) -> Box<Future<Item = (), Error = Error> + 'r> {
+        let blocking = async_block! {
+            let bytes = hex::decode(&digest_hex)?;
+            ensure!(
+                bytes.len() == Sha3Digest::SIGNATURE.size,
+                "{} is not a valid {} digest",
+                digest_hex,
+                Sha3Digest::SIGNATURE.name
+            );
+            let digest = Sha3Digest::from_bytes(&bytes);
+
+            let handle = await!(self.store.resolve_digest(digest))?
+                .ok_or_else(|| format_err!("no object with digest {}", digest_hex))?;
+            let (objref, object) = await!(Object::fetch(&handle))?;
+
+            println!("{}", describe(&objref));
+
+            let children = match object {
+                Object::Small(_) => Vec::new(),
+                Object::Large(large) => {
+                    if let Some(splitter) = large.splitter() {
+                        println!("splitter {}", splitter);
+                    }
+                    large
+                        .into_iter()
+                        .map(|(range, child)| (format!("{}..{}", range.start, range.end), child))
+                        .collect()
+                }
+                Object::Tree(tree) => {
+                    let metadata = tree.as_metadata().clone();
+                    tree.into_iter()
+                        .chain(metadata.into_iter().map(|(name, child)| (format!("@{}", name), child)))
+                        .collect()
+                }
+                Object::Commit(commit) => {
+                    let mut children = vec![("subtree".to_owned(), ObjectRef::Tree(commit.as_subtree().clone()))];
+                    children.extend(commit.as_parents().iter().map(|parent| ("parent".to_owned(), ObjectRef::Commit(parent.clone()))));
+                    children.extend(commit.as_metadata().iter().map(|(name, child)| (format!("@{}", name), child.clone())));
+                    children
+                }
+            };
+
+            for (name, child) in children {
+                let child_digest = await!(child.digest::<Sha3Digest>())?;
+                println!(
+                    "{} => {} {}",
+                    name,
+                    describe(&child_digest),
+                    hex::encode(child_digest.as_inner().as_bytes())
+                );
+            }
+
+            Ok(())
+        };
+
+        Box::new(blocking)
+    }
 }
@@ -1,6 +1,7 @@
 use std::{collections::HashMap, io::{BufRead, Write}};
 
-use attaca::{digest::prelude::*, object::{CommitRef, TreeRef}, store::prelude::*};
+use attaca::{digest::prelude::*, object::{CommitRef, LargeRef, ObjectRef, SmallRef, TreeRef},
+             path::ObjectPath, store::prelude::*};
 use capnp::{message, serialize_packed};
 use failure::*;
 use futures::prelude::*;
@@ -50,12 +51,32 @@ impl<H> Head<H> {
     }
 }
 
+/// A path which couldn't be merged. Only our side of it is checked out, so both sides are kept
+/// here until it is resolved.
+#[derive(Debug, Clone)]
+pub struct MergeConflict<H> {
+    pub path: ObjectPath,
+    pub ours: Option<ObjectRef<H>>,
+    pub theirs: Option<ObjectRef<H>>,
+}
+
+/// A merge which has been started but not yet committed.
+#[derive(Debug, Clone)]
+pub struct MergeState<H> {
+    /// The commit being merged into HEAD, which becomes the second parent of the merge commit.
+    pub parent: CommitRef<H>,
+
+    /// Paths which couldn't be merged and have yet to be staged by hand.
+    pub conflicts: Vec<MergeConflict<H>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct State<H> {
     pub candidate: Option<TreeRef<H>>,
     pub head: Head<H>,
     pub remote_branches: HashMap<Name, HashMap<Name, CommitRef<H>>>,
     pub upstreams: HashMap<Name, RemoteRef>,
+    pub merge: Option<MergeState<H>>,
 }
 
 impl<H> Default for State<H> {
@@ -65,10 +86,100 @@ impl<H> Default for State<H> {
             head: Head::Empty,
             remote_branches: HashMap::new(),
             upstreams: HashMap::new(),
+            merge: None,
         }
     }
 }
 
+type ConflictIds<B> = (
+    ObjectPath,
+    Option<ObjectRef<OwnedLocalId<B>>>,
+    Option<ObjectRef<OwnedLocalId<B>>>,
+);
+
+fn decode_object_ref<B: Backend>(
+    reader: ::object_ref_capnp::object_ref::Reader,
+) -> Result<ObjectRef<OwnedLocalId<B>>, Error> {
+    use object_ref_capnp::object_ref::kind;
+
+    let id = LocalId::<B>::from_bytes(reader.get_bytes()?);
+    let objref = match reader.get_kind().which()? {
+        kind::Small(small_kind) => ObjectRef::Small(SmallRef::new(small_kind.get_size(), id)),
+        kind::Large(large_kind) => ObjectRef::Large(LargeRef::new(
+            large_kind.get_size(),
+            large_kind.get_depth(),
+            id,
+        )),
+        kind::Tree(_) => ObjectRef::Tree(TreeRef::new(id)),
+        kind::Commit(_) => ObjectRef::Commit(CommitRef::new(id)),
+    };
+
+    Ok(objref)
+}
+
+fn encode_object_ref<B: Backend>(
+    mut builder: ::object_ref_capnp::object_ref::Builder,
+    objref: &ObjectRef<OwnedLocalId<B>>,
+) {
+    {
+        use std::borrow::Borrow;
+        let id: &LocalId<B> = objref.as_inner().borrow();
+        builder.set_bytes(id.as_bytes());
+    }
+    let mut kind_builder = builder.init_kind();
+    match *objref {
+        ObjectRef::Small(ref small) => {
+            let mut small_builder = kind_builder.init_small();
+            small_builder.set_size(small.size());
+        }
+        ObjectRef::Large(ref large) => {
+            let mut large_builder = kind_builder.init_large();
+            large_builder.set_size(large.size());
+            large_builder.set_depth(large.depth());
+        }
+        ObjectRef::Tree(_) => {
+            kind_builder.init_tree();
+        }
+        ObjectRef::Commit(_) => {
+            kind_builder.init_commit();
+        }
+    }
+}
+
+#[async]
+fn resolve_object_id<B: Backend>(
+    id: Option<ObjectRef<OwnedLocalId<B>>>,
+    store: Store<B>,
+) -> Result<Option<ObjectRef<Handle<B>>>, Error> {
+    match id {
+        Some(id) => {
+            let objref = await!(id.resolve_id(&store))?
+                .ok_or_else(|| format_err!("Conflicted object does not exist!"))?;
+            Ok(Some(objref))
+        }
+        None => Ok(None),
+    }
+}
+
+#[async]
+fn resolve_merge<B: Backend>(
+    parent_id: CommitRef<OwnedLocalId<B>>,
+    conflict_ids: Vec<ConflictIds<B>>,
+    store: Store<B>,
+) -> Result<MergeState<Handle<B>>, Error> {
+    let parent = await!(parent_id.resolve_id(&store))?
+        .ok_or_else(|| format_err!("Merge parent does not exist!"))?;
+
+    let mut conflicts = Vec::new();
+    for (path, our_id, their_id) in conflict_ids {
+        let ours = await!(resolve_object_id(our_id, store.clone()))?;
+        let theirs = await!(resolve_object_id(their_id, store.clone()))?;
+        conflicts.push(MergeConflict { path, ours, theirs });
+    }
+
+    Ok(MergeState { parent, conflicts })
+}
+
 #[async]
 fn resolve_refs<B: Backend>(
     raw: Vec<(Name, Vec<(Name, OwnedLocalId<B>)>)>,
@@ -94,7 +205,7 @@ impl<B: Backend> State<Handle<B>> {
     where
         R: BufRead,
     {
-        use state_capnp::state::{self, candidate, head};
+        use state_capnp::state::{self, candidate, conflict, head, merge};
 
         async_block! {
             let (blocking, upstreams) = {
@@ -152,6 +263,37 @@ impl<B: Backend> State<Handle<B>> {
                     })
                     .collect::<Result<_, Error>>()?;
 
+                let merge_ids = match state.get_merge().which()? {
+                    merge::Some(merge_res) => {
+                        let merge = merge_res?;
+                        let parent_id =
+                            CommitRef::new(LocalId::<B>::from_bytes(merge.get_parent()?));
+                        let conflicts = merge
+                            .get_conflicts()?
+                            .iter()
+                            .map(|conflict| {
+                                let path = ObjectPath::from_path(conflict.get_path()?)?;
+                                let ours = match conflict.get_ours().which()? {
+                                    conflict::ours::Some(objref) => {
+                                        Some(decode_object_ref::<B>(objref?)?)
+                                    }
+                                    conflict::ours::None(()) => None,
+                                };
+                                let theirs = match conflict.get_theirs().which()? {
+                                    conflict::theirs::Some(objref) => {
+                                        Some(decode_object_ref::<B>(objref?)?)
+                                    }
+                                    conflict::theirs::None(()) => None,
+                                };
+                                Ok((path, ours, theirs))
+                            })
+                            .collect::<Result<Vec<_>, Error>>()?;
+
+                        Some((parent_id, conflicts))
+                    }
+                    merge::None(()) => None,
+                };
+
                 let future_head = head_id
                     .resolve_id(&store)
                     .and_then(|rs| rs.ok_or_else(|| format_err!("Head does not exist!")));
@@ -161,19 +303,25 @@ impl<B: Backend> State<Handle<B>> {
                         .and_then(|rs| rs.ok_or_else(|| format_err!("Candidate does not exist!")))
                 });
 
+                let future_merge = merge_ids.map(|(parent_id, conflicts)| {
+                    resolve_merge(parent_id, conflicts, store.clone())
+                });
+
                 let future_remote_branches = resolve_refs(remote_branches, store.clone());
-                let blocking = future_candidate.join3(future_head, future_remote_branches);
+                let blocking =
+                    future_candidate.join4(future_head, future_remote_branches, future_merge);
 
                 (blocking, upstreams)
             };
 
-            let (candidate, head, remote_branches) = await!(blocking)?;
+            let (candidate, head, remote_branches, merge) = await!(blocking)?;
 
             Ok(State {
                 candidate,
                 head,
                 remote_branches,
                 upstreams,
+                merge,
             })
         }
     }
@@ -197,6 +345,26 @@ impl<B: Backend> State<Handle<B>> {
                 None => None,
             };
 
+            let merge = match state.merge {
+                Some(MergeState { parent, conflicts }) => {
+                    let mut conflict_ids = Vec::new();
+                    for MergeConflict { path, ours, theirs } in conflicts {
+                        let our_id = match ours {
+                            Some(objref) => Some(await!(objref.id())?),
+                            None => None,
+                        };
+                        let their_id = match theirs {
+                            Some(objref) => Some(await!(objref.id())?),
+                            None => None,
+                        };
+                        conflict_ids.push((path, our_id, their_id));
+                    }
+
+                    Some((await!(parent.id())?.into_inner(), conflict_ids))
+                }
+                None => None,
+            };
+
             let remote_branch_ids = {
                 let mut remote_branch_ids = HashMap::new();
                 for (remote_name, branches) in state.remote_branches {
@@ -260,6 +428,38 @@ impl<B: Backend> State<Handle<B>> {
                         value.set_branch(remote_ref.branch.as_str());
                     }
                 }
+                {
+                    let mut merge_builder = state_builder.borrow().get_merge();
+                    match merge {
+                        Some((parent_id, conflicts)) => {
+                            let mut merge_in_progress = merge_builder.init_some();
+                            {
+                                use std::borrow::Borrow;
+                                merge_in_progress.set_parent(parent_id.borrow().as_bytes());
+                            }
+                            let mut entries = merge_in_progress.borrow().init_conflicts(conflicts.len() as u32);
+                            for (i, &(ref path, ref our_id, ref their_id)) in conflicts.iter().enumerate() {
+                                let mut entry = entries.borrow().get(i as u32);
+                                entry.set_path(&path.to_path().to_string_lossy());
+                                {
+                                    let mut ours = entry.borrow().get_ours();
+                                    match *our_id {
+                                        Some(ref id) => encode_object_ref::<B>(ours.init_some(), id),
+                                        None => ours.set_none(()),
+                                    }
+                                }
+                                {
+                                    let mut theirs = entry.borrow().get_theirs();
+                                    match *their_id {
+                                        Some(ref id) => encode_object_ref::<B>(theirs.init_some(), id),
+                                        None => theirs.set_none(()),
+                                    }
+                                }
+                            }
+                        }
+                        None => merge_builder.set_none(()),
+                    }
+                }
             }
 
             serialize_packed::write_message(&mut buf, &message)?;
@@ -37,6 +37,16 @@ pub enum Change {
     Removed(ObjectPath),
 }
 
+impl Change {
+    pub fn path(&self) -> &ObjectPath {
+        match *self {
+            Change::Added(ref path) | Change::Modified(ref path) | Change::Removed(ref path) => {
+                path
+            }
+        }
+    }
+}
+
 impl<B: Backend> Repository<B> {
     pub fn status<'r>(&'r self, _args: StatusArgs) -> StatusOut<'r> {
         let blocking = self.get_state().compat().into_future();
@@ -99,8 +109,8 @@ impl<B: Backend> Repository<B> {
             queue = new_queue;
 
             let merged = Itertools::merge_join_by(
-                head_st.into_iter(),
-                candidate_st.into_iter(),
+                head_st.into_iter_with_attrs(),
+                candidate_st.into_iter_with_attrs(),
                 |head, cand| head.0.cmp(&cand.0),
             );
 
@@ -110,13 +120,13 @@ impl<B: Backend> Repository<B> {
                 // 2. Only the candidate contains the path.
                 // 3. Both HEAD and the candidate contain the path.
                 match either_or_both {
-                    EitherOrBoth::Left((name, _)) => {
+                    EitherOrBoth::Left((name, _, _)) => {
                         stream_yield!(Change::Removed(path.push_back(name)));
                     }
-                    EitherOrBoth::Right((name, _)) => {
+                    EitherOrBoth::Right((name, _, _)) => {
                         stream_yield!(Change::Added(path.push_back(name)));
                     }
-                    EitherOrBoth::Both((name, head_ref), (_, cand_ref)) => {
+                    EitherOrBoth::Both((name, head_ref, head_attrs), (_, cand_ref, cand_attrs)) => {
                         let child_path = path.push_back(name);
                         // Four cases:
                         // 1. HEAD entry is a subtree and candidate entry is a subtree.
@@ -156,8 +166,10 @@ impl<B: Backend> Repository<B> {
                                     stream_yield!(Change::Removed(child_path.push_back(name)));
                                 }
                             }
+                            // A file whose contents are unchanged may still have had its mode or
+                            // other attributes changed.
                             (head_not_tree, cand_not_tree) => {
-                                if head_not_tree != cand_not_tree {
+                                if head_not_tree != cand_not_tree || head_attrs != cand_attrs {
                                     stream_yield!(Change::Modified(child_path));
                                 }
                             }
@@ -171,7 +183,7 @@ impl<B: Backend> Repository<B> {
     }
 
     #[async_stream(item = self::Change)]
-    fn staged_changes(store: Store<B>, state: State<Handle<B>>) -> Result<(), Error> {
+    pub(crate) fn staged_changes(store: Store<B>, state: State<Handle<B>>) -> Result<(), Error> {
         // We have four possible cases:
         // 1. Both HEAD and the candidate tree are `Some`. In this case we take the
         //    union of all paths in both the HEAD tree and candidate tree; then, we look at
@@ -219,3 +231,48 @@ impl<B: Backend> Repository<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io::Write};
+
+    use attaca::object::{FileMode, SmallBuilder, TreeBuilder};
+    use attaca_leveldb::LevelDbBackend;
+
+    use init;
+
+    #[test]
+    fn mode_changes_are_modifications() {
+        let repository = init::scratch("status-mode");
+        let store = &repository.store;
+
+        let mut small_builder = SmallBuilder::new();
+        small_builder.write_all(b"#!/bin/sh\n").unwrap();
+        let small_ref = small_builder.as_small().send(store).wait().unwrap();
+
+        let mut head = TreeBuilder::new();
+        head.insert("run.sh".to_owned(), ObjectRef::Small(small_ref.clone()));
+        head.insert("same".to_owned(), ObjectRef::Small(small_ref));
+        let mut candidate = head.clone();
+        candidate.set_mode("run.sh", FileMode::Executable);
+
+        let head_ref = head.as_tree().send(store).wait().unwrap();
+        let candidate_ref = candidate.as_tree().send(store).wait().unwrap();
+        let changes = Repository::<LevelDbBackend>::compare_subtrees(head_ref, candidate_ref)
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(changes.len(), 1, "Expected only run.sh to change: {:?}", changes);
+        match changes[0] {
+            Change::Modified(ref path) => {
+                assert_eq!(path, &ObjectPath::new().push_back("run.sh".to_owned()))
+            }
+            ref other => panic!("Expected run.sh to be modified, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&*repository.path).unwrap();
+    }
+}
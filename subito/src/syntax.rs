@@ -150,3 +150,116 @@ impl FromStr for Ref {
         }
     }
 }
+
+/// A `key=value` pair, split at the first `=`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Property {
+    pub key: String,
+    pub value: String,
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+impl FromStr for Property {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find('=')
+            .ok_or_else(|| format_err!("'{}' is not of the form key=value", s))?;
+        ensure!(split > 0, "'{}' has an empty key", s);
+
+        Ok(Property {
+            key: s[..split].to_owned(),
+            value: s[split + 1..].to_owned(),
+        })
+    }
+}
+
+/// A glob selecting paths to be split on records, optionally followed by `=` and the record
+/// delimiter. The delimiter is a single ASCII character, one of the escapes `\n`, `\t`, `\r` and
+/// `\0`, or a byte in hex such as `0x1e`; it defaults to a newline. An `=` within the glob itself
+/// must be escaped as `\=`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RecordPattern {
+    pub pattern: String,
+    pub delimiter: u8,
+}
+
+impl fmt::Display for RecordPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=0x{:02x}", self.pattern, self.delimiter)
+    }
+}
+
+impl FromStr for RecordPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, delimiter) = match find_unescaped(s, '=') {
+            Some(split) => (&s[..split], parse_delimiter(&s[split + 1..])?),
+            None => (s, b'\n'),
+        };
+        ensure!(!pattern.is_empty(), "'{}' has an empty pattern", s);
+
+        Ok(RecordPattern {
+            pattern: pattern.to_owned(),
+            delimiter,
+        })
+    }
+}
+
+/// Find the first occurrence of a character which isn't escaped with a backslash.
+fn find_unescaped(s: &str, needle: char) -> Option<usize> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == needle {
+            return Some(i);
+        }
+    }
+
+    None
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, Error> {
+    match s {
+        "\\n" => Ok(b'\n'),
+        "\\t" => Ok(b'\t'),
+        "\\r" => Ok(b'\r'),
+        "\\0" => Ok(b'\0'),
+        _ if s.starts_with("0x") && s.len() > 2 => Ok(u8::from_str_radix(&s[2..], 16)
+            .map_err(|_| format_err!("'{}' is not a valid hex byte", s))?),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => bail!("'{}' is not a valid record delimiter", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_patterns() {
+        let pattern = "*.csv".parse::<RecordPattern>().unwrap();
+        assert_eq!(pattern.pattern, "*.csv");
+        assert_eq!(pattern.delimiter, b'\n');
+
+        let pattern = "data/*.tsv=\\0".parse::<RecordPattern>().unwrap();
+        assert_eq!(pattern.pattern, "data/*.tsv");
+        assert_eq!(pattern.delimiter, b'\0');
+
+        // The delimiter may itself be `=`, and escaped `=`s belong to the glob.
+        let pattern = "year\\=2018/*.log==".parse::<RecordPattern>().unwrap();
+        assert_eq!(pattern.pattern, "year\\=2018/*.log");
+        assert_eq!(pattern.delimiter, b'=');
+        assert_eq!(pattern.to_string().parse::<RecordPattern>().unwrap(), pattern);
+
+        assert!("year=2018/*.log".parse::<RecordPattern>().is_err());
+        assert!("=;".parse::<RecordPattern>().is_err());
+    }
+}